@@ -0,0 +1,110 @@
+use ndarray::prelude::*;
+use ndarray_rand::rand_distr::Uniform;
+use ndarray_rand::RandomExt;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+extern crate wall_e;
+use wall_e::fcn::*;
+
+/// System allocator that counts allocations.
+struct CountingAlloc;
+
+static NUM_ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        NUM_ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// Runs f num_evals times and prints time and allocations per evaluation.
+fn measure<G: FnMut(usize)>(name: &str, num_evals: usize, mut f: G) {
+    let num_allocs = NUM_ALLOCS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for k in 0..num_evals {
+        f(k);
+    }
+    let elapsed = start.elapsed();
+    let num_allocs = NUM_ALLOCS.load(Ordering::Relaxed) - num_allocs;
+    println!(
+        "{}: {:.1} ns/eval, {:.1} allocs/eval",
+        name,
+        elapsed.as_nanos() as f64 / num_evals as f64,
+        num_allocs as f64 / num_evals as f64
+    );
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<String>>();
+    if args.len() > 2 {
+        panic!("Bad cmd line parameters.");
+    }
+    let num_evals = args.get(1).map_or(100000, |n| n.parse().unwrap());
+
+    // Same shape as the rl controller
+    let mut fcn = FCN::new_graph_with(
+        vec![
+            (3, Activation::Linear),
+            (5, Activation::LeakyReLu(0.1)),
+            (5, Activation::LeakyReLu(0.1)),
+            (5, Activation::LeakyReLu(0.1)),
+            (5, Activation::LeakyReLu(0.1)),
+            (2, Activation::ScaledTanh(vec![(-1.0, 1.0), (-1.0, 1.0)])),
+        ],
+        vec![],
+        vec![Skip::Concat { from: 0, to: 5 }],
+        &Init::Uniform(0.0, 1.0),
+        &mut rand::thread_rng(),
+    );
+    fcn.set_input_normalizer(Some(Normalizer::from_bounds(&[(-10.0, 10.0); 3])))
+        .unwrap();
+    println!("{}", fcn.summary());
+    let params = fcn.params().clone();
+
+    // Single inputs
+    let inputs = Array::random((1000, 3), Uniform::new(-10.0f32, 10.0));
+    let inputs = inputs
+        .genrows()
+        .into_iter()
+        .map(|input| input.to_owned())
+        .collect::<Vec<Array1<f32>>>();
+    let mut checksum = 0.0;
+    measure("at_with", num_evals, |k| {
+        checksum += fcn.at_with(&inputs[k % inputs.len()], &params)[0];
+    });
+    let mut workspace = fcn.workspace();
+    let mut output = Array1::zeros(2);
+    measure("at_into", num_evals, |k| {
+        fcn.at_into(
+            &inputs[k % inputs.len()],
+            &params,
+            &mut workspace,
+            &mut output,
+        );
+        checksum += output[0];
+    });
+
+    // Batches of the size of an rl evaluation
+    let batch = Array::random((6, 3), Uniform::new(-10.0f32, 10.0));
+    measure("at_batch_with (6 rows)", num_evals / 6, |_| {
+        checksum += fcn.at_batch_with(&batch, &params)[[0, 0]];
+    });
+    let mut batch_workspace = fcn.batch_workspace(6);
+    let mut outputs = Array2::zeros((6, 2));
+    measure("at_batch_into (6 rows)", num_evals / 6, |_| {
+        fcn.at_batch_into(&batch, &params, &mut batch_workspace, &mut outputs);
+        checksum += outputs[[0, 0]];
+    });
+    println!("checksum={}", checksum);
+}
@@ -0,0 +1,270 @@
+use ndarray::prelude::*;
+use ndarray_rand::rand_distr::Uniform;
+use ndarray_rand::RandomExt;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+extern crate wall_e;
+use wall_e::codegen;
+use wall_e::fcn::*;
+
+/// Loads a serialized fcn, or the fcn of a serialized rl experiment.
+fn load(path: &str) -> FCN {
+    let file = File::open(path).unwrap();
+    let reader = BufReader::new(file);
+    let value: serde_json::Value = serde_json::from_reader(reader).unwrap();
+    match value.get("fcn") {
+        Some(fcn) => serde_json::from_value(fcn.clone()).unwrap(),
+        None => serde_json::from_value(value).unwrap(),
+    }
+}
+
+fn generate(fcn: &FCN, dir: &Path) {
+    fs::create_dir_all(dir).unwrap();
+    fs::write(dir.join("fcn.rs"), codegen::rust_module(fcn).unwrap()).unwrap();
+    fs::write(dir.join("fcn.h"), codegen::c_header(fcn).unwrap()).unwrap();
+    fs::write(dir.join("fcn.c"), codegen::c_source(fcn, "fcn.h").unwrap()).unwrap();
+}
+
+const RUST_HARNESS: &str = "
+#[path = \"fcn.rs\"]
+mod fcn;
+use std::io::BufRead;
+
+fn main() {
+    let stdin = std::io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line.unwrap();
+        let mut input = [0.0f32; fcn::INPUT_LEN];
+        for (x, word) in input.iter_mut().zip(line.split_whitespace()) {
+            *x = f32::from_bits(u32::from_str_radix(word, 16).unwrap());
+        }
+        let mut output = [0.0f32; fcn::OUTPUT_LEN];
+        fcn::infer(&input, &mut output);
+        let words = output.iter().map(|y| format!(\"{:08x}\", y.to_bits())).collect::<Vec<String>>();
+        println!(\"{}\", words.join(\" \"));
+    }
+}
+";
+
+const C_HARNESS: &str = "
+#include <stdio.h>
+#include <string.h>
+#include \"fcn.h\"
+
+int main(void) {
+    float input[FCN_INPUT_LEN], output[FCN_OUTPUT_LEN];
+    unsigned int word;
+    int i;
+    for (;;) {
+        for (i = 0; i < FCN_INPUT_LEN; i++) {
+            if (scanf(\"%x\", &word) != 1) {
+                return 0;
+            }
+            memcpy(&input[i], &word, sizeof(float));
+        }
+        infer(input, output);
+        for (i = 0; i < FCN_OUTPUT_LEN; i++) {
+            memcpy(&word, &output[i], sizeof(float));
+            printf(i == 0 ? \"%08x\" : \" %08x\", word);
+        }
+        printf(\"\\n\");
+    }
+}
+";
+
+fn build(dir: &Path, program: &str, args: &[&str]) {
+    let status = Command::new(program)
+        .args(args)
+        .current_dir(dir)
+        .status()
+        .unwrap();
+    assert!(
+        status.success(),
+        "Failed to build harness using {}.",
+        program
+    );
+}
+
+/// Runs harness on inputs, one per line, and returns its output lines.
+fn run(harness: &Path, inputs: &str) -> Vec<String> {
+    let mut child = Command::new(harness)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Feed inputs from another thread so that a full stdout pipe can't block the harness
+    let mut stdin = child.stdin.take().unwrap();
+    let inputs = inputs.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(inputs.as_bytes()).unwrap());
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap();
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+/// Checks generated rust and c against the library on random inputs.
+fn check(fcn: &FCN, num_inputs: usize) -> bool {
+    check_with(fcn, num_inputs, true)
+}
+
+/// Same as check, building the c harness only if with_c.
+fn check_with(fcn: &FCN, num_inputs: usize, with_c: bool) -> bool {
+    let dir = env::temp_dir().join(format!("wall-e-codegen-{}", std::process::id()));
+    generate(fcn, &dir);
+    fs::write(dir.join("main.rs"), RUST_HARNESS).unwrap();
+    fs::write(dir.join("main.c"), C_HARNESS).unwrap();
+    build(
+        &dir,
+        "rustc",
+        &["--edition", "2018", "-O", "main.rs", "-o", "harness_rs"],
+    );
+    let mut harnesses = vec!["harness_rs"];
+    if with_c {
+        build(
+            &dir,
+            "cc",
+            &[
+                "-O2",
+                "-ffp-contract=off",
+                "main.c",
+                "fcn.c",
+                "-lm",
+                "-o",
+                "harness_c",
+            ],
+        );
+        harnesses.push("harness_c");
+    }
+
+    let input_len = fcn.layers()[0].0;
+    let inputs = Array::random((num_inputs, input_len), Uniform::new(-10.0f32, 10.0));
+    let expected = inputs
+        .genrows()
+        .into_iter()
+        .map(|input| {
+            fcn.at(&input.to_owned())
+                .iter()
+                .map(|y| format!("{:08x}", y.to_bits()))
+                .collect::<Vec<String>>()
+                .join(" ")
+        })
+        .collect::<Vec<String>>();
+    let inputs = inputs
+        .genrows()
+        .into_iter()
+        .map(|input| {
+            input
+                .iter()
+                .map(|x| format!("{:08x}", x.to_bits()))
+                .collect::<Vec<String>>()
+                .join(" ")
+                + "\n"
+        })
+        .collect::<String>();
+
+    let mut ok = true;
+    for harness in harnesses.iter() {
+        let actual = run(&dir.join(harness), &inputs);
+        let num_mismatches = expected
+            .iter()
+            .zip(actual.iter())
+            .filter(|(e, a)| e != a)
+            .count()
+            + expected.len()
+            - actual.len().min(expected.len());
+        println!(
+            "{}: {} of {} outputs differ from library",
+            harness, num_mismatches, num_inputs
+        );
+        ok &= num_mismatches == 0;
+    }
+    fs::remove_dir_all(&dir).unwrap();
+    ok
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<String>>();
+    if args.len() >= 3 && args[1] == "check" {
+        // Check generated code against library
+        if args.len() > 4 {
+            panic!("Bad cmd line parameters.");
+        }
+        let fcn = load(&args[2]);
+        println!("{}", fcn);
+        let num_inputs = args.get(3).map_or(10000, |n| n.parse().unwrap());
+        if !check(&fcn, num_inputs) {
+            std::process::exit(1);
+        }
+    } else {
+        // Generate code
+        if args.len() != 3 {
+            panic!("Bad cmd line parameters.");
+        }
+        let fcn = load(&args[1]);
+        println!("{}", fcn);
+        generate(&fcn, Path::new(&args[2]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wall_e::seed;
+
+    fn has_program(program: &str) -> bool {
+        Command::new(program)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok()
+    }
+
+    #[test]
+    fn generated_code_is_bit_identical_to_library() {
+        // Every activation, and layers wider than 16 to exercise unrolled dot products
+        let mut fcn = FCN::new_graph_with(
+            vec![
+                (3, Activation::Linear),
+                (20, Activation::LeakyReLu(0.1)),
+                (20, Activation::Gelu),
+                (17, Activation::Sigmoid),
+                (17, Activation::Tanh),
+                (8, Activation::ReLu),
+                (8, Activation::Elu(0.5)),
+                (6, Activation::Softplus),
+                (6, Activation::Swish),
+                (4, Activation::Softmax),
+                (2, Activation::ScaledTanh(vec![(-1.0, 1.0), (0.0, 5.0)])),
+            ],
+            vec![],
+            vec![
+                Skip::Residual { from: 1, to: 2 },
+                Skip::Concat { from: 0, to: 10 },
+            ],
+            &Init::Normal(0.0, 0.5),
+            &mut seed::rng(3),
+        );
+        fcn.set_input_normalizer(Some(Normalizer::from_bounds(&[
+            (-10.0, 10.0),
+            (0.0, 5.0),
+            (-1.0, 3.0),
+        ])))
+        .unwrap();
+        fcn.set_output_normalizer(Some(Normalizer::from_bounds(&[(-2.0, 2.0), (0.0, 10.0)])))
+            .unwrap();
+        let with_c = has_program("cc");
+        if !with_c {
+            println!("cc is unavailable, checking generated rust only");
+        }
+        assert!(check_with(&fcn, 500, with_c));
+    }
+}
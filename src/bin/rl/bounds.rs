@@ -0,0 +1,99 @@
+use super::Experiment;
+use ndarray::prelude::*;
+use rand::Rng;
+use wall_e::bounds::{interval_bounds, symbolic_bounds, BoundsError};
+
+/// Sub-boxes of box, each dimension split into num_splits equal parts.
+fn split(bounds: &[(f32, f32)], num_splits: usize) -> Vec<Vec<(f32, f32)>> {
+    let mut boxes = vec![vec![]];
+    for (lo, hi) in bounds.iter() {
+        let step = (hi - lo) / num_splits as f32;
+        let parts = (0..num_splits)
+            .map(|k| {
+                let part_hi = if k + 1 == num_splits {
+                    *hi
+                } else {
+                    lo + step * (k + 1) as f32
+                };
+                (lo + step * k as f32, part_hi)
+            })
+            .collect::<Vec<(f32, f32)>>();
+        boxes = boxes
+            .iter()
+            .flat_map(|b| {
+                parts.iter().map(move |part| {
+                    let mut b = b.clone();
+                    b.push(*part);
+                    b
+                })
+            })
+            .collect();
+    }
+    boxes
+}
+
+/// Union of bounds over sub-boxes.
+fn union<B: Fn(&[(f32, f32)]) -> Result<Vec<(f32, f32)>, BoundsError>>(
+    boxes: &[Vec<(f32, f32)>],
+    bounds: B,
+) -> Result<Vec<(f32, f32)>, BoundsError> {
+    let mut union: Option<Vec<(f32, f32)>> = None;
+    for b in boxes {
+        let bounds = bounds(b)?;
+        union = Some(match union {
+            None => bounds,
+            Some(union) => union
+                .iter()
+                .zip(bounds.iter())
+                .map(|((lo, hi), (b_lo, b_hi))| (lo.min(*b_lo), hi.max(*b_hi)))
+                .collect(),
+        });
+    }
+    Ok(union.unwrap())
+}
+
+/// Reports certified bounds of controls over every observation of an episode of exp,
+/// split into num_splits parts per dimension, along with the range of controls on sampled observations.
+pub fn report(exp: &Experiment, num_splits: usize, num_samples: usize) {
+    let domain = match exp.reward.episode_observation_bounds() {
+        Some(domain) => domain,
+        None => {
+            println!("observations are unbounded, the start and goal regions of reward overlap");
+            return;
+        }
+    };
+    println!("observation domain={:?}", domain);
+    let boxes = split(&domain, num_splits);
+    let (interval, symbolic) = match (
+        union(&boxes, |b| interval_bounds(&exp.fcn, b)),
+        union(&boxes, |b| symbolic_bounds(&exp.fcn, b)),
+    ) {
+        (Ok(interval), Ok(symbolic)) => (interval, symbolic),
+        (Err(error), _) | (_, Err(error)) => {
+            println!("bounds failed: {}", error);
+            return;
+        }
+    };
+    // Sampled range is contained in certified bounds
+    let mut rng = exp.eval_rng();
+    let observations = Array2::from_shape_fn((num_samples, domain.len()), |(_, k)| {
+        let (lo, hi) = domain[k];
+        if lo < hi {
+            rng.gen_range(lo, hi)
+        } else {
+            lo
+        }
+    });
+    let controls = exp.fcn.at_batch(&observations);
+    for (k, name) in ["v", "w"].iter().enumerate() {
+        let column = controls.column(k);
+        let sampled = (
+            column.fold(f32::INFINITY, |lo, &c| lo.min(c)),
+            column.fold(f32::NEG_INFINITY, |hi, &c| hi.max(c)),
+        );
+        println!(
+            "{}: interval={:?} symbolic={:?} sampled={:?}",
+            name, interval[k], symbolic[k], sampled
+        );
+    }
+}
@@ -1,37 +1,131 @@
 use ggez::*;
-use serde::{Deserialize, Serialize};
+use rand_pcg::Pcg32;
+use serde::{Deserialize, Deserializer, Serialize};
 mod vis;
 use vis::*;
 
 mod reward;
 use reward::*;
 
+mod bounds;
+mod quant;
+
 extern crate wall_e;
-use wall_e::ceo::CEO;
+use wall_e::ceo::{CeoCheckpoint, CeoObserver, GenerationStats, HallOfFame, PrintObserver, CEO};
+use wall_e::diff_drive_model::DiffDriveModel;
 use wall_e::fcn::*;
+use wall_e::interrupt;
+use wall_e::moceo::{MoCEO, ParetoSet};
+use wall_e::optimizer::{AnyOptimizer, Optimizer};
+use wall_e::seed;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 struct Experiment {
     fcn: FCN,
-    ceo: CEO,
+    /// Experiments saved before optimizers were selectable have a bare ceo instead.
+    #[serde(alias = "ceo", deserialize_with = "deserialize_optimizer")]
+    optimizer: AnyOptimizer,
     reward: DiffDriveReward,
+    /// Best controllers of the last optimization, any of which can be saved as an experiment of its own.
+    #[serde(default)]
+    hall_of_fame: HallOfFame,
+    /// Multi-objective optimizer of the terms of reward, used by pareto mode.
+    #[serde(default)]
+    pareto_optimizer: MoCEO,
+    /// Pareto set of the last multi-objective optimization, any point of which can be saved as an experiment of its own.
+    #[serde(default)]
+    pareto_set: ParetoSet,
+}
+
+/// Optimizer of an experiment, either selected by name or a bare ceo.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedOptimizer {
+    Any(AnyOptimizer),
+    Ceo(CEO),
+}
+
+fn deserialize_optimizer<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<AnyOptimizer, D::Error> {
+    Ok(match SavedOptimizer::deserialize(deserializer)? {
+        SavedOptimizer::Any(optimizer) => optimizer,
+        SavedOptimizer::Ceo(ceo) => AnyOptimizer::Ceo(ceo),
+    })
 }
 
 impl Experiment {
     const VIS_WIDTH: f32 = 500.0;
     const VIS_HEIGHT: f32 = 500.0;
+
+    /// Source of evaluations outside of optimization, derived from the seed of optimizer if any.
+    fn eval_rng(&self) -> Pcg32 {
+        seed::rng(seed::derive(
+            self.optimizer.seed().unwrap_or_else(rand::random),
+            seed::EVAL_STREAM,
+        ))
+    }
 }
 
-fn run() -> Experiment {
-    let mut fcn = FCN::new(vec![
-        (3, Activation::Linear),
-        (5, Activation::LeakyReLu(0.1)),
-        (5, Activation::LeakyReLu(0.1)),
-        (5, Activation::LeakyReLu(0.1)),
-        (5, Activation::LeakyReLu(0.1)),
-        (2, Activation::Linear),
-    ]);
+/// Experiment in progress along with the state of its optimization.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Checkpoint {
+    exp: Experiment,
+    ceo_checkpoint: CeoCheckpoint,
+}
+
+/// Prints progress and writes checkpoints of exp to path.
+struct CheckpointWriter {
+    exp: Experiment,
+    path: String,
+}
+
+impl CeoObserver for CheckpointWriter {
+    fn observe(&mut self, stats: &GenerationStats) -> bool {
+        PrintObserver.observe(stats)
+    }
+
+    fn checkpoint(&mut self, ceo_checkpoint: &CeoCheckpoint) {
+        use std::fs::File;
+
+        let mut checkpoint = Checkpoint {
+            exp: self.exp.clone(),
+            ceo_checkpoint: ceo_checkpoint.clone(),
+        };
+        checkpoint.exp.fcn.set_params(ceo_checkpoint.params.clone());
+        // Replaces last checkpoint only once fully written
+        let tmp_path = format!("{}.tmp", self.path);
+        serde_json::to_writer(&File::create(&tmp_path).unwrap(), &checkpoint).unwrap();
+        std::fs::rename(&tmp_path, &self.path).unwrap();
+        println!(
+            "checkpoint generation={} path={}",
+            ceo_checkpoint.generation, self.path
+        );
+    }
+}
+
+/// New experiment optimized by ceo, with all randomness derived from master_seed.
+fn new_experiment(master_seed: u64) -> Experiment {
+    // Goal-relative input is also fed straight to the output head
+    let mut fcn = FCN::new_graph_with(
+        vec![
+            (3, Activation::Linear),
+            (5, Activation::LeakyReLu(0.1)),
+            (5, Activation::LeakyReLu(0.1)),
+            (5, Activation::LeakyReLu(0.1)),
+            (5, Activation::LeakyReLu(0.1)),
+            (
+                2,
+                Activation::ScaledTanh(vec![DiffDriveModel::V_BOUNDS, DiffDriveModel::W_BOUNDS]),
+            ),
+        ],
+        vec![],
+        vec![Skip::Concat { from: 0, to: 5 }],
+        &Init::Uniform(0.0, 1.0),
+        &mut seed::rng(seed::derive(master_seed, seed::INIT_STREAM)),
+    );
 
     let mut ceo = CEO::default();
     ceo.generations = 1000;
@@ -40,8 +134,20 @@ fn run() -> Experiment {
     ceo.elite_frac = 0.25;
     ceo.initial_std = 3.0;
     ceo.noise_factor = 3.0;
+    ceo.checkpoint_interval = 10;
+    ceo.seed = Some(master_seed);
 
-    let reward = DiffDriveReward::new(
+    let pareto_optimizer = MoCEO {
+        generations: 1000,
+        batch_size: 100,
+        num_evaluation_samples: 6,
+        initial_std: 3.0,
+        noise_factor: 3.0,
+        seed: Some(master_seed),
+        ..MoCEO::default()
+    };
+
+    let mut reward = DiffDriveReward::new(
         // (100.0, 100.0),
         // (400.0, 400.0),
         (20.0, 240.0),
@@ -54,45 +160,196 @@ fn run() -> Experiment {
         (260.0, 480.0),
         500,
     );
+    // Controller carries its own normalization of the raw state
+    reward.set_raw_state(true);
+    fcn.set_input_normalizer(Some(Normalizer::from_bounds(&reward.raw_state_bounds())))
+        .unwrap();
 
-    let _th_std = ceo.optimize(&mut fcn, &reward).unwrap();
-
-    let exp = Experiment {
+    Experiment {
         fcn: fcn,
-        ceo: ceo,
+        optimizer: AnyOptimizer::Ceo(ceo),
         reward: reward,
+        hall_of_fame: HallOfFame::default(),
+        pareto_optimizer: pareto_optimizer,
+        pareto_set: ParetoSet::default(),
+    }
+}
+
+/// Optimizes controller of exp from its current params, writing checkpoints to a new file.
+fn run(mut exp: Experiment) -> Experiment {
+    let now = chrono::offset::Local::now();
+    optimize(
+        &mut exp,
+        None,
+        format!("checkpoint{},{}.json", now.date(), now.time()),
+    );
+    exp
+}
+
+/// Optimizes controller of exp, from checkpoint if any, writing checkpoints to path.
+/// Only ceo supports checkpoints.
+fn optimize(exp: &mut Experiment, checkpoint: Option<CeoCheckpoint>, path: String) {
+    // Ctrl-C stops optimization early, the experiment so far is still saved
+    interrupt::install_handler().unwrap();
+    let mut writer = CheckpointWriter {
+        exp: exp.clone(),
+        path: path,
+    };
+    let result = match (&exp.optimizer, checkpoint) {
+        (_, None) => exp
+            .optimizer
+            .optimize_with(&mut exp.fcn, &exp.reward, &mut writer),
+        (AnyOptimizer::Ceo(ceo), Some(checkpoint)) => {
+            ceo.resume_with(&mut exp.fcn, &exp.reward, &mut writer, checkpoint)
+        }
+        (_, Some(_)) => panic!("Checkpoint of an optimizer other than ceo."),
     };
+    // Controller so far is still saved
+    match result {
+        Ok(outcome) => exp.hall_of_fame = outcome.hall_of_fame,
+        Err(error) => println!("optimization failed: {}", error),
+    }
+}
+
+/// Optimizes the terms of reward of exp jointly from its current params, keeping their pareto set.
+fn optimize_pareto(exp: &mut Experiment) {
+    // Ctrl-C stops optimization early, the pareto set so far is still saved
+    interrupt::install_handler().unwrap();
+    match exp.pareto_optimizer.optimize(&mut exp.fcn, &exp.reward) {
+        Ok(pareto_set) => exp.pareto_set = pareto_set,
+        Err(error) => println!("optimization failed: {}", error),
+    }
+    print_pareto_set(&exp.pareto_set);
+}
+
+fn print_pareto_set(pareto_set: &ParetoSet) {
+    for (k, point) in pareto_set.points.iter().enumerate() {
+        println!(
+            "point={} generation={} objectives={:?}",
+            k,
+            point.generation,
+            point.objectives.to_vec()
+        );
+    }
+}
 
+/// Writes value to <prefix><date>,<time>.json.
+fn save<T: Serialize>(value: &T, prefix: &str) {
+    use std::fs::File;
+
+    let now = chrono::offset::Local::now();
+    serde_json::to_writer(
+        &File::create(format!("{}{},{}.json", prefix, now.date(), now.time())).unwrap(),
+        value,
+    )
+    .unwrap();
+}
+
+/// Resumes optimization of a checkpointed experiment, writing further checkpoints to the same file.
+fn resume(path: &str) -> Experiment {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let file = File::open(path).unwrap();
+    let checkpoint: Checkpoint = serde_json::from_reader(BufReader::new(file)).unwrap();
+    let mut exp = checkpoint.exp;
+    optimize(&mut exp, Some(checkpoint.ceo_checkpoint), path.to_string());
     exp
 }
 
-fn main() {
-    use std::env;
+fn load(path: &str) -> Experiment {
     use std::fs::File;
     use std::io::BufReader;
 
-    let args = env::args();
-    let exp = if args.len() == 1 {
-        // Run
-        let exp = run();
-        // Save
-        let now = chrono::offset::Local::now();
-        serde_json::to_writer(
-            &File::create(format!("exp{},{}.json", now.date(), now.time())).unwrap(),
-            &exp,
-        )
-        .unwrap();
+    let file = File::open(path).unwrap();
+    let reader = BufReader::new(file);
+    serde_json::from_reader(reader).unwrap()
+}
+
+fn main() {
+    use std::env;
+
+    let mut args = env::args().collect::<Vec<String>>();
+    let master_seed = seed::from_args(&mut args);
+    if args.len() >= 3 && args[1] == "quant" {
+        if args.len() > 4 {
+            panic!("Bad cmd line parameters.");
+        }
+        // Quantize controller and report reward change
+        let exp = load(&args[2]);
+        let num_scenarios = args.get(3).map_or(100, |n| n.parse().unwrap());
+        let quantized_fcn = quant::report(&exp, num_scenarios);
+        save(&quantized_fcn, "quant");
+        return;
+    }
+    if args.len() >= 3 && args[1] == "bounds" {
+        if args.len() > 4 {
+            panic!("Bad cmd line parameters.");
+        }
+        // Certify range of controls over observations of episodes
+        let exp = load(&args[2]);
+        let num_splits = args.get(3).map_or(4, |n| n.parse().unwrap());
+        bounds::report(&exp, num_splits, 10000);
+        return;
+    }
+    if args.len() == 4 && args[1] == "entry" {
+        // Saves an entry of hall of fame as an experiment of its own
+        let mut exp = load(&args[2]);
+        let index: usize = args[3].parse().unwrap();
+        for (k, entry) in exp.hall_of_fame.entries.iter().enumerate() {
+            println!(
+                "entry={} generation={} reward={}",
+                k, entry.generation, entry.reward
+            );
+        }
+        let entry = exp.hall_of_fame.entries.get(index).unwrap_or_else(|| {
+            panic!("Bad cmd line parameters.");
+        });
+        exp.fcn.set_params(entry.params.clone());
+        save(&exp, "entry");
+        return;
+    }
+    let exp = if args.len() == 1 || (args.len() == 3 && (args[1] == "run" || args[1] == "resume")) {
+        // Run or resume
+        let exp = if args.len() == 1 {
+            println!("seed={}", master_seed);
+            run(new_experiment(master_seed))
+        } else if args[1] == "run" {
+            // Continues from params of a saved experiment, under the optimizer in its json
+            let mut exp = load(&args[2]);
+            println!("seed={}", master_seed);
+            exp.optimizer.set_seed(Some(master_seed));
+            run(exp)
+        } else {
+            resume(&args[2])
+        };
+        save(&exp, "exp");
+        exp
+    } else if args.len() == 3 && args[1] == "pareto" {
+        // Optimizes the terms of reward jointly from params of a saved experiment, under its pareto optimizer
+        let mut exp = load(&args[2]);
+        println!("seed={}", master_seed);
+        exp.pareto_optimizer.seed = Some(master_seed);
+        optimize_pareto(&mut exp);
+        save(&exp, "pareto");
+        exp
+    } else if args.len() == 4 && args[1] == "point" {
+        // Saves a point of pareto set as an experiment of its own, then visualizes it
+        let mut exp = load(&args[2]);
+        print_pareto_set(&exp.pareto_set);
+        let index: usize = args[3].parse().unwrap();
+        let point = exp.pareto_set.points.get(index).unwrap_or_else(|| {
+            panic!("Bad cmd line parameters.");
+        });
+        exp.fcn.set_params(point.params.clone());
+        save(&exp, "point");
         exp
     } else {
         if args.len() != 2 {
             panic!("Bad cmd line parameters.");
         }
         // Load from file
-        let args = args.collect::<Vec<String>>();
-        let file = File::open(&args[1]).unwrap();
-        let reader = BufReader::new(file);
-        let exp: Experiment = serde_json::from_reader(reader).unwrap();
-        exp
+        load(&args[1])
     };
     println!("{:?}", exp);
     // Visualize
@@ -106,3 +363,86 @@ fn main() {
         .unwrap();
     event::run(ctx, event_loop, app).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+    use wall_e::ceo::Reward;
+
+    /// Experiment in the format saved before optimizers were selectable, with a bare ceo.
+    const BASELINE_EXPERIMENT: &str = r#"{
+        "fcn": {
+            "layers": [[3, "Linear"], [5, {"LeakyReLu": 0.1}], [2, "Linear"]],
+            "params": {"v": 1, "dim": [32], "data": [-0.90,0.90,0.80,0.70,0.60,0.50,0.40,0.30,0.20,0.10,0.00,-0.10,-0.20,-0.30,-0.40,-0.50,-0.60,-0.70,-0.80,-0.90,0.90,0.80,0.70,0.60,0.50,0.40,0.30,0.20,0.10,0.00,-0.10,-0.20]}
+        },
+        "ceo": {
+            "generations": 1000,
+            "batch_size": 100,
+            "num_evalation_samples": 6,
+            "elite_frac": 0.25,
+            "initial_std": 3.0,
+            "noise_factor": 3.0
+        },
+        "reward": {
+            "start_x_bounds": [20.0, 480.0],
+            "start_y_bounds": [20.0, 480.0],
+            "start_or_bounds": [0.0, 6.28],
+            "radius": 10.0,
+            "goal_x_bounds": [20.0, 480.0],
+            "goal_y_bounds": [20.0, 480.0],
+            "num_episode_ticks": 500
+        }
+    }"#;
+
+    #[test]
+    fn baseline_experiment_loads_with_its_ceo() {
+        let exp = serde_json::from_str::<Experiment>(BASELINE_EXPERIMENT).unwrap();
+        match &exp.optimizer {
+            AnyOptimizer::Ceo(ceo) => {
+                assert_eq!(ceo.generations, 1000);
+                assert_eq!(ceo.num_evalation_samples, 6);
+                assert_eq!(ceo.seed, None);
+            }
+            optimizer => panic!("Loaded {:?} instead of ceo.", optimizer),
+        }
+        assert_eq!(exp.fcn.params().len(), 32);
+        assert!(exp.fcn.at(&Array1::zeros(3)).iter().all(|y| y.is_finite()));
+        // Saved again under its current key, which loads the same
+        let saved = serde_json::to_value(&exp).unwrap();
+        assert!(saved.get("ceo").is_none() && saved.get("optimizer").is_some());
+        let reloaded = serde_json::from_value::<Experiment>(saved).unwrap();
+        assert_eq!(format!("{:?}", reloaded), format!("{:?}", exp));
+    }
+
+    #[test]
+    fn checkpoint_with_bare_ceo_loads() {
+        let exp = serde_json::from_str::<Experiment>(BASELINE_EXPERIMENT).unwrap();
+        let ceo = match &exp.optimizer {
+            AnyOptimizer::Ceo(ceo) => ceo.clone(),
+            optimizer => panic!("Loaded {:?} instead of ceo.", optimizer),
+        };
+        let checkpoint = Checkpoint {
+            ceo_checkpoint: ceo.initial_checkpoint(&exp.fcn),
+            exp: exp,
+        };
+        let mut saved = serde_json::to_value(&checkpoint).unwrap();
+        let saved_exp = saved["exp"].as_object_mut().unwrap();
+        saved_exp.remove("optimizer");
+        saved_exp.insert("ceo".to_string(), serde_json::to_value(&ceo).unwrap());
+        let loaded = serde_json::from_value::<Checkpoint>(saved).unwrap();
+        assert!(matches!(loaded.exp.optimizer, AnyOptimizer::Ceo(_)));
+        assert_eq!(
+            loaded.ceo_checkpoint.params,
+            checkpoint.ceo_checkpoint.params
+        );
+    }
+
+    #[test]
+    fn reward_depends_only_on_its_seed() {
+        let exp = serde_json::from_str::<Experiment>(BASELINE_EXPERIMENT).unwrap();
+        let reward = |seed| exp.reward.reward(&exp.fcn, exp.fcn.params(), 3, seed);
+        assert_eq!(reward(1), reward(1));
+        assert_ne!(reward(1), reward(2));
+    }
+}
@@ -0,0 +1,54 @@
+use super::Experiment;
+use ndarray::prelude::*;
+use ndarray::stack;
+use wall_e::quant::QuantizedFCN;
+
+/// Quantizes the controller of exp, calibrated on states visited in one set of scenarios,
+/// and reports the change in reward on another fixed set of scenarios.
+pub fn report(exp: &Experiment, num_scenarios: usize) -> QuantizedFCN {
+    // Scenarios are reproducible given the seed of exp
+    let mut rng = exp.eval_rng();
+    let mut sample_scenarios = || {
+        (0..num_scenarios)
+            .map(|_| exp.reward.sample_scenario_using(&mut rng))
+            .collect::<Vec<_>>()
+    };
+    let (calibration_scenarios, eval_scenarios) = (sample_scenarios(), sample_scenarios());
+    // Calibrate
+    let mut visited_states = Vec::new();
+    let mut hidden_state = exp.fcn.hidden_state(num_scenarios);
+    exp.reward
+        .rollout(&calibration_scenarios, |states, controls| {
+            visited_states.push(states.clone());
+            controls.assign(
+                &exp.fcn
+                    .step_batch_with(states, exp.fcn.params(), &mut hidden_state),
+            )
+        });
+    let visited_states = visited_states
+        .iter()
+        .map(|states| states.view())
+        .collect::<Vec<ArrayView2<f32>>>();
+    let samples = stack(Axis(0), &visited_states).unwrap();
+    let quantized_fcn = QuantizedFCN::calibrate(&exp.fcn, &samples).unwrap();
+    println!("{}", quantized_fcn);
+    // Compare on same scenarios
+    let mut hidden_state = exp.fcn.hidden_state(num_scenarios);
+    let f32_reward = exp.reward.rollout(&eval_scenarios, |states, controls| {
+        controls.assign(
+            &exp.fcn
+                .step_batch_with(states, exp.fcn.params(), &mut hidden_state),
+        )
+    });
+    let int8_reward = exp.reward.rollout(&eval_scenarios, |states, controls| {
+        controls.assign(&quantized_fcn.at_batch(states))
+    });
+    println!(
+        "num_scenarios={} f32_reward={:?} int8_reward={:?} reward_change={:?}",
+        num_scenarios,
+        f32_reward,
+        int8_reward,
+        int8_reward - f32_reward
+    );
+    quantized_fcn
+}
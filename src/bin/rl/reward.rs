@@ -1,11 +1,14 @@
 use ndarray::prelude::*;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use wall_e::ceo::Reward;
 use wall_e::diff_drive_model::DiffDriveModel;
 use wall_e::fcn::*;
 use wall_e::goal::Goal;
+use wall_e::moceo::MultiReward;
+use wall_e::seed;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct DiffDriveReward {
     start_x_bounds: (f32, f32),
@@ -15,6 +18,9 @@ pub struct DiffDriveReward {
     goal_x_bounds: (f32, f32),
     goal_y_bounds: (f32, f32),
     num_episode_ticks: usize,
+    /// Controller observes unscaled relative state instead of scaled state, and normalizes it itself.
+    #[serde(default)]
+    raw_state: bool,
 }
 
 impl DiffDriveReward {
@@ -35,6 +41,7 @@ impl DiffDriveReward {
             goal_x_bounds: goal_x_bounds,
             goal_y_bounds: goal_y_bounds,
             num_episode_ticks: num_episode_ticks,
+            raw_state: false,
         }
     }
     pub fn start_x_bounds(&self) -> (f32, f32) {
@@ -55,52 +62,180 @@ impl DiffDriveReward {
     pub fn goal_y_bounds(&self) -> (f32, f32) {
         self.goal_y_bounds
     }
+    pub fn set_raw_state(&mut self, raw_state: bool) {
+        self.raw_state = raw_state;
+    }
+
+    /// State observed by the controller.
+    pub fn observation(&self, model: &DiffDriveModel) -> (f32, f32, f32) {
+        if self.raw_state {
+            model.relative_state()
+        } else {
+            model.scaled_state()
+        }
+    }
+
+    /// Bounds of the unscaled relative state of a freshly spawned agent.
+    pub fn raw_state_bounds(&self) -> Vec<(f32, f32)> {
+        vec![
+            (
+                self.goal_x_bounds.0 - self.start_x_bounds.1,
+                self.goal_x_bounds.1 - self.start_x_bounds.0,
+            ),
+            (
+                self.goal_y_bounds.0 - self.start_y_bounds.1,
+                self.goal_y_bounds.1 - self.start_y_bounds.0,
+            ),
+            self.start_or_bounds,
+        ]
+    }
+
+    /// Bounds of every observation of an episode, none if unbounded.
+    /// The agent moves at most max |v| and turns at most max |w| per second over the ticks of an episode,
+    /// which widens the bounds of the relative goal coordinates and orientation of a freshly spawned agent.
+    /// Scaled coordinates are divided by the spawn distance to goal, so they are unbounded if it can be 0.
+    pub fn episode_observation_bounds(&self) -> Option<Vec<(f32, f32)>> {
+        let max_v = DiffDriveModel::V_BOUNDS
+            .0
+            .abs()
+            .max(DiffDriveModel::V_BOUNDS.1.abs());
+        let max_w = DiffDriveModel::W_BOUNDS
+            .0
+            .abs()
+            .max(DiffDriveModel::W_BOUNDS.1.abs());
+        let duration = Self::TICK_SECS * self.num_episode_ticks as f32;
+        let (reach, turn) = (max_v * duration, max_w * duration);
+        let or_bounds = (self.start_or_bounds.0 - turn, self.start_or_bounds.1 + turn);
+        if self.raw_state {
+            let bounds = self.raw_state_bounds();
+            return Some(vec![
+                (bounds[0].0 - reach, bounds[0].1 + reach),
+                (bounds[1].0 - reach, bounds[1].1 + reach),
+                or_bounds,
+            ]);
+        }
+        // Spawn distance is at least the distance b/w the start and goal regions
+        let gap =
+            |start: (f32, f32), goal: (f32, f32)| (goal.0 - start.1).max(start.0 - goal.1).max(0.0);
+        let min_dist = gap(self.start_x_bounds, self.goal_x_bounds)
+            .hypot(gap(self.start_y_bounds, self.goal_y_bounds));
+        if min_dist <= 0.0 {
+            return None;
+        }
+        let max_coordinate = 1.0 + reach / min_dist;
+        Some(vec![
+            (-max_coordinate, max_coordinate),
+            (-max_coordinate, max_coordinate),
+            or_bounds,
+        ])
+    }
 }
 
-impl Reward for DiffDriveReward {
-    fn reward(&self, fcn: &FCN, params: &Array1<f32>, num_episodes: usize) -> f32 {
-        let mut cumulative_reward = 0.0;
-        for _ in 0..num_episodes {
-            // Set goal
-            let goal_coordinates =
-                Goal::in_region(self.goal_x_bounds, self.goal_y_bounds).coordinates();
-            // Spawn agent
-            let mut model = DiffDriveModel::spawn_randomly(
-                self.start_x_bounds,
-                self.start_y_bounds,
-                self.start_or_bounds,
-                self.radius,
-                goal_coordinates,
-            );
-            // Start calculating reward
-            let mut episode_reward = 0.0;
-            for tick in 0..self.num_episode_ticks {
-                // Curr state
-                let (x, y, or_in_rad) = model.scaled_state();
-                // Control for curr state
-                let control = fcn.at_with(&arr1(&[x, y, or_in_rad]), params);
+/// Goal coordinates and start pose (x, y, or_in_rad) of an episode.
+pub type Scenario = ((f32, f32), (f32, f32, f32));
+
+impl DiffDriveReward {
+    /// Distance to goal, angular deviation and rotational jitter summed over ticks and negated,
+    /// and the bonus of reaching the goal and stopping on it.
+    /// Reward weighs them as 30, 1, 1 and 200.
+    pub const NUM_OBJECTIVES: usize = 4;
+    /// Simulated time b/w controls.
+    pub const TICK_SECS: f32 = 0.1;
+
+    pub fn sample_scenario_using<R: Rng + ?Sized>(&self, rng: &mut R) -> Scenario {
+        // Set goal
+        let goal_coordinates =
+            Goal::in_region_using(self.goal_x_bounds, self.goal_y_bounds, rng).coordinates();
+        // Spawn agent
+        let model = DiffDriveModel::spawn_randomly_using(
+            self.start_x_bounds,
+            self.start_y_bounds,
+            self.start_or_bounds,
+            self.radius,
+            goal_coordinates,
+            rng,
+        );
+        (goal_coordinates, model.state())
+    }
+
+    /// Average reward of controller over scenarios.
+    pub fn rollout<F: FnMut(&Array2<f32>, &mut Array2<f32>)>(
+        &self,
+        scenarios: &[Scenario],
+        controller: F,
+    ) -> f32 {
+        self.rollout_objectives(scenarios, controller).0
+    }
+
+    /// Average reward of controller over scenarios, along with the average of its unweighted terms, see objectives.
+    /// All episodes are rolled out in lockstep so that the controller is called once per tick for the whole batch,
+    /// with observed states as rows of its input and controls written into rows of its output.
+    pub fn rollout_objectives<F: FnMut(&Array2<f32>, &mut Array2<f32>)>(
+        &self,
+        scenarios: &[Scenario],
+        mut controller: F,
+    ) -> (f32, Array1<f32>) {
+        let num_episodes = scenarios.len();
+        // Spawn agents at their start poses, each with its own goal
+        let mut models = scenarios
+            .iter()
+            .map(|(goal_coordinates, (x, y, or_in_rad))| {
+                DiffDriveModel::at(*x, *y, *or_in_rad, self.radius, *goal_coordinates)
+            })
+            .collect::<Vec<DiffDriveModel>>();
+        // Start calculating reward
+        let mut episode_rewards = vec![0.0; num_episodes];
+        let mut objectives = Array1::<f32>::zeros(Self::NUM_OBJECTIVES);
+        let mut states = Array2::<f32>::zeros((num_episodes, 3));
+        let mut controls = Array2::<f32>::zeros((num_episodes, 2));
+        for tick in 0..self.num_episode_ticks {
+            // Curr states
+            for (mut state, model) in states.genrows_mut().into_iter().zip(models.iter()) {
+                let (x, y, or_in_rad) = self.observation(model);
+                state[0] = x;
+                state[1] = y;
+                state[2] = or_in_rad;
+            }
+            // Controls for curr states
+            controller(&states, &mut controls);
+            for ((model, control), episode_reward) in models
+                .iter_mut()
+                .zip(controls.genrows())
+                .zip(episode_rewards.iter_mut())
+            {
                 let (v, w) = (control[[0]], control[[1]]);
                 // Apply control
                 model.set_control(v, w);
-                model.update(0.1).unwrap();
+                model.update(Self::TICK_SECS).unwrap();
                 // Next state
                 let (x, y, or_in_rad) = model.scaled_state();
-                // Makes agent orient towards goal
+                // Makes agent orient towards goal, any orientation is towards it when on it
                 let (x_hat, y_hat) = {
                     let norm = (x * x + y * y).sqrt();
-                    (x / norm, y / norm)
+                    if norm > 0.0 {
+                        (x / norm, y / norm)
+                    } else {
+                        (or_in_rad.cos(), or_in_rad.sin())
+                    }
                 };
                 let angular_deviation = ((x_hat - or_in_rad.cos()).powf(2.0)
                     + (y_hat - or_in_rad.sin()).powf(2.0))
                 .sqrt()
                     * (1.0 / (1.0 + tick as f32));
-                episode_reward -= angular_deviation;
+                *episode_reward -= angular_deviation;
                 // Removes rotational jitter
-                episode_reward -= w.abs();
+                *episode_reward -= w.abs();
                 // Makes agent translate towards goal
                 let dist = (x * x + y * y).sqrt();
-                episode_reward -= dist * 30.0;
+                *episode_reward -= dist * 30.0;
+                objectives[0] -= dist;
+                objectives[1] -= angular_deviation;
+                objectives[2] -= w.abs();
             }
+        }
+        let mut cumulative_reward = 0.0;
+        for (model, episode_reward) in models.iter().zip(episode_rewards.iter()) {
+            let mut episode_reward = *episode_reward;
             // Makes agent reach the goal at the end of episode
             let (x, y, _or_in_rad) = model.scaled_state();
             let final_dist = (x * x + y * y).sqrt();
@@ -109,11 +244,61 @@ impl Reward for DiffDriveReward {
             let (v, w) = model.control();
             episode_reward += 200.0 * (-v.abs()).exp() * (-final_dist).exp();
             episode_reward += 200.0 * (-w.abs()).exp() * (-final_dist).exp();
+            objectives[3] += (-final_dist).exp()
+                + (-v.abs()).exp() * (-final_dist).exp()
+                + (-w.abs()).exp() * (-final_dist).exp();
 
             cumulative_reward += episode_reward;
         }
 
         let average_reward = cumulative_reward / num_episodes as f32;
-        average_reward
+        (average_reward, objectives / num_episodes as f32)
+    }
+
+    /// Rollout of controller on num_episodes scenarios, every one sampled from its own stream derived from seed.
+    fn evaluate(
+        &self,
+        fcn: &FCN,
+        params: &Array1<f32>,
+        num_episodes: usize,
+        seed: u64,
+    ) -> (f32, Array1<f32>) {
+        let scenarios = (0..num_episodes)
+            .map(|episode| {
+                self.sample_scenario_using(&mut seed::rng(seed::derive(seed, episode as u64)))
+            })
+            .collect::<Vec<Scenario>>();
+        let mut hidden_state = fcn.hidden_state(num_episodes);
+        // Feed-forward controllers are evaluated as matrix-matrix products without allocating per tick
+        let mut workspace = fcn.batch_workspace(num_episodes);
+        self.rollout_objectives(&scenarios, |states, controls| {
+            if fcn.recurrent().is_empty() {
+                fcn.at_batch_into(states, params, &mut workspace, controls)
+            } else {
+                controls.assign(&fcn.step_batch_with(states, params, &mut hidden_state))
+            }
+        })
+    }
+}
+
+impl Reward for DiffDriveReward {
+    fn reward(&self, fcn: &FCN, params: &Array1<f32>, num_episodes: usize, seed: u64) -> f32 {
+        self.evaluate(fcn, params, num_episodes, seed).0
+    }
+}
+
+impl MultiReward for DiffDriveReward {
+    fn num_objectives(&self) -> usize {
+        Self::NUM_OBJECTIVES
+    }
+
+    fn rewards(
+        &self,
+        fcn: &FCN,
+        params: &Array1<f32>,
+        num_episodes: usize,
+        seed: u64,
+    ) -> Array1<f32> {
+        self.evaluate(fcn, params, num_episodes, seed).1
     }
 }
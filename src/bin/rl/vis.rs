@@ -4,12 +4,14 @@ use ggez::nalgebra::Point2;
 use ggez::*;
 use ndarray::prelude::*;
 use wall_e::diff_drive_model::DiffDriveModel;
+use wall_e::fcn::HiddenState;
 use wall_e::goal::Goal;
 
 pub struct Visualizer {
     exp: Experiment,
     model: DiffDriveModel,
     goal: Goal,
+    hidden_state: HiddenState,
     time: usize,
     is_paused: bool,
     model_start_bound_rect: graphics::Rect,
@@ -35,6 +37,7 @@ impl Visualizer {
         // Restart
         self.goal = goal;
         self.model = model;
+        self.hidden_state = self.exp.fcn.hidden_state(1);
         self.time = 0;
     }
 }
@@ -57,10 +60,12 @@ impl From<Experiment> for Visualizer {
             exp.reward.radius(),
             goal.coordinates(),
         );
+        let hidden_state = exp.fcn.hidden_state(1);
         Visualizer {
             exp: exp,
             model: model,
             goal: goal,
+            hidden_state: hidden_state,
             time: 0,
             is_paused: false,
             model_start_bound_rect: model_start_bound_rect,
@@ -75,8 +80,11 @@ impl event::EventHandler for Visualizer {
         if self.is_paused {
             return Ok(());
         }
-        let (x, y, or_in_rad) = self.model.scaled_state();
-        let control = self.exp.fcn.at(&arr1(&[x, y, or_in_rad]));
+        let (x, y, or_in_rad) = self.exp.reward.observation(&self.model);
+        let control = self
+            .exp
+            .fcn
+            .step(&arr1(&[x, y, or_in_rad]), &mut self.hidden_state);
         let (v, w) = (control[[0]], control[[1]]);
         self.model.set_control(v, w);
         self.model.update(self.dt)?;
@@ -1,40 +1,147 @@
 use ndarray::prelude::*;
 
 extern crate wall_e;
-use rand::Rng;
+use ndarray_rand::rand_distr::Uniform;
+use ndarray_rand::RandomExt;
+use std::env;
 use wall_e::ceo::{Reward, CEO};
+use wall_e::cma_es::CmaEs;
+use wall_e::es::ES;
 use wall_e::fcn::*;
+use wall_e::gd::{Minibatches, Update, GD};
+use wall_e::interrupt;
+use wall_e::optimizer::{AnyOptimizer, Optimizer};
+use wall_e::quant::QuantizedFCN;
+use wall_e::seed;
 
 struct SinReward;
+impl SinReward {
+    const MAX_X: f32 = 6.28;
+}
+
 impl Reward for SinReward {
-    fn reward(&self, fcn: &FCN, params: &Array1<f32>, num_samples: usize) -> f32 {
-        let mut rng = rand::thread_rng();
-        let max_x = 6.28;
-        let cumulative_reward = (0..num_samples)
-            .map(|_| {
-                let x = rng.gen::<f32>() * max_x;
-                let y_true = x.sin();
-                let y_pred = fcn.at_with(&arr1(&[x]), &params)[0];
-                -(y_true - y_pred) * (y_true - y_pred)
-            })
-            .sum::<f32>();
+    fn reward(&self, fcn: &FCN, params: &Array1<f32>, num_samples: usize, seed: u64) -> f32 {
+        let xs = Array::random_using(
+            (num_samples, 1),
+            Uniform::new(0.0, SinReward::MAX_X),
+            &mut seed::rng(seed),
+        );
+        let y_true = xs.column(0).mapv(f32::sin);
+        let y_pred = fcn.at_batch_with(&xs, params);
+        let cumulative_reward = -(&y_true - &y_pred.column(0)).mapv(|e| e * e).sum();
         cumulative_reward / num_samples as f32
     }
 }
 
+impl Minibatches for SinReward {
+    fn minibatch(&self, batch_size: usize, seed: u64) -> (Array2<f32>, Array2<f32>) {
+        let xs = Array::random_using(
+            (batch_size, 1),
+            Uniform::new(0.0, SinReward::MAX_X),
+            &mut seed::rng(seed),
+        );
+        let ys = xs.mapv(f32::sin);
+        (xs, ys)
+    }
+}
+
+/// Quantizes a saved fit, calibrated on a grid over the fitted range, and reports mse of both models on it.
+fn report_quantization(path: &str) {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let file = File::open(path).unwrap();
+    let fcn: FCN = serde_json::from_reader(BufReader::new(file)).unwrap();
+    println!("{}", fcn);
+    let num_samples = 1000;
+    let xs = Array::linspace(0.0, SinReward::MAX_X, num_samples)
+        .into_shape((num_samples, 1))
+        .unwrap();
+    let ys = xs.mapv(f32::sin);
+    let quantized_fcn = QuantizedFCN::calibrate(&fcn, &xs).unwrap();
+    println!("{}", quantized_fcn);
+    let mse = |y_pred: Array2<f32>| (y_pred - &ys).mapv(|e| e * e).mean().unwrap();
+    let f32_mse = mse(fcn.at_batch(&xs));
+    let int8_mse = mse(quantized_fcn.at_batch(&xs));
+    println!(
+        "f32_mse={:?} int8_mse={:?} mse_change={:?}",
+        f32_mse,
+        int8_mse,
+        int8_mse - f32_mse
+    );
+}
+
 fn main() {
-    let mut fcn = FCN::new(vec![
-        (1, Activation::Linear),
-        (5, Activation::Sigmoid),
-        (5, Activation::Sigmoid),
-        (5, Activation::Sigmoid),
-        (1, Activation::Linear),
-    ]);
+    let mut args = env::args().collect::<Vec<String>>();
+    let master_seed = seed::from_args(&mut args);
+    if args.len() == 3 && args[1] == "quant" {
+        report_quantization(&args[2]);
+        return;
+    }
+    println!("seed={}", master_seed);
+    let mut fcn = FCN::new_with(
+        vec![
+            (1, Activation::Linear),
+            (5, Activation::Sigmoid),
+            (5, Activation::Sigmoid),
+            (5, Activation::Sigmoid),
+            (1, Activation::Linear),
+        ],
+        &Init::Uniform(0.0, 1.0),
+        &mut seed::rng(seed::derive(master_seed, seed::INIT_STREAM)),
+    );
+    // Raw x is mapped onto [-1, 1]
+    fcn.set_input_normalizer(Some(Normalizer::from_bounds(&[(0.0, SinReward::MAX_X)])))
+        .unwrap();
     println!("{}", fcn);
-    let ceo = CEO::default();
-    println!("{:?}", ceo);
     let reward = SinReward;
-    let _th_std = ceo.optimize(&mut fcn, &reward).unwrap();
+    let any_optimizer = match args.len() {
+        1 => Some(AnyOptimizer::Ceo(CEO::default())),
+        2 if args[1] == "cmaes" => Some(AnyOptimizer::CmaEs(CmaEs::default())),
+        2 if args[1] == "es" => Some(AnyOptimizer::Es(ES::default())),
+        3 if args[1] == "optimizer" => {
+            use std::fs::File;
+            use std::io::BufReader;
+
+            let file = File::open(&args[2]).unwrap();
+            Some(serde_json::from_reader(BufReader::new(file)).unwrap())
+        }
+        _ => None,
+    };
+    // Ctrl-C stops optimization early, the fit so far is still plotted and saved
+    interrupt::install_handler().unwrap();
+    let optimizer = if let Some(mut any_optimizer) = any_optimizer {
+        any_optimizer.set_seed(Some(master_seed));
+        println!("{:?}", any_optimizer);
+        // Fit so far is still plotted and saved
+        if let Err(error) = any_optimizer.optimize(&mut fcn, &reward) {
+            println!("optimization failed: {}", error);
+        }
+        format!("{:?}", any_optimizer)
+    } else {
+        if args.len() != 2 {
+            panic!("Bad cmd line parameters.");
+        }
+        let mut gd = GD {
+            seed: Some(master_seed),
+            ..GD::default()
+        };
+        gd.update = match args[1].as_str() {
+            "sgd" => Update::Sgd,
+            "momentum" => Update::Momentum(0.9),
+            "adam" => gd.update,
+            _ => panic!("Bad cmd line parameters."),
+        };
+        println!("{:?}", gd);
+        let (inputs, targets) =
+            reward.minibatch(gd.batch_size, seed::derive(master_seed, seed::EVAL_STREAM));
+        println!(
+            "grad check max relative error={:?}",
+            fcn.check_mse_grad_with(&inputs, &targets, fcn.params(), 1e-2)
+        );
+        let _mse = gd.optimize(&mut fcn, &reward);
+        format!("{:?}", gd)
+    };
 
     use gnuplot::*;
     let mut fg = Figure::new();
@@ -60,10 +167,15 @@ fn main() {
         .set_y_grid(true)
         .set_title(
             &format!(
-                "reward={}\nmodel={}\nceo={:?}\n",
-                reward.reward(&fcn, &fcn.params(), ceo.num_evalation_samples),
+                "reward={}\nmodel={}\noptimizer={}\n",
+                reward.reward(
+                    &fcn,
+                    &fcn.params(),
+                    CEO::default().num_evalation_samples,
+                    seed::derive(master_seed, seed::EVAL_STREAM),
+                ),
                 fcn,
-                ceo,
+                optimizer,
             ),
             &[],
         );
@@ -0,0 +1,511 @@
+//! Sound bounds on the outputs of a fcn over a box of inputs, by interval and symbolic-linear bound propagation.
+//! Every bound is widened by a bound on its floating point rounding error, so that it holds for FCN::at as well.
+use crate::fcn::*;
+use crate::float::Float;
+use ndarray::prelude::*;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BoundsError {
+    /// Only feed-forward models can be bounded.
+    RecurrentLayers,
+    InputLenMismatch {
+        expected: usize,
+        found: usize,
+    },
+    /// Input interval with lo > hi or a nan bound.
+    InvalidInterval(usize),
+}
+
+impl fmt::Display for BoundsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BoundsError::RecurrentLayers => {
+                write!(f, "Trying to bound a model with recurrent layers.")
+            }
+            BoundsError::InputLenMismatch { expected, found } => write!(
+                f,
+                "Invalid input bounds len for fcn, expected {}, found {}.",
+                expected, found
+            ),
+            BoundsError::InvalidInterval(i) => {
+                write!(
+                    f,
+                    "Trying to bound over an invalid interval for input {}.",
+                    i
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for BoundsError {}
+
+/// Bounds (lo, hi) of every output of fcn for any input within given per-dimension bounds (lo, hi).
+pub fn interval_bounds<F: Float>(
+    fcn: &FCN<F>,
+    input_bounds: &[(F, F)],
+) -> Result<Vec<(F, F)>, BoundsError> {
+    propagate(fcn, input_bounds, false)
+}
+
+/// Same as interval_bounds, but also carries linear lower and upper bounds of every unit in terms of the inputs.
+/// These keep the correlation b/w units that intervals lose, so bounds are at least as tight.
+pub fn symbolic_bounds<F: Float>(
+    fcn: &FCN<F>,
+    input_bounds: &[(F, F)],
+) -> Result<Vec<(F, F)>, BoundsError> {
+    propagate(fcn, input_bounds, true)
+}
+
+/// Per-unit lower and upper bounds.
+#[derive(Clone)]
+struct Intervals<F> {
+    lo: Array1<F>,
+    hi: Array1<F>,
+}
+
+impl<F: Float> Intervals<F> {
+    /// Max abs value of every unit.
+    fn magnitude(&self) -> Array1<F> {
+        let mut magnitude = self.lo.mapv(F::abs);
+        magnitude.zip_mut_with(&self.hi, |m, &hi| *m = m.max(hi.abs()));
+        magnitude
+    }
+
+    fn widen(&mut self, margin: &Array1<F>) {
+        self.lo = &self.lo - margin;
+        self.hi = &self.hi + margin;
+    }
+
+    fn intersect(&mut self, other: &Intervals<F>) {
+        self.lo
+            .zip_mut_with(&other.lo, |lo, &other| *lo = lo.max(other));
+        self.hi
+            .zip_mut_with(&other.hi, |hi, &other| *hi = hi.min(other));
+    }
+
+    /// Bounds of x * scale + offset, per unit.
+    fn scale(&self, scale: &Array1<F>, offset: &Array1<F>) -> Intervals<F> {
+        let (a, b) = (&self.lo * scale + offset, &self.hi * scale + offset);
+        let mut lo = a.clone();
+        lo.zip_mut_with(&b, |lo, &b| *lo = lo.min(b));
+        let mut hi = a;
+        hi.zip_mut_with(&b, |hi, &b| *hi = hi.max(b));
+        let mut intervals = Intervals { lo: lo, hi: hi };
+        let magnitude = &self.magnitude() * &scale.mapv(F::abs) + &offset.mapv(F::abs);
+        intervals.widen(&margin(2, &magnitude));
+        intervals
+    }
+
+    /// Adds bounds of matrix.dot(x) for x within bounds.
+    fn add_product(&mut self, matrix: &ArrayView2<F>, bounds: &Intervals<F>) {
+        let (positive, negative) = split(matrix);
+        self.lo = &self.lo + &positive.dot(&bounds.lo) + &negative.dot(&bounds.hi);
+        self.hi = &self.hi + &positive.dot(&bounds.hi) + &negative.dot(&bounds.lo);
+    }
+}
+
+/// Linear bounds lower.0 x + lower.1 <= unit <= upper.0 x + upper.1 over inputs x, one row per unit.
+#[derive(Clone)]
+struct LinearBounds<F> {
+    lower: (Array2<F>, Array1<F>),
+    upper: (Array2<F>, Array1<F>),
+}
+
+impl<F: Float> LinearBounds<F> {
+    /// Adds linear bounds of matrix.dot(x) for x within bounds.
+    fn add_product(&mut self, matrix: &ArrayView2<F>, bounds: &LinearBounds<F>) {
+        let (positive, negative) = split(matrix);
+        self.lower.0 =
+            &self.lower.0 + &positive.dot(&bounds.lower.0) + &negative.dot(&bounds.upper.0);
+        self.lower.1 =
+            &self.lower.1 + &positive.dot(&bounds.lower.1) + &negative.dot(&bounds.upper.1);
+        self.upper.0 =
+            &self.upper.0 + &positive.dot(&bounds.upper.0) + &negative.dot(&bounds.lower.0);
+        self.upper.1 =
+            &self.upper.1 + &positive.dot(&bounds.upper.1) + &negative.dot(&bounds.lower.1);
+    }
+
+    /// Interval bounds over the box of inputs.
+    fn concretize(&self, inputs: &Intervals<F>) -> (Intervals<F>, Array1<F>) {
+        let mut intervals = Intervals {
+            lo: self.lower.1.clone(),
+            hi: self.upper.1.clone(),
+        };
+        let (positive, negative) = split(&self.lower.0.view());
+        intervals.lo = intervals.lo + positive.dot(&inputs.lo) + negative.dot(&inputs.hi);
+        let (positive, negative) = split(&self.upper.0.view());
+        intervals.hi = intervals.hi + positive.dot(&inputs.hi) + negative.dot(&inputs.lo);
+        let magnitude = intervals.magnitude();
+        (intervals, magnitude)
+    }
+}
+
+/// (positive part, negative part) of matrix.
+fn split<F: Float>(matrix: &ArrayView2<F>) -> (Array2<F>, Array2<F>) {
+    (
+        matrix.mapv(|w| w.max(F::zero())),
+        matrix.mapv(|w| w.min(F::zero())),
+    )
+}
+
+/// Bound on rounding error of a sum of num_terms products of given magnitude,
+/// counted twice as both the bound and the evaluation are rounded.
+fn margin<F: Float>(num_terms: usize, magnitude: &Array1<F>) -> Array1<F> {
+    let gamma = F::from_usize(2 * (num_terms + 1)) * F::epsilon();
+    magnitude.mapv(|m| gamma * m + F::min_positive_value())
+}
+
+/// Minima of swish and of tanh approximation of gelu, where they turn from decreasing to increasing.
+const SWISH_ARGMIN: f32 = -1.278_464_6;
+const GELU_ARGMIN: f32 = -0.752_461_4;
+
+/// Bounds of activations of units with pre-activations within z.
+fn activation_bounds<F: Float>(activation: &Activation, z: &Intervals<F>) -> Intervals<F> {
+    let len = z.lo.len();
+    let (zero, one) = (F::zero(), F::one());
+    let mut bounds = match activation {
+        Activation::Softmax => {
+            // Unit k is 1 / (1 + sum_{j != k} exp(z_j - z_k)), which increases with z_k and decreases with z_j
+            let bound = |own: &Array1<F>, others: &Array1<F>| {
+                Array1::from_shape_fn(len, |k| {
+                    let sum = (0..len)
+                        .filter(|j| *j != k)
+                        .fold(zero, |sum, j| sum + (others[j] - own[k]).exp());
+                    one / (one + sum)
+                })
+            };
+            Intervals {
+                lo: bound(&z.lo, &z.hi),
+                hi: bound(&z.hi, &z.lo),
+            }
+        }
+        _ => {
+            // Elementwise activations are monotone b/w these points,
+            // so their extremes over an interval are at its ends or at the points inside it
+            let points = [zero, F::from_f32(SWISH_ARGMIN), F::from_f32(GELU_ARGMIN)];
+            let candidates = Array2::from_shape_fn((2 + points.len(), len), |(c, k)| match c {
+                0 => z.lo[k],
+                1 => z.hi[k],
+                _ => points[c - 2].max(z.lo[k]).min(z.hi[k]),
+            });
+            let activations = activation.activate(candidates);
+            Intervals {
+                lo: activations.fold_axis(Axis(0), F::infinity(), |lo, &a| lo.min(a)),
+                hi: activations.fold_axis(Axis(0), F::neg_infinity(), |hi, &a| hi.max(a)),
+            }
+        }
+    };
+    // Math functions are not correctly rounded, so a few more ulps are allowed
+    let mut magnitude = bounds.magnitude() + one;
+    if let Activation::ScaledTanh(unit_bounds) = activation {
+        for (m, (lo, hi)) in magnitude.iter_mut().zip(unit_bounds.iter()) {
+            *m += F::from_f32(lo.abs().max(hi.abs()));
+        }
+    }
+    bounds.widen(&margin(len + 4, &magnitude));
+    bounds
+}
+
+/// Linear bounds of activations of units, given linear bounds zs and interval bounds z of pre-activations,
+/// and interval bounds of activations a.
+/// Piecewise linear convex activations are relaxed by a line below and a chord above,
+/// other activations by constants.
+fn activation_linear_bounds<F: Float>(
+    activation: &Activation,
+    zs: &LinearBounds<F>,
+    z: &Intervals<F>,
+    a: &Intervals<F>,
+) -> LinearBounds<F> {
+    let leak = match activation {
+        Activation::Linear => return zs.clone(),
+        Activation::ReLu => Some(F::zero()),
+        Activation::LeakyReLu(leak) if *leak >= 0.0 && *leak <= 1.0 => Some(F::from_f32(*leak)),
+        _ => None,
+    };
+    let num_inputs = zs.lower.0.ncols();
+    let mut bounds = LinearBounds {
+        lower: (Array2::zeros((z.lo.len(), num_inputs)), a.lo.clone()),
+        upper: (Array2::zeros((z.lo.len(), num_inputs)), a.hi.clone()),
+    };
+    let leak = match leak {
+        Some(leak) => leak,
+        None => return bounds,
+    };
+    let f = |x: F| if x > F::zero() { x } else { x * leak };
+    for k in 0..z.lo.len() {
+        let (lo, hi) = (z.lo[k], z.hi[k]);
+        // (slope, offset) of lines below and above activation over [lo, hi]
+        let (lower, upper) = if lo >= F::zero() {
+            ((F::one(), F::zero()), (F::one(), F::zero()))
+        } else if hi <= F::zero() {
+            ((leak, F::zero()), (leak, F::zero()))
+        } else {
+            let slope = (f(hi) - f(lo)) / (hi - lo);
+            let lower_slope = if hi > -lo { F::one() } else { leak };
+            ((lower_slope, F::zero()), (slope, f(lo) - slope * lo))
+        };
+        // Slopes are non-negative, so lower bounds of z bound lines below and upper bounds lines above
+        let mut row = bounds.lower.0.row_mut(k);
+        row.assign(&(&zs.lower.0.row(k) * lower.0));
+        bounds.lower.1[k] = zs.lower.1[k] * lower.0 + lower.1;
+        let mut row = bounds.upper.0.row_mut(k);
+        row.assign(&(&zs.upper.0.row(k) * upper.0));
+        bounds.upper.1[k] = zs.upper.1[k] * upper.0 + upper.1;
+    }
+    bounds
+}
+
+fn propagate<F: Float>(
+    fcn: &FCN<F>,
+    input_bounds: &[(F, F)],
+    symbolic: bool,
+) -> Result<Vec<(F, F)>, BoundsError> {
+    if !fcn.recurrent().is_empty() {
+        return Err(BoundsError::RecurrentLayers);
+    }
+    let layers = fcn.layers();
+    if input_bounds.len() != layers[0].0 {
+        return Err(BoundsError::InputLenMismatch {
+            expected: layers[0].0,
+            found: input_bounds.len(),
+        });
+    }
+    if let Some(i) = input_bounds
+        .iter()
+        .position(|(lo, hi)| lo > hi || lo.is_nan() || hi.is_nan())
+    {
+        return Err(BoundsError::InvalidInterval(i));
+    }
+    let num_inputs = input_bounds.len();
+    let inputs = Intervals {
+        lo: input_bounds.iter().map(|(lo, _)| *lo).collect(),
+        hi: input_bounds.iter().map(|(_, hi)| *hi).collect(),
+    };
+    // Pre-activations of input layer
+    let (z, zs) = match fcn.input_normalizer() {
+        Some(normalizer) => {
+            let (center, divisor) = normalizer.center_and_divisor();
+            let scale = divisor.mapv(|d| F::one() / d);
+            let offset = center.mapv(F::neg) * &scale;
+            let coefficients = Array2::from_diag(&scale);
+            let zs = LinearBounds {
+                lower: (coefficients.clone(), offset.clone()),
+                upper: (coefficients, offset.clone()),
+            };
+            (inputs.scale(&scale, &offset), zs)
+        }
+        None => {
+            let identity = Array2::eye(num_inputs);
+            let zs = LinearBounds {
+                lower: (identity.clone(), Array1::zeros(num_inputs)),
+                upper: (identity, Array1::zeros(num_inputs)),
+            };
+            (inputs.clone(), zs)
+        }
+    };
+    let (mut z, mut zs) = (z, if symbolic { Some(zs) } else { None });
+    // Interval and linear bounds of outputs of every layer
+    let mut outputs: Vec<(Intervals<F>, Option<LinearBounds<F>>)> =
+        Vec::with_capacity(layers.len());
+    // Number of terms accumulated in linear bounds so far, which their rounding error grows with
+    let mut num_terms = num_inputs + 2;
+    for i in 0..layers.len() {
+        if i > 0 {
+            let (weights, bias) = (fcn.layer_weights(i), fcn.layer_bias(i));
+            let dof = layers[i].0;
+            z = Intervals {
+                lo: bias.to_owned(),
+                hi: bias.to_owned(),
+            };
+            zs = zs.map(|_| LinearBounds {
+                lower: (Array2::zeros((dof, num_inputs)), bias.to_owned()),
+                upper: (Array2::zeros((dof, num_inputs)), bias.to_owned()),
+            });
+            let mut layer_terms = layers[i - 1].0;
+            let mut magnitude =
+                weights.mapv(F::abs).dot(&outputs[i - 1].0.magnitude()) + bias.mapv(F::abs);
+            z.add_product(&weights, &outputs[i - 1].0);
+            if let (Some(zs), Some(from)) = (&mut zs, &outputs[i - 1].1) {
+                zs.add_product(&weights, from);
+            }
+            for (j, skip) in fcn.skips().iter().enumerate().filter(|(_, s)| s.to() == i) {
+                let identity;
+                let weights = match fcn.skip_weights(j) {
+                    Some(weights) => weights,
+                    None => {
+                        identity = Array2::eye(dof);
+                        identity.view()
+                    }
+                };
+                let (from, from_symbolic) = &outputs[skip.from()];
+                layer_terms += weights.ncols();
+                magnitude = magnitude + weights.mapv(F::abs).dot(&from.magnitude());
+                z.add_product(&weights, from);
+                if let (Some(zs), Some(from)) = (&mut zs, from_symbolic) {
+                    zs.add_product(&weights, from);
+                }
+            }
+            z.widen(&margin(layer_terms, &magnitude));
+            num_terms += layer_terms + 1;
+        }
+        let activation = &layers[i].1;
+        let a = match &zs {
+            Some(zs) => {
+                let (mut concrete, magnitude) = zs.concretize(&inputs);
+                concrete.widen(&margin(num_terms, &(magnitude + z.magnitude())));
+                z.intersect(&concrete);
+                let mut a = activation_bounds(activation, &z);
+                let a_symbolic = activation_linear_bounds(activation, zs, &z, &a);
+                let (mut concrete, magnitude) = a_symbolic.concretize(&inputs);
+                concrete.widen(&margin(num_terms, &(magnitude + a.magnitude())));
+                a.intersect(&concrete);
+                (a, Some(a_symbolic))
+            }
+            None => (activation_bounds(activation, &z), None),
+        };
+        outputs.push(a);
+    }
+    let (mut bounds, _) = outputs.pop().unwrap();
+    if let Some(normalizer) = fcn.output_normalizer() {
+        let (center, divisor) = normalizer.center_and_divisor();
+        bounds = bounds.scale(&divisor, &center);
+    }
+    Ok(bounds
+        .lo
+        .iter()
+        .cloned()
+        .zip(bounds.hi.iter().cloned())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seed;
+    use rand::Rng;
+
+    /// Checks that outputs of fcn on inputs sampled in random boxes, and on their corners, lie within bounds.
+    fn check_soundness(fcn: &FCN<f32>, seed: u64) {
+        let mut rng = seed::rng(seed);
+        let num_inputs = fcn.layers()[0].0;
+        for _ in 0..20 {
+            let input_bounds = (0..num_inputs)
+                .map(|_| {
+                    let center = rng.gen_range(-3.0, 3.0);
+                    // Some intervals are degenerate
+                    let radius = if rng.gen_range(0.0, 1.0) < 0.2 {
+                        0.0
+                    } else {
+                        rng.gen_range(0.0, 2.0)
+                    };
+                    (center - radius, center + radius)
+                })
+                .collect::<Vec<(f32, f32)>>();
+            let interval = interval_bounds(fcn, &input_bounds).unwrap();
+            let symbolic = symbolic_bounds(fcn, &input_bounds).unwrap();
+            let mut inputs = Array2::from_shape_fn((200, num_inputs), |(_, k)| {
+                let (lo, hi) = input_bounds[k];
+                if lo < hi {
+                    rng.gen_range(lo, hi)
+                } else {
+                    lo
+                }
+            });
+            for (corner, mut input) in inputs.genrows_mut().into_iter().enumerate().take(8) {
+                for (k, x) in input.iter_mut().enumerate() {
+                    let (lo, hi) = input_bounds[k];
+                    *x = if corner >> k & 1 == 0 { lo } else { hi };
+                }
+            }
+            let outputs = fcn.at_batch(&inputs);
+            for (k, column) in outputs.gencolumns().into_iter().enumerate() {
+                for bounds in [interval[k], symbolic[k]].iter() {
+                    assert!(
+                        column.iter().all(|y| bounds.0 <= *y && *y <= bounds.1),
+                        "output {} out of {:?} over {:?}",
+                        k,
+                        bounds,
+                        input_bounds
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bounds_contain_sampled_outputs_for_every_activation() {
+        let activations = [
+            Activation::Linear,
+            Activation::LeakyReLu(0.1),
+            Activation::Sigmoid,
+            Activation::Tanh,
+            Activation::ReLu,
+            Activation::Elu(1.0),
+            Activation::Softplus,
+            Activation::Swish,
+            Activation::Gelu,
+            Activation::Softmax,
+            Activation::ScaledTanh(vec![(-1.0, 2.0), (0.0, 3.0), (-5.0, -4.0)]),
+        ];
+        for (k, activation) in activations.iter().enumerate() {
+            let fcn = FCN::new_with(
+                vec![
+                    (3, Activation::Linear),
+                    (3, activation.clone()),
+                    (3, activation.clone()),
+                ],
+                &Init::Normal(0.0, 1.0),
+                &mut seed::rng(k as u64),
+            );
+            check_soundness(&fcn, k as u64);
+        }
+    }
+
+    #[test]
+    fn bounds_contain_sampled_outputs_through_skips_and_normalizers() {
+        let mut fcn = FCN::new_graph_with(
+            vec![
+                (3, Activation::Linear),
+                (6, Activation::ReLu),
+                (6, Activation::Tanh),
+                (2, Activation::ScaledTanh(vec![(0.0, 20.0), (-1.0, 1.0)])),
+            ],
+            vec![],
+            vec![
+                Skip::Residual { from: 1, to: 2 },
+                Skip::Concat { from: 0, to: 3 },
+            ],
+            &Init::Normal(0.0, 1.0),
+            &mut seed::rng(3),
+        );
+        fcn.set_input_normalizer(Some(Normalizer::from_bounds(&[
+            (-3.0, 3.0),
+            (0.0, 2.0),
+            (1.0, 1.0),
+        ])))
+        .unwrap();
+        check_soundness(&fcn, 3);
+    }
+
+    #[test]
+    fn invalid_intervals_are_errors() {
+        let fcn = FCN::new_with(
+            vec![(2, Activation::Linear), (1, Activation::Tanh)],
+            &Init::Normal(0.0, 1.0),
+            &mut seed::rng(0),
+        );
+        assert_eq!(
+            interval_bounds(&fcn, &[(0.0, 1.0), (1.0, 0.0)]).unwrap_err(),
+            BoundsError::InvalidInterval(1)
+        );
+        assert_eq!(
+            symbolic_bounds(&fcn, &[(0.0, 1.0)]).unwrap_err(),
+            BoundsError::InputLenMismatch {
+                expected: 2,
+                found: 1,
+            }
+        );
+    }
+}
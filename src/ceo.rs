@@ -1,16 +1,496 @@
 use crate::fcn::*;
+use crate::float::Float;
+use crate::interrupt;
+use crate::optimizer::{Optimizer, Outcome};
+use crate::seed;
 use ndarray::prelude::*;
-use ndarray::stack;
-use ndarray_rand::rand_distr::{NormalError, StandardNormal};
-use ndarray_rand::RandomExt;
+use ndarray::{stack, ShapeError};
+use rand::RngCore;
+use rand_pcg::Pcg32;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::time::{Duration, Instant};
 
-pub trait Reward {
-    fn reward(&self, fcn: &FCN, params: &Array1<f32>, num_episodes: usize) -> f32;
+pub trait Reward<F: Float = f32> {
+    /// Episodes are sampled from streams derived from seed, so equal seeds give equal episodes.
+    fn reward(&self, fcn: &FCN<F>, params: &Array1<F>, num_episodes: usize, seed: u64) -> F;
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
+pub enum CeoError {
+    /// A reward was nan or infinite under NanPolicy::Abort.
+    InvalidReward {
+        generation: usize,
+    },
+    InvalidCheckpoint {
+        expected: usize,
+        found: usize,
+    },
+    /// Population is too small to select any candidates, ex. batch_size * elite_frac of ceo rounds to 0.
+    TooFewCandidates,
+    /// Number of layer noise scales of ceo differs from the number of layers of fcn after its input.
+    InvalidNoiseScales {
+        expected: usize,
+        found: usize,
+    },
+    Shape(ShapeError),
+}
+
+impl fmt::Display for CeoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CeoError::InvalidReward { generation } => {
+                write!(f, "Invalid reward in generation {}.", generation)
+            }
+            CeoError::InvalidCheckpoint { expected, found } => write!(
+                f,
+                "Invalid checkpoint params len for fcn, expected {}, found {}.",
+                expected, found
+            ),
+            CeoError::TooFewCandidates => write!(f, "Too few candidates per generation."),
+            CeoError::InvalidNoiseScales { expected, found } => write!(
+                f,
+                "Invalid number of layer noise scales for fcn, expected {}, found {}.",
+                expected, found
+            ),
+            CeoError::Shape(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for CeoError {}
+
+impl From<ShapeError> for CeoError {
+    fn from(error: ShapeError) -> CeoError {
+        CeoError::Shape(error)
+    }
+}
+
+/// Handling of nan or infinite rewards, ex. from a diverging rollout.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum NanPolicy {
+    /// Ranked below every finite reward and left out of statistics.
+    #[default]
+    Worst,
+    /// Evaluated again on new episodes up to given number of times, then treated as worst.
+    Resample(usize),
+    /// Optimization stops with an error.
+    Abort,
+}
+
+impl NanPolicy {
+    /// Reward of params, resampled if needed, along with the number of invalid evaluations.
+    pub(crate) fn evaluate<F: Float>(
+        self,
+        reward: &(dyn Reward<F> + std::marker::Sync),
+        fcn: &FCN<F>,
+        params: &Array1<F>,
+        num_episodes: usize,
+        seed: u64,
+    ) -> (F, usize) {
+        let mut value = reward.reward(fcn, params, num_episodes, seed);
+        let mut num_invalid = 0;
+        while !value.is_finite() {
+            num_invalid += 1;
+            match self {
+                NanPolicy::Resample(max_resamples) if num_invalid <= max_resamples => {
+                    value = reward.reward(
+                        fcn,
+                        params,
+                        num_episodes,
+                        seed::derive(seed, num_invalid as u64),
+                    )
+                }
+                _ => break,
+            }
+        }
+        (value, num_invalid)
+    }
+
+    /// Fails under Abort if there were invalid evaluations in generation.
+    pub(crate) fn check(self, num_invalid: usize, generation: usize) -> Result<(), CeoError> {
+        match self {
+            NanPolicy::Abort if num_invalid > 0 => Err(CeoError::InvalidReward { generation }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Order of rewards with nan and infinite ones below all finite ones.
+pub(crate) fn compare_rewards<F: Float>(a: F, b: F) -> Ordering {
+    match (a.is_finite(), b.is_finite()) {
+        (true, true) => a.partial_cmp(&b).unwrap(),
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => Ordering::Equal,
+    }
+}
+
+/// Evaluations behind rewards of a generation, every invalid one was either resampled or left its reward invalid.
+pub(crate) fn count_evaluations<F: Float>(
+    rewards: &[F],
+    current_reward: F,
+    num_invalid: usize,
+) -> usize {
+    let num_valid = rewards.iter().filter(|r| r.is_finite()).count();
+    num_invalid + num_valid + current_reward.is_finite() as usize
+}
+
+/// Conditions to stop optimization before all generations, any of which stops it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StoppingCriteria {
+    /// Reward of current params at which to stop.
+    pub target_reward: Option<f32>,
+    /// Generations without improvement of the reward of current params, counted across resumes by ceo.
+    pub patience: Option<usize>,
+    pub min_std_mean: Option<f32>,
+    pub max_wall_time_secs: Option<f64>,
+    pub max_evaluations: Option<usize>,
+}
+
+impl StoppingCriteria {
+    /// Observer that stops once any criterion is met or an interrupt is requested, passing everything on to inner.
+    pub(crate) fn observer<'a, F: Float>(
+        &'a self,
+        inner: &'a mut dyn CeoObserver<F>,
+    ) -> StoppingObserver<'a, F> {
+        self.resumed_observer(inner, None, 0)
+    }
+
+    /// Same as observer, but counts patience on from the best reward and stagnation of a checkpoint.
+    pub(crate) fn resumed_observer<'a, F: Float>(
+        &'a self,
+        inner: &'a mut dyn CeoObserver<F>,
+        best_reward: Option<F>,
+        num_stagnant_generations: usize,
+    ) -> StoppingObserver<'a, F> {
+        StoppingObserver {
+            criteria: self,
+            inner: inner,
+            best_reward: best_reward,
+            num_stagnant_generations: num_stagnant_generations,
+        }
+    }
+}
+
+/// Weights of the elite candidates in the mean and std of the next generation.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum Recombination {
+    #[default]
+    Equal,
+    /// Weight of the candidate of rank i, from 1, proportional to ln(n_elite + 1/2) - ln(i), as in cma-es.
+    LogRank,
+}
+
+impl Recombination {
+    /// Weighted mean and std of elite params, rows sorted by decreasing reward.
+    pub(crate) fn mean_and_std<F: Float>(self, elite_ths: Array2<F>) -> (Array1<F>, Array1<F>) {
+        let n_elite = elite_ths.nrows();
+        match self {
+            Recombination::Equal => {
+                let mean = elite_ths.sum_axis(Axis(0)) / F::from_usize(n_elite);
+                let std = ((elite_ths - &mean).mapv(|d| d * d).sum_axis(Axis(0))
+                    / F::from_usize(n_elite))
+                .mapv(F::sqrt);
+                (mean, std)
+            }
+            Recombination::LogRank => {
+                let weights = Array1::from_shape_fn(n_elite, |i| {
+                    (F::from_usize(n_elite) + F::from_f32(0.5)).ln() - F::from_usize(i + 1).ln()
+                });
+                let weights = &weights / weights.sum();
+                let mean = elite_ths.t().dot(&weights);
+                let std = (elite_ths - &mean)
+                    .mapv(|d| d * d)
+                    .t()
+                    .dot(&weights)
+                    .mapv(F::sqrt);
+                (mean, std)
+            }
+        }
+    }
+}
+
+/// Extra std added to the std of the elite every generation, to keep exploring.
+/// Every form is a multiple of noise_factor of ceo, g is the generation from 1 and n the number of generations.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum NoiseSchedule {
+    /// noise_factor / g.
+    #[default]
+    Hyperbolic,
+    /// noise_factor.
+    Constant,
+    /// noise_factor (1 - (g - 1) / n).
+    Linear,
+    /// noise_factor decay^(g - 1).
+    Exponential { decay: f32 },
+    /// noise_factor (1 + cos(pi (g - 1) / n)) / 2.
+    Cosine,
+    /// Hyperbolic, replaced by noise_factor boost while the reward of mean params
+    /// has not improved for patience generations.
+    Plateau { patience: usize, boost: f32 },
+}
+
+impl NoiseSchedule {
+    /// Extra std of generation, from 0, after num_stagnant generations without improvement.
+    pub(crate) fn extra_std<F: Float>(
+        self,
+        noise_factor: f32,
+        generation: usize,
+        generations: usize,
+        num_stagnant: usize,
+    ) -> F {
+        let noise_factor = F::from_f32(noise_factor);
+        let progress = F::from_usize(generation) / F::from_usize(generations.max(1));
+        match self {
+            NoiseSchedule::Hyperbolic => noise_factor / F::from_usize(generation + 1),
+            NoiseSchedule::Constant => noise_factor,
+            NoiseSchedule::Linear => noise_factor * (F::one() - progress),
+            NoiseSchedule::Exponential { decay } => {
+                noise_factor * F::from_f32(decay).powi(generation as i32)
+            }
+            NoiseSchedule::Cosine => {
+                noise_factor * (F::one() + (F::from_f32(std::f32::consts::PI) * progress).cos())
+                    / F::from_f32(2.0)
+            }
+            NoiseSchedule::Plateau { patience, boost } if num_stagnant >= patience => {
+                noise_factor * F::from_f32(boost)
+            }
+            NoiseSchedule::Plateau { .. } => noise_factor / F::from_usize(generation + 1),
+        }
+    }
+}
+
+/// Params with the highest rewards seen during optimization, in decreasing order of reward.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, bound(deserialize = "F: Float"))]
+pub struct HallOfFame<F = f32> {
+    /// Maximum number of entries.
+    pub capacity: usize,
+    pub entries: Vec<HallOfFameEntry<F>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, bound(deserialize = "F: Float"))]
+pub struct HallOfFameEntry<F = f32> {
+    /// Generation of the reward, from 1.
+    pub generation: usize,
+    pub reward: F,
+    pub params: Array1<F>,
+}
+
+impl<F> Default for HallOfFame<F> {
+    fn default() -> HallOfFame<F> {
+        HallOfFame::new(0)
+    }
+}
+
+impl<F> HallOfFame<F> {
+    pub fn new(capacity: usize) -> HallOfFame<F> {
+        HallOfFame {
+            capacity: capacity,
+            entries: Vec::with_capacity(capacity),
+        }
+    }
+}
+
+impl<F: Float> HallOfFame<F> {
+    /// Keeps params if their reward is among the best so far, invalid rewards never are.
+    /// Params already kept are kept once, with the higher of their rewards.
+    pub fn insert(&mut self, generation: usize, reward: F, params: &Array1<F>) {
+        if !reward.is_finite() {
+            return;
+        }
+        if let Some(k) = self.entries.iter().position(|e| e.params == *params) {
+            if reward <= self.entries[k].reward {
+                return;
+            }
+            self.entries.remove(k);
+        }
+        let k = self
+            .entries
+            .iter()
+            .take_while(|e| e.reward >= reward)
+            .count();
+        if k < self.capacity {
+            self.entries.insert(
+                k,
+                HallOfFameEntry {
+                    generation: generation,
+                    reward: reward,
+                    params: params.clone(),
+                },
+            );
+            self.entries.truncate(self.capacity);
+        }
+    }
+}
+
+pub(crate) struct StoppingObserver<'a, F> {
+    criteria: &'a StoppingCriteria,
+    inner: &'a mut dyn CeoObserver<F>,
+    best_reward: Option<F>,
+    num_stagnant_generations: usize,
+}
+
+impl<'a, F: Float> CeoObserver<F> for StoppingObserver<'a, F> {
+    fn observe(&mut self, stats: &GenerationStats<F>) -> bool {
+        let stop = self.inner.observe(stats);
+        let improved = match self.best_reward {
+            Some(best_reward) => stats.current_reward > best_reward,
+            None => stats.current_reward.is_finite(),
+        };
+        if improved {
+            self.best_reward = Some(stats.current_reward);
+            self.num_stagnant_generations = 0;
+        } else {
+            self.num_stagnant_generations += 1;
+        }
+        let criteria = self.criteria;
+        let met = matches!(criteria.target_reward, Some(target) if stats.current_reward >= F::from_f32(target))
+            || matches!(criteria.patience, Some(patience) if self.num_stagnant_generations >= patience)
+            || matches!(criteria.min_std_mean, Some(min) if stats.std_mean < F::from_f32(min))
+            || matches!(criteria.max_wall_time_secs, Some(max) if stats.wall_time.as_secs_f64() >= max)
+            || matches!(criteria.max_evaluations, Some(max) if stats.num_evaluations >= max);
+        stop || met || interrupt::requested()
+    }
+
+    fn checkpoint(&mut self, checkpoint: &CeoCheckpoint<F>) {
+        self.inner.checkpoint(checkpoint)
+    }
+}
+
+/// Statistics of one generation, rewards of the sampled params are over the whole batch.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenerationStats<F = f32> {
+    /// Starts at 1.
+    pub generation: usize,
+    pub mean_reward: F,
+    pub min_reward: F,
+    pub max_reward: F,
+    pub median_reward: F,
+    pub elite_mean_reward: F,
+    /// Reward of the params after the update of this generation.
+    pub current_reward: F,
+    pub std_mean: F,
+    /// Since the start of optimization.
+    pub wall_time: Duration,
+    /// Evaluations that were nan or infinite, including resampled ones.
+    /// Statistics are over finite rewards only, nan if there are none.
+    #[serde(default)]
+    pub num_invalid: usize,
+    /// Since the start of optimization, including resampled ones.
+    #[serde(default)]
+    pub num_evaluations: usize,
+    /// Restarts of the optimizer before this generation, only cma-es restarts.
+    #[serde(default)]
+    pub restart: usize,
+}
+
+impl<F: Float> GenerationStats<F> {
+    /// Statistics from rewards sorted from best to worst by compare_rewards, of which the first num_elite are elite.
+    /// Wall time and counts are left to the caller.
+    pub(crate) fn from_sorted_rewards(
+        generation: usize,
+        sorted_rewards: &[F],
+        num_elite: usize,
+        current_reward: F,
+        std_mean: F,
+    ) -> GenerationStats<F> {
+        let num_valid = sorted_rewards.iter().filter(|r| r.is_finite()).count();
+        let rewards = &sorted_rewards[..num_valid];
+        let mid = rewards.len() / 2;
+        GenerationStats {
+            generation: generation,
+            mean_reward: mean(rewards),
+            min_reward: rewards.last().copied().unwrap_or_else(F::nan),
+            max_reward: rewards.first().copied().unwrap_or_else(F::nan),
+            median_reward: if rewards.is_empty() {
+                F::nan()
+            } else if 2 * mid == rewards.len() {
+                (rewards[mid - 1] + rewards[mid]) / F::from_f32(2.0)
+            } else {
+                rewards[mid]
+            },
+            elite_mean_reward: mean(&rewards[..num_elite.min(num_valid)]),
+            current_reward: current_reward,
+            std_mean: std_mean,
+            wall_time: Duration::default(),
+            num_invalid: 0,
+            num_evaluations: 0,
+            restart: 0,
+        }
+    }
+}
+
+/// Receives statistics after every generation.
+pub trait CeoObserver<F: Float = f32> {
+    /// Returns true to stop optimization after this generation.
+    fn observe(&mut self, stats: &GenerationStats<F>) -> bool;
+
+    /// Receives a checkpoint every checkpoint_interval generations.
+    fn checkpoint(&mut self, _checkpoint: &CeoCheckpoint<F>) {}
+}
+
+/// State of optimization after a number of generations, from which it can be resumed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, bound(deserialize = "F: Float"))]
+pub struct CeoCheckpoint<F = f32> {
+    /// Number of completed generations.
+    pub generation: usize,
+    /// Mean params.
+    pub params: Array1<F>,
+    pub noise_std: Array1<F>,
+    /// Source of the seeds of the next generations.
+    pub rng: Pcg32,
+    /// Reward of mean params and the params, of the generation where it was highest.
+    pub best: Option<(F, Array1<F>)>,
+    pub wall_time: Duration,
+    #[serde(default)]
+    pub num_evaluations: usize,
+    /// Reward and params of the highest rewarded candidate so far, carried under elitism.
+    #[serde(default)]
+    pub best_candidate: Option<(F, Array1<F>)>,
+    #[serde(default)]
+    pub hall_of_fame: HallOfFame<F>,
+    /// Generations since the reward of mean params last improved.
+    #[serde(default)]
+    pub num_stagnant_generations: usize,
+}
+
+impl<F: Float, O: FnMut(&GenerationStats<F>) -> bool> CeoObserver<F> for O {
+    fn observe(&mut self, stats: &GenerationStats<F>) -> bool {
+        self(stats)
+    }
+}
+
+/// Prints a line per generation and never stops.
+pub struct PrintObserver;
+
+impl<F: Float> CeoObserver<F> for PrintObserver {
+    fn observe(&mut self, stats: &GenerationStats<F>) -> bool {
+        println!(
+            "generation={} mean_reward={:?} reward_with_current_th={:?}, th_std_mean={:?}",
+            stats.generation, stats.mean_reward, stats.current_reward, stats.std_mean,
+        );
+        if stats.num_invalid > 0 {
+            println!("num_invalid={}", stats.num_invalid);
+        }
+        if stats.restart > 0 {
+            println!("restart={}", stats.restart);
+        }
+        false
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct CEO {
     pub generations: usize,
@@ -19,6 +499,43 @@ pub struct CEO {
     pub elite_frac: f32,
     pub initial_std: f32,
     pub noise_factor: f32,
+    /// Generations b/w checkpoints passed to the observer, 0 for none.
+    #[serde(default)]
+    pub checkpoint_interval: usize,
+    /// Master seed of noise and evaluations, none to draw one from entropy.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub nan_policy: NanPolicy,
+    #[serde(default)]
+    pub stopping: StoppingCriteria,
+    /// Evaluates all candidates of a generation on the same episodes,
+    /// so that they are ranked by their params rather than by the luck of their episodes.
+    #[serde(default)]
+    pub common_random_numbers: bool,
+    /// Best candidates evaluated again on new episodes before elite selection,
+    /// each then ranked by the mean of both rewards, 0 for none.
+    #[serde(default)]
+    pub num_reevaluated: usize,
+    #[serde(default)]
+    pub recombination: Recombination,
+    /// Carries the highest rewarded candidate so far unperturbed into every generation, in place of a sampled one.
+    #[serde(default)]
+    pub elitism: bool,
+    /// Number of best params kept with their rewards, among candidates and mean params, 0 for none.
+    #[serde(default)]
+    pub hall_of_fame_size: usize,
+    #[serde(default)]
+    pub noise_schedule: NoiseSchedule,
+    /// Bounds of the std of every param after the extra std is added.
+    #[serde(default)]
+    pub min_std: Option<f32>,
+    #[serde(default)]
+    pub max_std: Option<f32>,
+    /// Scales of the extra std of the params of every layer after the input, in order, empty for none.
+    /// Params of recurrent connections and concat skips are scaled as the layer they feed.
+    #[serde(default)]
+    pub layer_noise_scales: Vec<f32>,
 }
 
 impl Default for CEO {
@@ -30,62 +547,832 @@ impl Default for CEO {
             elite_frac: 0.25,
             initial_std: 2.0,
             noise_factor: 2.0,
+            checkpoint_interval: 0,
+            seed: None,
+            nan_policy: NanPolicy::Worst,
+            stopping: StoppingCriteria::default(),
+            common_random_numbers: false,
+            num_reevaluated: 0,
+            recombination: Recombination::Equal,
+            elitism: false,
+            hall_of_fame_size: 0,
+            noise_schedule: NoiseSchedule::Hyperbolic,
+            min_std: None,
+            max_std: None,
+            layer_noise_scales: vec![],
         }
     }
 }
 
+/// Params, rewards and the elite statistics are in the float type of the fcn.
+impl Optimizer for CEO {
+    fn optimize_with<F: Float>(
+        &self,
+        fcn: &mut FCN<F>,
+        reward: &(dyn Reward<F> + std::marker::Sync),
+        observer: &mut dyn CeoObserver<F>,
+    ) -> Result<Outcome<F>, CeoError> {
+        let checkpoint = self.initial_checkpoint(fcn);
+        self.resume_with(fcn, reward, observer, checkpoint)
+    }
+}
+
 impl CEO {
-    pub fn optimize(
+    /// State before the first generation, starting from params of fcn.
+    pub fn initial_checkpoint<F: Float>(&self, fcn: &FCN<F>) -> CeoCheckpoint<F> {
+        CeoCheckpoint {
+            generation: 0,
+            params: fcn.params().clone(),
+            noise_std: Array::from_elem((fcn.params().len(),), F::from_f32(self.initial_std)),
+            rng: seed::rng(seed::derive(
+                self.seed.unwrap_or_else(rand::random),
+                seed::OPTIMIZER_STREAM,
+            )),
+            best: None,
+            wall_time: Duration::default(),
+            num_evaluations: 0,
+            best_candidate: None,
+            hall_of_fame: HallOfFame::new(self.hall_of_fame_size),
+            num_stagnant_generations: 0,
+        }
+    }
+
+    /// Scale of the extra std of every param of fcn.
+    fn noise_scales<F: Float>(&self, fcn: &FCN<F>) -> Result<Array1<F>, CeoError> {
+        let mut scales = Array1::ones(fcn.params().len());
+        if self.layer_noise_scales.is_empty() {
+            return Ok(scales);
+        }
+        let num_layers = fcn.layers().len();
+        if self.layer_noise_scales.len() != num_layers - 1 {
+            return Err(CeoError::InvalidNoiseScales {
+                expected: num_layers - 1,
+                found: self.layer_noise_scales.len(),
+            });
+        }
+        for i in 1..num_layers {
+            let scale = F::from_f32(self.layer_noise_scales[i - 1]);
+            scales.slice_mut(s![fcn.layer_param_range(i)]).fill(scale);
+            if let Some(range) = fcn.recurrent_param_range(i) {
+                scales.slice_mut(s![range]).fill(scale);
+            }
+        }
+        for (j, skip) in fcn.skips().iter().enumerate() {
+            if let Some(range) = fcn.skip_param_range(j) {
+                let scale = F::from_f32(self.layer_noise_scales[skip.to() - 1]);
+                scales.slice_mut(s![range]).fill(scale);
+            }
+        }
+        Ok(scales)
+    }
+
+    /// Continues optimization from checkpoint, using PrintObserver.
+    pub fn resume<F: Float>(
         &self,
-        fcn: &mut FCN,
-        reward: &(dyn Reward + std::marker::Sync),
-    ) -> Result<Array1<f32>, NormalError> {
+        fcn: &mut FCN<F>,
+        reward: &(dyn Reward<F> + std::marker::Sync),
+        checkpoint: CeoCheckpoint<F>,
+    ) -> Result<Outcome<F>, CeoError> {
+        self.resume_with(fcn, reward, &mut PrintObserver, checkpoint)
+    }
+
+    /// Continues optimization from checkpoint up to generations, setting params of fcn to its mean params,
+    /// or to the best ones if stopped early by the stopping criteria, an interrupt or observer.
+    /// Every candidate draws its noise and evaluation seed from streams derived from the seed of its generation,
+    /// so the result depends only on the seed and not on scheduling, and is bit-identical to that of an uninterrupted run.
+    pub fn resume_with<F: Float>(
+        &self,
+        fcn: &mut FCN<F>,
+        reward: &(dyn Reward<F> + std::marker::Sync),
+        observer: &mut dyn CeoObserver<F>,
+        checkpoint: CeoCheckpoint<F>,
+    ) -> Result<Outcome<F>, CeoError> {
+        if checkpoint.params.len() != fcn.params().len() {
+            return Err(CeoError::InvalidCheckpoint {
+                expected: fcn.params().len(),
+                found: checkpoint.params.len(),
+            });
+        }
+        let start = Instant::now();
+        let CeoCheckpoint {
+            generation: first_generation,
+            params,
+            mut noise_std,
+            mut rng,
+            mut best,
+            wall_time,
+            mut num_evaluations,
+            mut best_candidate,
+            mut hall_of_fame,
+            mut num_stagnant_generations,
+        } = checkpoint;
+        let noise_scales = self.noise_scales(fcn)?;
+        hall_of_fame.capacity = self.hall_of_fame_size;
+        hall_of_fame.entries.truncate(self.hall_of_fame_size);
+        let mut observer = self.stopping.resumed_observer(
+            observer,
+            best.as_ref().map(|(best_reward, _)| *best_reward),
+            num_stagnant_generations,
+        );
+        fcn.set_params(params);
         let n_elite = (self.batch_size as f32 * self.elite_frac).round().floor() as usize;
-        let mut noise_std = Array::from_elem((fcn.params().len(),), self.initial_std);
-        for generation in 0..self.generations {
-            let (sorted_th_means, mean_reward) = {
+        if n_elite == 0 {
+            return Err(CeoError::TooFewCandidates);
+        }
+        for generation in first_generation..self.generations {
+            let generation_seed = rng.next_u64();
+            // With common random numbers all candidates share the episodes of seeds past the candidates
+            let reward_seed = |candidate_seed, stream| {
+                if self.common_random_numbers {
+                    seed::derive(generation_seed, self.batch_size as u64 + stream)
+                } else {
+                    seed::derive(candidate_seed, stream)
+                }
+            };
+            let (sorted_th_means, rewards, mut num_invalid, num_candidate_evaluations) = {
                 let mut reward_th_mean_tuples = (0..self.batch_size)
                     .into_par_iter()
-                    .map(|_| {
-                        let randn_noise: Array1<f32> =
-                            Array::random(fcn.params().len(), StandardNormal);
-                        let scaled_randn_noise = randn_noise * &noise_std;
-                        let perturbed_params = scaled_randn_noise + fcn.params();
-                        (
-                            reward.reward(fcn, &perturbed_params, self.num_evalation_samples),
-                            perturbed_params,
-                        )
+                    .map(|k| {
+                        let candidate_seed = seed::derive(generation_seed, k as u64);
+                        let perturbed_params = match &best_candidate {
+                            Some((_, params)) if self.elitism && k + 1 == self.batch_size => {
+                                params.clone()
+                            }
+                            _ => sample_candidate(candidate_seed, fcn.params(), &noise_std),
+                        };
+                        let (reward, num_invalid) = self.nan_policy.evaluate(
+                            reward,
+                            fcn,
+                            &perturbed_params,
+                            self.num_evalation_samples,
+                            reward_seed(candidate_seed, 1),
+                        );
+                        (reward, perturbed_params, num_invalid, candidate_seed)
                     })
-                    .collect::<Vec<(f32, Array1<f32>)>>();
-                reward_th_mean_tuples.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+                    .collect::<Vec<(F, Array1<F>, usize, u64)>>();
+                reward_th_mean_tuples.sort_by(|a, b| compare_rewards(a.0, b.0));
                 reward_th_mean_tuples.reverse();
-                let (rewards, sorted_th_means): (Vec<_>, Vec<_>) =
-                    reward_th_mean_tuples.into_iter().unzip();
-                (
-                    sorted_th_means,
-                    rewards.iter().sum::<f32>() / rewards.len() as f32,
-                )
+                let mut num_invalid = 0;
+                let mut num_evaluations = 0;
+                for (reward, _, candidate_num_invalid, _) in &reward_th_mean_tuples {
+                    num_invalid += candidate_num_invalid;
+                    num_evaluations += candidate_num_invalid + reward.is_finite() as usize;
+                }
+                let num_reevaluated = self.num_reevaluated.min(self.batch_size);
+                if num_reevaluated > 0 {
+                    let reevaluations = reward_th_mean_tuples[..num_reevaluated]
+                        .par_iter()
+                        .map(|(_, th_mean, _, candidate_seed)| {
+                            self.nan_policy.evaluate(
+                                reward,
+                                fcn,
+                                th_mean,
+                                self.num_evalation_samples,
+                                reward_seed(*candidate_seed, 2),
+                            )
+                        })
+                        .collect::<Vec<(F, usize)>>();
+                    for (tuple, (reevaluated_reward, reevaluation_num_invalid)) in
+                        reward_th_mean_tuples.iter_mut().zip(reevaluations)
+                    {
+                        tuple.0 = (tuple.0 + reevaluated_reward) / F::from_f32(2.0);
+                        num_invalid += reevaluation_num_invalid;
+                        num_evaluations +=
+                            reevaluation_num_invalid + reevaluated_reward.is_finite() as usize;
+                    }
+                    reward_th_mean_tuples.sort_by(|a, b| compare_rewards(a.0, b.0));
+                    reward_th_mean_tuples.reverse();
+                }
+                let mut rewards = Vec::with_capacity(self.batch_size);
+                let mut sorted_th_means = Vec::with_capacity(self.batch_size);
+                for (reward, th_mean, _, _) in reward_th_mean_tuples {
+                    rewards.push(reward);
+                    sorted_th_means.push(th_mean);
+                }
+                (sorted_th_means, rewards, num_invalid, num_evaluations)
             };
+            self.nan_policy.check(num_invalid, generation + 1)?;
+            let improved = match &best_candidate {
+                Some((best_reward, _)) => rewards[0] > *best_reward,
+                None => rewards[0].is_finite(),
+            };
+            if improved {
+                best_candidate = Some((rewards[0], sorted_th_means[0].clone()));
+            }
+            for (candidate_reward, th) in rewards.iter().zip(&sorted_th_means) {
+                hall_of_fame.insert(generation + 1, *candidate_reward, th);
+            }
             let elite_ths = sorted_th_means
                 .iter()
                 .take(n_elite)
-                .map(|th| th.slice(s![..]))
-                .collect::<Vec<ArrayView1<f32>>>();
-            let elite_ths = stack(Axis(0), &elite_ths)
+                .map(|th| th.view())
+                .collect::<Vec<ArrayView1<F>>>();
+            let extra_std = self.noise_schedule.extra_std::<F>(
+                self.noise_factor,
+                generation,
+                self.generations,
+                num_stagnant_generations,
+            );
+            let (elite_th_mean, elite_th_std) = next_distribution(
+                &elite_ths,
+                self.recombination,
+                extra_std,
+                &noise_scales,
+                (self.min_std, self.max_std),
+            )?;
+            noise_std = elite_th_std;
+            fcn.set_params(elite_th_mean);
+            let (current_reward, current_num_invalid) = self.nan_policy.evaluate(
+                reward,
+                fcn,
+                fcn.params(),
+                self.num_evalation_samples,
+                seed::derive(generation_seed, self.batch_size as u64),
+            );
+            num_invalid += current_num_invalid;
+            self.nan_policy.check(num_invalid, generation + 1)?;
+            let improved = match &best {
+                Some((best_reward, _)) => current_reward > *best_reward,
+                None => current_reward.is_finite(),
+            };
+            if improved {
+                best = Some((current_reward, fcn.params().clone()));
+                num_stagnant_generations = 0;
+            } else {
+                num_stagnant_generations += 1;
+            }
+            hall_of_fame.insert(generation + 1, current_reward, fcn.params());
+            num_evaluations += num_candidate_evaluations
+                + current_num_invalid
+                + current_reward.is_finite() as usize;
+            let stats = GenerationStats {
+                wall_time: wall_time + start.elapsed(),
+                num_invalid: num_invalid,
+                num_evaluations: num_evaluations,
+                ..GenerationStats::from_sorted_rewards(
+                    generation + 1,
+                    &rewards,
+                    n_elite,
+                    current_reward,
+                    noise_std.sum() / F::from_usize(noise_std.len()),
+                )
+            };
+            let stop = observer.observe(&stats);
+            if self.checkpoint_interval > 0 && (generation + 1) % self.checkpoint_interval == 0 {
+                observer.checkpoint(&CeoCheckpoint {
+                    generation: generation + 1,
+                    params: fcn.params().clone(),
+                    noise_std: noise_std.clone(),
+                    rng: rng.clone(),
+                    best: best.clone(),
+                    wall_time: stats.wall_time,
+                    num_evaluations: num_evaluations,
+                    best_candidate: best_candidate.clone(),
+                    hall_of_fame: hall_of_fame.clone(),
+                    num_stagnant_generations: num_stagnant_generations,
+                });
+            }
+            if stop {
+                if let Some((_, best_params)) = best {
+                    fcn.set_params(best_params);
+                }
+                break;
+            }
+        }
+        Ok(Outcome {
+            noise_std: noise_std,
+            hall_of_fame: hall_of_fame,
+        })
+    }
+}
+
+/// Params of a candidate of ceo or moceo, perturbed by noise drawn from a stream derived from its seed.
+pub(crate) fn sample_candidate<F: Float>(
+    candidate_seed: u64,
+    mean: &Array1<F>,
+    noise_std: &Array1<F>,
+) -> Array1<F> {
+    let mut noise_rng = seed::rng(seed::derive(candidate_seed, 0));
+    let randn_noise = Array::from_shape_fn(mean.len(), |_| F::standard_normal(&mut noise_rng));
+    randn_noise * noise_std + mean
+}
+
+/// Mean and std of the next generation of ceo or moceo, from elite params in decreasing order of rank.
+/// The std of recombination gets extra std scaled per param by noise_scales, then is bounded by (min, max) std.
+pub(crate) fn next_distribution<F: Float>(
+    elite_ths: &[ArrayView1<F>],
+    recombination: Recombination,
+    extra_std: F,
+    noise_scales: &Array1<F>,
+    (min_std, max_std): (Option<f32>, Option<f32>),
+) -> Result<(Array1<F>, Array1<F>), CeoError> {
+    let elite_ths = stack(Axis(0), elite_ths)?.into_shape((elite_ths.len(), noise_scales.len()))?;
+    let (mean, mut std) = recombination.mean_and_std(elite_ths);
+    std.scaled_add(extra_std, noise_scales);
+    if let Some(min_std) = min_std {
+        std.mapv_inplace(|std| std.max(F::from_f32(min_std)));
+    }
+    if let Some(max_std) = max_std {
+        std.mapv_inplace(|std| std.min(F::from_f32(max_std)));
+    }
+    Ok((mean, std))
+}
+
+pub(crate) fn mean<F: Float>(xs: &[F]) -> F {
+    xs.iter().fold(F::zero(), |sum, &x| sum + x) / F::from_usize(xs.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Negative squared distance of params to 1, plus noise of the episodes drawn from seed.
+    struct NoisyQuadratic;
+
+    impl Reward<f64> for NoisyQuadratic {
+        fn reward(
+            &self,
+            _: &FCN<f64>,
+            params: &Array1<f64>,
+            num_episodes: usize,
+            seed: u64,
+        ) -> f64 {
+            let mut rng = seed::rng(seed);
+            let noise = (0..num_episodes)
+                .map(|_| f64::standard_normal(&mut rng))
+                .sum::<f64>()
+                / num_episodes as f64;
+            -params.mapv(|th| (th - 1.0) * (th - 1.0)).sum() + noise
+        }
+    }
+
+    /// Negative squared distance of params to 1, recording the params and seed of every evaluation.
+    #[derive(Default)]
+    struct Recording {
+        evaluations: Mutex<Vec<(Array1<f64>, u64)>>,
+    }
+
+    impl Reward<f64> for Recording {
+        fn reward(&self, _: &FCN<f64>, params: &Array1<f64>, _: usize, seed: u64) -> f64 {
+            self.evaluations
+                .lock()
                 .unwrap()
-                .into_shape((n_elite, fcn.params().len()))
+                .push((params.clone(), seed));
+            -params.mapv(|th| (th - 1.0) * (th - 1.0)).sum()
+        }
+    }
+
+    /// Negative squared distance of params to 1, nan for seeds that are multiples of 4.
+    struct SometimesNan;
+
+    impl Reward<f64> for SometimesNan {
+        fn reward(&self, _: &FCN<f64>, params: &Array1<f64>, _: usize, seed: u64) -> f64 {
+            if seed & 3 == 0 {
+                f64::NAN
+            } else {
+                -params.mapv(|th| (th - 1.0) * (th - 1.0)).sum()
+            }
+        }
+    }
+
+    /// Debug representations of every generation without wall time, and the checkpoints received.
+    #[derive(Default)]
+    struct Recorder {
+        stats: Vec<String>,
+        checkpoints: Vec<CeoCheckpoint<f64>>,
+        stop_at: Option<usize>,
+    }
+
+    impl CeoObserver<f64> for Recorder {
+        fn observe(&mut self, stats: &GenerationStats<f64>) -> bool {
+            let stats = GenerationStats {
+                wall_time: Duration::default(),
+                ..stats.clone()
+            };
+            self.stats.push(format!("{:?}", stats));
+            self.stop_at == Some(stats.generation)
+        }
+
+        fn checkpoint(&mut self, checkpoint: &CeoCheckpoint<f64>) {
+            self.checkpoints.push(checkpoint.clone());
+        }
+    }
+
+    fn fcn() -> FCN<f64> {
+        FCN::new_with(
+            vec![
+                (2, Activation::Linear),
+                (2, Activation::Tanh),
+                (1, Activation::Linear),
+            ],
+            &Init::Normal(0.0, 0.5),
+            &mut seed::rng(0),
+        )
+    }
+
+    #[test]
+    fn resumed_run_is_bit_identical_to_uninterrupted_run() {
+        let ceo = CEO {
+            generations: 30,
+            batch_size: 20,
+            num_evalation_samples: 4,
+            initial_std: 0.5,
+            noise_factor: 0.5,
+            checkpoint_interval: 1,
+            seed: Some(42),
+            stopping: StoppingCriteria {
+                patience: Some(3),
+                ..StoppingCriteria::default()
+            },
+            num_reevaluated: 3,
+            recombination: Recombination::LogRank,
+            elitism: true,
+            hall_of_fame_size: 5,
+            noise_schedule: NoiseSchedule::Plateau {
+                patience: 2,
+                boost: 2.0,
+            },
+            ..CEO::default()
+        };
+        let mut uninterrupted_fcn = fcn();
+        let mut uninterrupted = Recorder::default();
+        let uninterrupted_outcome = ceo
+            .optimize_with(&mut uninterrupted_fcn, &NoisyQuadratic, &mut uninterrupted)
+            .unwrap();
+        // Stopped by patience, after a stagnant generation before the interruption
+        let num_generations = uninterrupted.stats.len();
+        assert!(num_generations < ceo.generations);
+        let k = (1..num_generations - 1)
+            .rev()
+            .find(|&k| uninterrupted.checkpoints[k - 1].num_stagnant_generations > 0)
+            .unwrap();
+
+        let mut interrupted = Recorder {
+            stop_at: Some(k),
+            ..Recorder::default()
+        };
+        ceo.optimize_with(&mut fcn(), &NoisyQuadratic, &mut interrupted)
+            .unwrap();
+        assert_eq!(interrupted.stats.len(), k);
+        // Through json, as saved by the rl binary
+        let checkpoint = serde_json::to_string(interrupted.checkpoints.last().unwrap()).unwrap();
+        let checkpoint = serde_json::from_str::<CeoCheckpoint<f64>>(&checkpoint).unwrap();
+        let mut resumed_fcn = fcn();
+        let mut resumed = Recorder::default();
+        let resumed_outcome = ceo
+            .resume_with(&mut resumed_fcn, &NoisyQuadratic, &mut resumed, checkpoint)
+            .unwrap();
+
+        assert_eq!(interrupted.stats[..], uninterrupted.stats[..k]);
+        assert_eq!(resumed.stats[..], uninterrupted.stats[k..]);
+        assert_eq!(resumed_fcn.params(), uninterrupted_fcn.params());
+        assert_eq!(resumed_outcome.noise_std, uninterrupted_outcome.noise_std);
+        assert_eq!(
+            format!("{:?}", resumed_outcome.hall_of_fame),
+            format!("{:?}", uninterrupted_outcome.hall_of_fame)
+        );
+    }
+
+    /// Seeds of the evaluations of a single generation of ceo, and the seed of that generation.
+    fn first_generation_seeds(ceo: &CEO) -> (Vec<(Array1<f64>, u64)>, u64) {
+        let ceo = CEO {
+            generations: 1,
+            ..ceo.clone()
+        };
+        let reward = Recording::default();
+        ceo.optimize_with(&mut fcn(), &reward, &mut |_: &GenerationStats<f64>| false)
+            .unwrap();
+        let generation_seed = ceo.initial_checkpoint(&fcn()).rng.next_u64();
+        (reward.evaluations.into_inner().unwrap(), generation_seed)
+    }
+
+    #[test]
+    fn common_random_numbers_share_the_seed_of_a_generation() {
+        let ceo = CEO {
+            batch_size: 10,
+            num_evalation_samples: 1,
+            seed: Some(3),
+            num_reevaluated: 3,
+            ..CEO::default()
+        };
+        let (evaluations, generation_seed) = first_generation_seeds(&CEO {
+            common_random_numbers: true,
+            ..ceo.clone()
+        });
+        let with_seed = |seed| {
+            evaluations
+                .iter()
+                .filter(|(_, s)| *s == seed)
+                .map(|(params, _)| params.clone())
+                .collect::<Vec<Array1<f64>>>()
+        };
+        let candidates = with_seed(seed::derive(generation_seed, 11));
+        let reevaluated = with_seed(seed::derive(generation_seed, 12));
+        assert_eq!(candidates.len(), 10);
+        assert_eq!(reevaluated.len(), 3);
+        assert_eq!(with_seed(seed::derive(generation_seed, 10)).len(), 1);
+        assert_eq!(evaluations.len(), 10 + 3 + 1);
+        // Reevaluated are the best candidates on the first evaluation
+        let mut rewards = candidates
+            .iter()
+            .map(|params| Recording::default().reward(&fcn(), params, 1, 0))
+            .collect::<Vec<f64>>();
+        rewards.sort_by(|a, b| b.partial_cmp(a).unwrap());
+        for params in &reevaluated {
+            let reward = Recording::default().reward(&fcn(), params, 1, 0);
+            assert!(reward >= rewards[2]);
+        }
+
+        // Otherwise every candidate has seeds of its own
+        let (evaluations, generation_seed) = first_generation_seeds(&ceo);
+        for k in 0..10 {
+            let candidate_seed = seed::derive(generation_seed, k);
+            let seed = seed::derive(candidate_seed, 1);
+            assert_eq!(evaluations.iter().filter(|(_, s)| *s == seed).count(), 1);
+        }
+        let reevaluation_seeds = (0..10)
+            .map(|k| seed::derive(seed::derive(generation_seed, k), 2))
+            .collect::<Vec<u64>>();
+        let num_reevaluations = evaluations
+            .iter()
+            .filter(|(_, s)| reevaluation_seeds.contains(s))
+            .count();
+        assert_eq!(num_reevaluations, 3);
+    }
+
+    #[test]
+    fn log_rank_weights_are_normalized_and_decrease_with_rank() {
+        // Mean of unit rows is the vector of weights
+        let (weights, _) = Recombination::LogRank.mean_and_std(Array2::<f64>::eye(5));
+        assert!((weights.sum() - 1.0).abs() < 1e-12);
+        for i in 1..5 {
+            assert!(weights[i] < weights[i - 1]);
+        }
+        assert!(weights[4] > 0.0);
+    }
+
+    #[test]
+    fn elitism_carries_the_best_candidate_into_the_next_generation() {
+        for &elitism in &[true, false] {
+            let ceo = CEO {
+                generations: 2,
+                batch_size: 10,
+                num_evalation_samples: 1,
+                seed: Some(5),
+                elitism: elitism,
+                ..CEO::default()
+            };
+            let reward = Recording::default();
+            ceo.optimize_with(&mut fcn(), &reward, &mut |_: &GenerationStats<f64>| false)
                 .unwrap();
-            fcn.set_params(elite_ths.mean_axis(Axis(0)).unwrap());
-            noise_std = elite_ths.std_axis(Axis(0), 0.0);
-            noise_std += self.noise_factor / (generation + 1) as f32;
-            println!(
-                "generation={} mean_reward={:?} reward_with_current_th={:?}, th_std_mean={:?}",
-                generation + 1,
-                mean_reward,
-                reward.reward(fcn, &fcn.params(), self.num_evalation_samples),
-                noise_std.mean(),
-            );
+            let evaluations = reward.evaluations.into_inner().unwrap();
+            let mut rng = ceo.initial_checkpoint(&fcn()).rng;
+            let generation_seeds = (rng.next_u64(), rng.next_u64());
+            let candidate = |generation_seed, k| {
+                let seed = seed::derive(seed::derive(generation_seed, k), 1);
+                evaluations
+                    .iter()
+                    .find(|(_, s)| *s == seed)
+                    .unwrap()
+                    .clone()
+            };
+            let best = (0..10)
+                .map(|k| candidate(generation_seeds.0, k).0)
+                .max_by(|a, b| {
+                    let reward = |params| Recording::default().reward(&fcn(), params, 1, 0);
+                    compare_rewards(reward(a), reward(b))
+                })
+                .unwrap();
+            // The last candidate is the carried one
+            let (carried, _) = candidate(generation_seeds.1, 9);
+            assert_eq!(carried == best, elitism);
         }
-        Ok(noise_std)
+    }
+
+    /// Generation, from 1, at which criteria stop a run of 10 generations, where generation g has
+    /// current reward min(g, 3), std mean 1 / g, a wall time of g secs and 10 g evaluations.
+    fn stopping_generation(criteria: StoppingCriteria) -> Option<usize> {
+        let mut inner = |_: &GenerationStats<f64>| false;
+        let mut observer = criteria.observer(&mut inner);
+        (1..=10).find(|&g| {
+            observer.observe(&GenerationStats {
+                wall_time: Duration::from_secs(g as u64),
+                num_evaluations: 10 * g,
+                ..GenerationStats::from_sorted_rewards(
+                    g,
+                    &[0.0],
+                    1,
+                    g.min(3) as f64,
+                    1.0 / g as f64,
+                )
+            })
+        })
+    }
+
+    #[test]
+    fn no_criteria_never_stop() {
+        assert_eq!(stopping_generation(StoppingCriteria::default()), None);
+    }
+
+    #[test]
+    fn target_reward_stops_once_reached() {
+        let criteria = StoppingCriteria {
+            target_reward: Some(2.5),
+            ..StoppingCriteria::default()
+        };
+        assert_eq!(stopping_generation(criteria), Some(3));
+    }
+
+    #[test]
+    fn patience_stops_after_generations_without_improvement() {
+        let criteria = StoppingCriteria {
+            patience: Some(2),
+            ..StoppingCriteria::default()
+        };
+        assert_eq!(stopping_generation(criteria), Some(5));
+    }
+
+    #[test]
+    fn min_std_mean_stops_below_it() {
+        let criteria = StoppingCriteria {
+            min_std_mean: Some(0.3),
+            ..StoppingCriteria::default()
+        };
+        assert_eq!(stopping_generation(criteria), Some(4));
+    }
+
+    #[test]
+    fn max_wall_time_stops_once_reached() {
+        let criteria = StoppingCriteria {
+            max_wall_time_secs: Some(6.0),
+            ..StoppingCriteria::default()
+        };
+        assert_eq!(stopping_generation(criteria), Some(6));
+    }
+
+    #[test]
+    fn max_evaluations_stops_once_reached() {
+        let criteria = StoppingCriteria {
+            max_evaluations: Some(35),
+            ..StoppingCriteria::default()
+        };
+        assert_eq!(stopping_generation(criteria), Some(4));
+    }
+
+    /// Stats of the first generation of ceo with nan_policy, on rewards nan for some candidates.
+    fn nan_generation(nan_policy: NanPolicy) -> Result<GenerationStats<f64>, CeoError> {
+        let ceo = CEO {
+            generations: 1,
+            batch_size: 20,
+            num_evalation_samples: 1,
+            seed: Some(2),
+            nan_policy: nan_policy,
+            ..CEO::default()
+        };
+        let mut stats = vec![];
+        ceo.optimize_with(&mut fcn(), &SometimesNan, &mut |s: &GenerationStats<
+            f64,
+        >| {
+            stats.push(s.clone());
+            false
+        })?;
+        Ok(stats.pop().unwrap())
+    }
+
+    /// Seed of a nan reward, followed by one of a finite reward.
+    fn nan_seed() -> u64 {
+        (0..)
+            .step_by(4)
+            .find(|&seed| seed::derive(seed, 1) & 3 != 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn worst_ranks_nan_rewards_last() {
+        let (reward, num_invalid) =
+            NanPolicy::Worst.evaluate(&SometimesNan, &fcn(), fcn().params(), 1, nan_seed());
+        assert!(reward.is_nan());
+        assert_eq!(num_invalid, 1);
+        let mut rewards = [-1.0, f64::NAN, 2.0, f64::NEG_INFINITY, 0.0];
+        rewards.sort_by(|a, b| compare_rewards(*b, *a));
+        assert_eq!(rewards[..3], [2.0, 0.0, -1.0]);
+        assert!(rewards[3..].iter().all(|r| !r.is_finite()));
+
+        let stats = nan_generation(NanPolicy::Worst).unwrap();
+        assert!(stats.num_invalid > 0);
+        assert!(stats.min_reward.is_finite() && stats.elite_mean_reward.is_finite());
+        assert_eq!(stats.num_evaluations, 20 + 1);
+    }
+
+    #[test]
+    fn resample_redraws_nan_rewards() {
+        let seed = nan_seed();
+        let (reward, num_invalid) =
+            NanPolicy::Resample(3).evaluate(&SometimesNan, &fcn(), fcn().params(), 1, seed);
+        assert_eq!(
+            reward,
+            SometimesNan.reward(&fcn(), fcn().params(), 1, seed::derive(seed, 1))
+        );
+        assert_eq!(num_invalid, 1);
+
+        let stats = nan_generation(NanPolicy::Resample(10)).unwrap();
+        assert!(stats.num_invalid > 0);
+        // Every candidate and the mean params have a finite reward in the end
+        assert_eq!(stats.num_evaluations, 20 + 1 + stats.num_invalid);
+    }
+
+    #[test]
+    fn abort_fails_on_nan_rewards() {
+        assert!(matches!(
+            nan_generation(NanPolicy::Abort),
+            Err(CeoError::InvalidReward { generation: 1 })
+        ));
+    }
+
+    #[test]
+    fn noise_schedules_decay_from_noise_factor() {
+        // At the first, middle and last of 10 generations
+        let extra_stds = |schedule: NoiseSchedule, num_stagnant| {
+            [0, 5, 9]
+                .iter()
+                .map(|&g| schedule.extra_std::<f64>(2.0, g, 10, num_stagnant))
+                .collect::<Vec<f64>>()
+        };
+        let expected = vec![
+            (NoiseSchedule::Hyperbolic, [2.0, 2.0 / 6.0, 0.2]),
+            (NoiseSchedule::Constant, [2.0, 2.0, 2.0]),
+            (NoiseSchedule::Linear, [2.0, 1.0, 0.2]),
+            (
+                NoiseSchedule::Exponential { decay: 0.5 },
+                [2.0, 2.0 / 32.0, 2.0 / 512.0],
+            ),
+            (
+                NoiseSchedule::Cosine,
+                [2.0, 1.0, 1.0 + (0.9 * std::f64::consts::PI).cos()],
+            ),
+        ];
+        for (schedule, expected) in expected {
+            for (extra_std, expected) in extra_stds(schedule, 0).iter().zip(&expected) {
+                assert!((extra_std - expected).abs() < 1e-6, "{:?}", schedule);
+            }
+        }
+        let plateau = NoiseSchedule::Plateau {
+            patience: 2,
+            boost: 3.0,
+        };
+        assert_eq!(
+            extra_stds(plateau, 1),
+            extra_stds(NoiseSchedule::Hyperbolic, 1)
+        );
+        assert_eq!(extra_stds(plateau, 2), vec![6.0, 6.0, 6.0]);
+    }
+
+    #[test]
+    fn layer_noise_scales_apply_to_params_of_their_layer() {
+        let fcn = FCN::<f64>::new_graph_with(
+            vec![
+                (2, Activation::Linear),
+                (3, Activation::Tanh),
+                (1, Activation::Linear),
+            ],
+            vec![],
+            vec![Skip::Concat { from: 0, to: 2 }],
+            &Init::Normal(0.0, 0.5),
+            &mut seed::rng(0),
+        );
+        let ceo = CEO {
+            layer_noise_scales: vec![1.0, 0.0],
+            ..CEO::default()
+        };
+        let scales = ceo.noise_scales(&fcn).unwrap();
+        // Concat skip to the last layer is scaled as it
+        assert_eq!(fcn.layer_param_range(1), 0..9);
+        assert_eq!(fcn.skip_param_range(0), Some(13..15));
+        assert!(scales.slice(s![..9]).iter().all(|&scale| scale == 1.0));
+        assert!(scales.slice(s![9..]).iter().all(|&scale| scale == 0.0));
+
+        // Elite without spread, so std is only the scaled extra std
+        let mean = Array1::from_elem(15, 0.25);
+        let elite_ths = vec![mean.view(); 3];
+        let (mean, std) =
+            next_distribution(&elite_ths, Recombination::Equal, 0.5, &scales, (None, None))
+                .unwrap();
+        assert_eq!(std, &scales * 0.5);
+        let candidate = sample_candidate(7, &mean, &std);
+        assert!((0..9).all(|i| candidate[i] != mean[i]));
+        assert_eq!(candidate.slice(s![9..]), mean.slice(s![9..]));
+
+        let ceo = CEO {
+            layer_noise_scales: vec![1.0],
+            ..CEO::default()
+        };
+        assert!(matches!(
+            ceo.noise_scales(&fcn),
+            Err(CeoError::InvalidNoiseScales {
+                expected: 2,
+                found: 1
+            })
+        ));
     }
 }
@@ -0,0 +1,462 @@
+use crate::ceo::{
+    compare_rewards, count_evaluations, CeoError, CeoObserver, GenerationStats, HallOfFame,
+    NanPolicy, Reward, StoppingCriteria,
+};
+use crate::fcn::*;
+use crate::float::Float;
+use crate::optimizer::{Optimizer, Outcome};
+use crate::seed;
+use ndarray::prelude::*;
+use ndarray::ShapeError;
+use rand::RngCore;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// Covariance matrix adaptation evolution strategy with a full covariance, cumulative step-size adaptation,
+/// rank-one and rank-mu updates, and restarts with doubled population (ipop).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CmaEs {
+    /// Over all runs.
+    pub generations: usize,
+    /// Candidates per generation of the first run, 0 for 4 + 3 ln(num_params).
+    pub population_size: usize,
+    pub num_evaluation_samples: usize,
+    pub initial_sigma: f32,
+    /// Runs after the first, each from the initial params with twice the population of the previous one.
+    pub max_restarts: usize,
+    /// A run ends once sigma times the longest axis of the distribution falls below it.
+    pub tol_x: f32,
+    /// A run ends once reward of its mean params hasn't improved for this many generations, 0 for never.
+    pub stagnation_generations: usize,
+    /// Master seed of sampling and evaluations, none to draw one from entropy.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub nan_policy: NanPolicy,
+    /// Stop all runs, unlike tol_x and stagnation_generations which end one.
+    #[serde(default)]
+    pub stopping: StoppingCriteria,
+}
+
+impl Default for CmaEs {
+    fn default() -> CmaEs {
+        CmaEs {
+            generations: 300,
+            population_size: 0,
+            num_evaluation_samples: 300,
+            initial_sigma: 1.0,
+            max_restarts: 3,
+            tol_x: 1e-6,
+            stagnation_generations: 50,
+            seed: None,
+            nan_policy: NanPolicy::Worst,
+            stopping: StoppingCriteria::default(),
+        }
+    }
+}
+
+/// Search distribution of one run, sigma^2 C with C = B diag(D^2) B^T, along with its learning rates.
+struct Run<F> {
+    mean: Array1<F>,
+    sigma: F,
+    covariance: Array2<F>,
+    /// B, eigenvectors of C as columns.
+    axes: Array2<F>,
+    /// D, square roots of eigenvalues of C.
+    scales: Array1<F>,
+    sigma_path: Array1<F>,
+    covariance_path: Array1<F>,
+    population_size: usize,
+    /// Recombination weights of the best candidates, sum to 1.
+    weights: Array1<F>,
+    mu_eff: F,
+    c_sigma: F,
+    d_sigma: F,
+    c_c: F,
+    c_1: F,
+    c_mu: F,
+    /// Expected norm of a standard normal sample.
+    chi_n: F,
+    generation: usize,
+    eigen_generation: usize,
+}
+
+impl<F: Float> Run<F> {
+    fn new(mean: Array1<F>, sigma: F, population_size: usize) -> Run<F> {
+        let n = mean.len();
+        let num_params = F::from_usize(n);
+        let two = F::from_f32(2.0);
+        let mu = population_size / 2;
+        let weights = Array1::from_shape_fn(mu, |i| {
+            (F::from_usize(population_size) / two + F::from_f32(0.5)).ln()
+                - F::from_usize(i + 1).ln()
+        });
+        let weights = &weights / weights.sum();
+        let mu_eff = F::one() / weights.dot(&weights);
+        let c_sigma = (mu_eff + two) / (num_params + mu_eff + F::from_f32(5.0));
+        let d_sigma = F::one()
+            + two
+                * F::zero().max(((mu_eff - F::one()) / (num_params + F::one())).sqrt() - F::one())
+            + c_sigma;
+        let c_c = (F::from_f32(4.0) + mu_eff / num_params)
+            / (num_params + F::from_f32(4.0) + two * mu_eff / num_params);
+        let c_1 = two / ((num_params + F::from_f32(1.3)).powi(2) + mu_eff);
+        let c_mu = (F::one() - c_1)
+            .min(two * (mu_eff - two + F::one() / mu_eff) / ((num_params + two).powi(2) + mu_eff));
+        let chi_n = num_params.sqrt()
+            * (F::one() - F::one() / (F::from_f32(4.0) * num_params)
+                + F::one() / (F::from_f32(21.0) * num_params * num_params));
+        Run {
+            mean: mean,
+            sigma: sigma,
+            covariance: Array2::eye(n),
+            axes: Array2::eye(n),
+            scales: Array1::ones(n),
+            sigma_path: Array1::zeros(n),
+            covariance_path: Array1::zeros(n),
+            population_size: population_size,
+            weights: weights,
+            mu_eff: mu_eff,
+            c_sigma: c_sigma,
+            d_sigma: d_sigma,
+            c_c: c_c,
+            c_1: c_1,
+            c_mu: c_mu,
+            chi_n: chi_n,
+            generation: 0,
+            eigen_generation: 0,
+        }
+    }
+
+    /// Step y ~ N(0, C) from a standard normal sample z.
+    fn step(&self, z: Array1<F>) -> Array1<F> {
+        self.axes.dot(&(z * &self.scales))
+    }
+
+    /// Updates the distribution from steps of candidates, sorted from best to worst.
+    fn update(&mut self, sorted_steps: &[Array1<F>]) -> Result<(), ShapeError> {
+        let n = self.mean.len();
+        let two = F::from_f32(2.0);
+        let best_steps = sorted_steps
+            .iter()
+            .take(self.weights.len())
+            .map(|step| step.view())
+            .collect::<Vec<ArrayView1<F>>>();
+        let best_steps =
+            ndarray::stack(Axis(0), &best_steps)?.into_shape((self.weights.len(), n))?;
+        let weighted_step = best_steps.t().dot(&self.weights);
+        self.mean = &self.mean + &(&weighted_step * self.sigma);
+        // Cumulation of C^-1/2 step for sigma, and of step for C
+        let whitened_step = self
+            .axes
+            .dot(&(self.axes.t().dot(&weighted_step) / &self.scales));
+        self.sigma_path = &self.sigma_path * (F::one() - self.c_sigma)
+            + whitened_step * (self.c_sigma * (two - self.c_sigma) * self.mu_eff).sqrt();
+        let sigma_path_norm = self.sigma_path.dot(&self.sigma_path).sqrt();
+        self.generation += 1;
+        // Stalls update of covariance path while sigma path is long
+        let h_sigma = sigma_path_norm
+            / (F::one() - (F::one() - self.c_sigma).powi(2 * self.generation as i32)).sqrt()
+            / self.chi_n
+            < F::from_f32(1.4) + two / (F::from_usize(n) + F::one());
+        self.covariance_path = &self.covariance_path * (F::one() - self.c_c);
+        if h_sigma {
+            self.covariance_path = &self.covariance_path
+                + &(&weighted_step * (self.c_c * (two - self.c_c) * self.mu_eff).sqrt());
+        }
+        // Rank-one and rank-mu updates
+        let path = self.covariance_path.view().insert_axis(Axis(1));
+        let rank_one = path.dot(&path.t());
+        let weighted_best_steps = &best_steps * &self.weights.view().insert_axis(Axis(1));
+        let rank_mu = weighted_best_steps.t().dot(&best_steps);
+        let h_sigma_correction = if h_sigma {
+            F::zero()
+        } else {
+            self.c_1 * self.c_c * (two - self.c_c)
+        };
+        self.covariance = &self.covariance * (F::one() - self.c_1 - self.c_mu + h_sigma_correction)
+            + rank_one * self.c_1
+            + rank_mu * self.c_mu;
+        self.sigma *=
+            ((self.c_sigma / self.d_sigma) * (sigma_path_norm / self.chi_n - F::one())).exp();
+        // Decomposition is lazy since it is cubic in num_params
+        let lag = F::from_usize(self.population_size)
+            / ((self.c_1 + self.c_mu) * F::from_usize(n) * F::from_f32(10.0));
+        if F::from_usize(self.generation - self.eigen_generation) > lag {
+            self.eigen_generation = self.generation;
+            self.covariance = (&self.covariance + &self.covariance.t()) / two;
+            let (eigenvalues, eigenvectors) = symmetric_eigen(&self.covariance);
+            let floor = eigenvalues.fold(F::zero(), |max, &e| max.max(e)) * F::epsilon();
+            self.scales = eigenvalues.mapv(|e| e.max(floor).sqrt());
+            self.axes = eigenvectors;
+        }
+        Ok(())
+    }
+
+    /// Std per param, sigma sqrt(diag(C)).
+    fn std(&self) -> Array1<F> {
+        self.covariance.diag().mapv(|c| c.sqrt() * self.sigma)
+    }
+
+    /// True once the distribution is too narrow or too ill-conditioned to make progress.
+    fn converged(&self, tol_x: F) -> bool {
+        let max_scale = self.scales.fold(F::zero(), |max, &s| max.max(s));
+        let min_scale = self.scales.fold(F::infinity(), |min, &s| min.min(s));
+        let width = self.sigma * max_scale;
+        let condition = (max_scale / min_scale).powi(2);
+        width < tol_x || width.is_nan() || condition > F::one() / (F::epsilon() * F::from_f32(10.0))
+    }
+}
+
+impl Optimizer for CmaEs {
+    /// Sets params of fcn to the mean params with highest reward over all runs.
+    fn optimize_with<F: Float>(
+        &self,
+        fcn: &mut FCN<F>,
+        reward: &(dyn Reward<F> + std::marker::Sync),
+        observer: &mut dyn CeoObserver<F>,
+    ) -> Result<Outcome<F>, CeoError> {
+        let start = Instant::now();
+        let initial_params = fcn.params().clone();
+        let num_params = initial_params.len();
+        let initial_sigma = F::from_f32(self.initial_sigma);
+        let mut rng = seed::rng(seed::derive(
+            self.seed.unwrap_or_else(rand::random),
+            seed::OPTIMIZER_STREAM,
+        ));
+        let mut population_size = if self.population_size == 0 {
+            4 + (3.0 * (num_params as f32).ln()).floor() as usize
+        } else {
+            self.population_size
+        };
+        if population_size < 2 {
+            return Err(CeoError::TooFewCandidates);
+        }
+        let mut best: Option<(F, Array1<F>)> = None;
+        let mut std = Array1::from_elem(num_params, initial_sigma);
+        let mut generation = 0;
+        let mut num_evaluations = 0;
+        let mut observer = self.stopping.observer(observer);
+        'runs: for restart in 0..=self.max_restarts {
+            if restart > 0 {
+                population_size *= 2;
+            }
+            let mut run = Run::new(initial_params.clone(), initial_sigma, population_size);
+            let mut run_best_reward = F::neg_infinity();
+            let mut num_stagnant_generations = 0;
+            loop {
+                if generation >= self.generations {
+                    break 'runs;
+                }
+                // Every candidate has its own streams derived from the seed of its generation
+                let generation_seed = rng.next_u64();
+                let mut reward_step_tuples = (0..population_size)
+                    .into_par_iter()
+                    .map(|k| {
+                        let candidate_seed = seed::derive(generation_seed, k as u64);
+                        let mut noise_rng = seed::rng(seed::derive(candidate_seed, 0));
+                        let step = run.step(Array::from_shape_fn(num_params, |_| {
+                            F::standard_normal(&mut noise_rng)
+                        }));
+                        let params = &run.mean + &(&step * run.sigma);
+                        let (reward, num_invalid) = self.nan_policy.evaluate(
+                            reward,
+                            fcn,
+                            &params,
+                            self.num_evaluation_samples,
+                            seed::derive(candidate_seed, 1),
+                        );
+                        (reward, step, num_invalid)
+                    })
+                    .collect::<Vec<(F, Array1<F>, usize)>>();
+                let mut num_invalid = reward_step_tuples.iter().map(|t| t.2).sum();
+                self.nan_policy.check(num_invalid, generation + 1)?;
+                reward_step_tuples.sort_by(|a, b| compare_rewards(b.0, a.0));
+                let (rewards, sorted_steps): (Vec<_>, Vec<_>) = reward_step_tuples
+                    .into_iter()
+                    .map(|(reward, step, _)| (reward, step))
+                    .unzip();
+                run.update(&sorted_steps)?;
+                std = run.std();
+                let (current_reward, current_num_invalid) = self.nan_policy.evaluate(
+                    reward,
+                    fcn,
+                    &run.mean,
+                    self.num_evaluation_samples,
+                    seed::derive(generation_seed, population_size as u64),
+                );
+                num_invalid += current_num_invalid;
+                self.nan_policy.check(num_invalid, generation + 1)?;
+                if current_reward > run_best_reward {
+                    run_best_reward = current_reward;
+                    num_stagnant_generations = 0;
+                } else {
+                    num_stagnant_generations += 1;
+                }
+                let improved = match &best {
+                    Some((best_reward, _)) => current_reward > *best_reward,
+                    None => current_reward.is_finite(),
+                };
+                if improved {
+                    best = Some((current_reward, run.mean.clone()));
+                }
+                generation += 1;
+                num_evaluations += count_evaluations(&rewards, current_reward, num_invalid);
+                let stats = GenerationStats {
+                    wall_time: start.elapsed(),
+                    num_invalid: num_invalid,
+                    num_evaluations: num_evaluations,
+                    restart: restart,
+                    ..GenerationStats::from_sorted_rewards(
+                        generation,
+                        &rewards,
+                        run.weights.len(),
+                        current_reward,
+                        std.sum() / F::from_usize(num_params),
+                    )
+                };
+                if observer.observe(&stats) {
+                    break 'runs;
+                }
+                if run.converged(F::from_f32(self.tol_x))
+                    || (self.stagnation_generations > 0
+                        && num_stagnant_generations >= self.stagnation_generations)
+                {
+                    break;
+                }
+            }
+        }
+        if let Some((_, params)) = best {
+            fcn.set_params(params);
+        }
+        Ok(Outcome {
+            noise_std: std,
+            hall_of_fame: HallOfFame::default(),
+        })
+    }
+}
+
+/// Eigenvalues and eigenvectors, as columns, of a symmetric matrix by cyclic Jacobi rotations.
+fn symmetric_eigen<F: Float>(matrix: &Array2<F>) -> (Array1<F>, Array2<F>) {
+    const MAX_SWEEPS: usize = 50;
+    let n = matrix.nrows();
+    let mut a = matrix.clone();
+    let mut v = Array2::eye(n);
+    let norm = a.fold(F::zero(), |sum, &x| sum + x * x);
+    for _ in 0..MAX_SWEEPS {
+        let mut off_diagonal = F::zero();
+        for p in 0..n {
+            for q in (p + 1)..n {
+                off_diagonal += a[[p, q]] * a[[p, q]];
+            }
+        }
+        if off_diagonal <= norm * F::epsilon() * F::epsilon() {
+            break;
+        }
+        for p in 0..n {
+            for q in (p + 1)..n {
+                let a_pq = a[[p, q]];
+                if a_pq == F::zero() {
+                    continue;
+                }
+                // Rotation in the (p, q) plane that zeroes a_pq
+                let theta = (a[[q, q]] - a[[p, p]]) / (F::from_f32(2.0) * a_pq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + F::one()).sqrt());
+                let c = F::one() / (t * t + F::one()).sqrt();
+                let s = t * c;
+                for k in 0..n {
+                    let (a_kp, a_kq) = (a[[k, p]], a[[k, q]]);
+                    a[[k, p]] = c * a_kp - s * a_kq;
+                    a[[k, q]] = s * a_kp + c * a_kq;
+                }
+                for k in 0..n {
+                    let (a_pk, a_qk) = (a[[p, k]], a[[q, k]]);
+                    a[[p, k]] = c * a_pk - s * a_qk;
+                    a[[q, k]] = s * a_pk + c * a_qk;
+                }
+                for k in 0..n {
+                    let (v_kp, v_kq) = (v[[k, p]], v[[k, q]]);
+                    v[[k, p]] = c * v_kp - s * v_kq;
+                    v[[k, q]] = s * v_kp + c * v_kq;
+                }
+            }
+        }
+    }
+    (a.diag().to_owned(), v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Negative squared distance of params to 1.
+    struct Quadratic;
+
+    impl Reward<f64> for Quadratic {
+        fn reward(&self, _: &FCN<f64>, params: &Array1<f64>, _: usize, _: u64) -> f64 {
+            -params.mapv(|th| (th - 1.0) * (th - 1.0)).sum()
+        }
+    }
+
+    fn fcn() -> FCN<f64> {
+        FCN::new_with(
+            vec![
+                (2, Activation::Linear),
+                (2, Activation::Tanh),
+                (1, Activation::Linear),
+            ],
+            &Init::Normal(0.0, 0.5),
+            &mut seed::rng(0),
+        )
+    }
+
+    #[test]
+    fn reaches_the_optimum_of_a_quadratic() {
+        let cma_es = CmaEs {
+            generations: 300,
+            num_evaluation_samples: 1,
+            max_restarts: 0,
+            tol_x: 1e-8,
+            seed: Some(11),
+            ..CmaEs::default()
+        };
+        let mut fcn = fcn();
+        cma_es
+            .optimize_with(&mut fcn, &Quadratic, &mut |_: &GenerationStats<f64>| false)
+            .unwrap();
+        for &th in fcn.params() {
+            assert!((th - 1.0).abs() < 1e-4, "{}", th);
+        }
+    }
+
+    #[test]
+    fn restarts_double_the_population() {
+        // Every run converges after its first generation
+        let cma_es = CmaEs {
+            generations: 10,
+            population_size: 4,
+            num_evaluation_samples: 1,
+            max_restarts: 2,
+            tol_x: 1e9,
+            seed: Some(11),
+            ..CmaEs::default()
+        };
+        let mut stats = vec![];
+        cma_es
+            .optimize_with(&mut fcn(), &Quadratic, &mut |s: &GenerationStats<f64>| {
+                stats.push(s.clone());
+                false
+            })
+            .unwrap();
+        assert_eq!(stats.len(), 3);
+        let mut num_evaluations = 0;
+        for (restart, s) in stats.iter().enumerate() {
+            assert_eq!(s.restart, restart);
+            // Candidates and mean params
+            assert_eq!(s.num_evaluations - num_evaluations, (4 << restart) + 1);
+            num_evaluations = s.num_evaluations;
+        }
+    }
+}
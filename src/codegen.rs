@@ -0,0 +1,725 @@
+//! Generates inference source for a fcn that depends on nothing but libm.
+//! Math functions such as expf and tanhf are declared extern "C" in the rust source as well,
+//! so it only uses core but must be linked against a libm, as std targets are.
+//! The generated infer reproduces FCN::at bit-for-bit in f32: dot products are accumulated in
+//! the same order as ndarray and math functions are the platform libm ones that std also uses.
+//! The C source must be compiled without floating point contraction, ex. -ffp-contract=off.
+use crate::fcn::*;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodegenError {
+    /// Only feed-forward models can be exported.
+    RecurrentLayers,
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodegenError::RecurrentLayers => {
+                write!(
+                    f,
+                    "Trying to generate code for a model with recurrent layers."
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CodegenError {}
+
+/// f32 literal that parses back to exactly the same value in both rust and c.
+fn literal(x: f32, lang: Lang) -> String {
+    match lang {
+        Lang::Rust if x.is_nan() => String::from("f32::NAN"),
+        Lang::Rust if x == f32::INFINITY => String::from("f32::INFINITY"),
+        Lang::Rust if x == f32::NEG_INFINITY => String::from("f32::NEG_INFINITY"),
+        Lang::Rust => format!("{:?}", x),
+        Lang::C if x.is_nan() => String::from("NAN"),
+        Lang::C if x == f32::INFINITY => String::from("INFINITY"),
+        Lang::C if x == f32::NEG_INFINITY => String::from("(-INFINITY)"),
+        Lang::C => format!("{:?}f", x),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Lang {
+    Rust,
+    C,
+}
+
+fn literals<'a, I: Iterator<Item = &'a f32>>(xs: I, lang: Lang) -> String {
+    xs.map(|x| literal(*x, lang))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Statement applying activation of layer i on slice/pointer x of len dof.
+fn activation_call(fcn: &FCN, i: usize, x: &str, lang: Lang) -> String {
+    let (dof, activation) = &fcn.layers()[i];
+    let (slice, len) = match lang {
+        Lang::Rust => (format!("&mut {}[..{}]", x, dof), String::new()),
+        Lang::C => (x.to_string(), format!(", {}", dof)),
+    };
+    match activation {
+        Activation::Linear => String::new(),
+        Activation::LeakyReLu(leak) => format!(
+            "    leaky_relu({}{}, {});\n",
+            slice,
+            len,
+            literal(*leak, lang)
+        ),
+        Activation::Sigmoid => format!("    sigmoid({}{});\n", slice, len),
+        Activation::Tanh => format!("    tanh_({}{});\n", slice, len),
+        Activation::ReLu => format!("    relu({}{});\n", slice, len),
+        Activation::Elu(alpha) => {
+            format!("    elu({}{}, {});\n", slice, len, literal(*alpha, lang))
+        }
+        Activation::Softplus => format!("    softplus({}{});\n", slice, len),
+        Activation::Swish => format!("    swish({}{});\n", slice, len),
+        Activation::Gelu => format!("    gelu({}{});\n", slice, len),
+        Activation::Softmax => format!("    softmax({}{});\n", slice, len),
+        Activation::ScaledTanh(_) => match lang {
+            Lang::Rust => format!("    scaled_tanh({}, &BOUNDS{});\n", slice, i),
+            Lang::C => format!("    scaled_tanh({}{}, BOUNDS{});\n", slice, len, i),
+        },
+    }
+}
+
+fn sqrt_2_by_pi() -> f32 {
+    (2.0 / std::f32::consts::PI).sqrt()
+}
+
+fn check(fcn: &FCN) -> Result<(), CodegenError> {
+    if fcn.recurrent().is_empty() {
+        Ok(())
+    } else {
+        Err(CodegenError::RecurrentLayers)
+    }
+}
+
+/// Center and divisor arrays of the input and output normalizers, prefixed IN_ and OUT_.
+fn normalizer_statics(fcn: &FCN, lang: Lang) -> String {
+    let mut src = String::new();
+    for (prefix, normalizer) in [
+        ("IN", fcn.input_normalizer()),
+        ("OUT", fcn.output_normalizer()),
+    ]
+    .iter()
+    {
+        if let Some(normalizer) = normalizer {
+            let (center, divisor) = normalizer.center_and_divisor();
+            for (name, values) in [("CENTER", center), ("DIVISOR", divisor)].iter() {
+                src += &match lang {
+                    Lang::Rust => format!(
+                        "static {}_{}: [f32; {}] = [{}];\n",
+                        prefix,
+                        name,
+                        values.len(),
+                        literals(values.iter(), lang)
+                    ),
+                    Lang::C => format!(
+                        "static const float {}_{}[{}] = {{{}}};\n",
+                        prefix,
+                        name,
+                        values.len(),
+                        literals(values.iter(), lang)
+                    ),
+                };
+            }
+        }
+    }
+    src
+}
+
+/// Statement (de)normalizing slice/pointer x of len dof with the IN_ or OUT_ arrays, if fcn has such a normalizer.
+fn normalizer_call(fcn: &FCN, input: bool, x: &str, dof: usize, lang: Lang) -> String {
+    let (normalizer, function, prefix) = if input {
+        (fcn.input_normalizer(), "normalize", "IN")
+    } else {
+        (fcn.output_normalizer(), "denormalize", "OUT")
+    };
+    match (normalizer, lang) {
+        (None, _) => String::new(),
+        (Some(_), Lang::Rust) => format!(
+            "    {}(&mut {}[..{}], &{}_CENTER, &{}_DIVISOR);\n",
+            function, x, dof, prefix, prefix
+        ),
+        (Some(_), Lang::C) => format!(
+            "    {}({}, {}, {}_CENTER, {}_DIVISOR);\n",
+            function, x, dof, prefix, prefix
+        ),
+    }
+}
+
+/// Weight matrices of concat skips, named S by skip index.
+fn skip_statics(fcn: &FCN, lang: Lang) -> String {
+    let mut src = String::new();
+    for j in 0..fcn.skips().len() {
+        if let Some(weights) = fcn.skip_weights(j) {
+            src += &match lang {
+                Lang::Rust => format!(
+                    "static S{}: [f32; {}] = [{}];\n",
+                    j,
+                    weights.len(),
+                    literals(weights.iter(), lang)
+                ),
+                Lang::C => format!(
+                    "static const float S{}[{}] = {{{}}};\n",
+                    j,
+                    weights.len(),
+                    literals(weights.iter(), lang)
+                ),
+            };
+        }
+    }
+    src
+}
+
+/// Layers whose outputs are kept for skips, in increasing order.
+fn skip_sources(fcn: &FCN) -> Vec<usize> {
+    let mut sources = fcn.skips().iter().map(Skip::from).collect::<Vec<usize>>();
+    sources.sort_unstable();
+    sources.dedup();
+    sources
+}
+
+/// Declarations of the buffers keeping outputs of skip sources, named s by layer index.
+fn skip_buffers(fcn: &FCN, lang: Lang) -> String {
+    skip_sources(fcn)
+        .iter()
+        .map(|k| match lang {
+            Lang::Rust => format!("    let mut s{} = [0.0f32; {}];\n", k, fcn.layers()[*k].0),
+            Lang::C => format!("    float s{}[{}];\n", k, fcn.layers()[*k].0),
+        })
+        .collect()
+}
+
+/// Statement keeping output x of layer k, if it is a skip source.
+fn skip_save_call(fcn: &FCN, k: usize, x: &str, lang: Lang) -> String {
+    if !skip_sources(fcn).contains(&k) {
+        return String::new();
+    }
+    let dof = fcn.layers()[k].0;
+    match lang {
+        Lang::Rust => format!("    s{}.copy_from_slice(&{}[..{}]);\n", k, x, dof),
+        Lang::C => format!("    memcpy(s{}, {}, sizeof(float) * {});\n", k, x, dof),
+    }
+}
+
+/// Statements adding skips into layer i to its pre-activations y.
+fn skip_calls(fcn: &FCN, i: usize, y: &str, lang: Lang) -> String {
+    let dof = fcn.layers()[i].0;
+    let mut src = String::new();
+    for (j, skip) in fcn.skips().iter().enumerate() {
+        if skip.to() != i {
+            continue;
+        }
+        let (from, from_dof) = (skip.from(), fcn.layers()[skip.from()].0);
+        src += &match (skip, lang) {
+            (Skip::Residual { .. }, Lang::Rust) => {
+                format!("    add(&s{}, &mut {}[..{}]);\n", from, y, dof)
+            }
+            (Skip::Residual { .. }, Lang::C) => format!("    add(s{}, {}, {});\n", from, y, dof),
+            (Skip::Concat { .. }, Lang::Rust) => format!(
+                "    dense_acc(&S{}, &s{}, &mut {}[..{}]);\n",
+                j, from, y, dof
+            ),
+            (Skip::Concat { .. }, Lang::C) => format!(
+                "    dense_acc(S{}, s{}, {}, {}, {});\n",
+                j, from, from_dof, y, dof
+            ),
+        };
+    }
+    src
+}
+
+fn max_dof(fcn: &FCN) -> usize {
+    fcn.layers().iter().map(|(dof, _)| *dof).max().unwrap()
+}
+
+/// Rust module that only uses core and extern "C" functions of libm, with a single
+/// infer(&[f32; INPUT_LEN], &mut [f32; OUTPUT_LEN]).
+pub fn rust_module(fcn: &FCN) -> Result<String, CodegenError> {
+    check(fcn)?;
+    let layers = fcn.layers();
+    let last = layers.len() - 1;
+    let mut src = format!(
+        "//! Generated by wall-e codegen from {}.
+//! Only uses core, math functions are linked from the platform libm.
+#![allow(dead_code, clippy::all)]
+
+pub const INPUT_LEN: usize = {};
+pub const OUTPUT_LEN: usize = {};
+const MAX_LEN: usize = {};
+
+",
+        fcn,
+        layers[0].0,
+        layers[last].0,
+        max_dof(fcn)
+    );
+    for i in 1..layers.len() {
+        let weights = fcn.layer_weights(i);
+        let bias = fcn.layer_bias(i);
+        src += &format!(
+            "static W{}: [f32; {}] = [{}];\nstatic B{}: [f32; {}] = [{}];\n",
+            i,
+            weights.len(),
+            literals(weights.iter(), Lang::Rust),
+            i,
+            bias.len(),
+            literals(bias.iter(), Lang::Rust)
+        );
+    }
+    for (i, (dof, activation)) in layers.iter().enumerate() {
+        if let Activation::ScaledTanh(bounds) = activation {
+            src += &format!(
+                "static BOUNDS{}: [(f32, f32); {}] = [{}];\n",
+                i,
+                dof,
+                bounds
+                    .iter()
+                    .map(|(lo, hi)| format!(
+                        "({}, {})",
+                        literal(*lo, Lang::Rust),
+                        literal(*hi, Lang::Rust)
+                    ))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+        }
+    }
+    src += &normalizer_statics(fcn, Lang::Rust);
+    src += &skip_statics(fcn, Lang::Rust);
+    src += &format!(
+        "
+pub fn infer(input: &[f32; INPUT_LEN], output: &mut [f32; OUTPUT_LEN]) {{
+    let mut a = [0.0f32; MAX_LEN];
+    let mut b = [0.0f32; MAX_LEN];
+{}    a[..{}].copy_from_slice(input);
+{}{}{}",
+        skip_buffers(fcn, Lang::Rust),
+        layers[0].0,
+        normalizer_call(fcn, true, "a", layers[0].0, Lang::Rust),
+        activation_call(fcn, 0, "a", Lang::Rust),
+        skip_save_call(fcn, 0, "a", Lang::Rust)
+    );
+    let buffers = ["a", "b"];
+    for i in 1..layers.len() {
+        let (x, y) = (buffers[(i - 1) % 2], buffers[i % 2]);
+        src += &format!(
+            "    dense(&W{}, &B{}, &{}[..{}], &mut {}[..{}]);\n{}{}{}",
+            i,
+            i,
+            x,
+            layers[i - 1].0,
+            y,
+            layers[i].0,
+            skip_calls(fcn, i, y, Lang::Rust),
+            activation_call(fcn, i, y, Lang::Rust),
+            skip_save_call(fcn, i, y, Lang::Rust)
+        );
+    }
+    src += &normalizer_call(fcn, false, buffers[last % 2], layers[last].0, Lang::Rust);
+    src += &format!(
+        "    output.copy_from_slice(&{}[..{}]);
+}}
+",
+        buffers[last % 2],
+        layers[last].0
+    );
+    src += &RUST_HELPERS.replace("SQRT_2_BY_PI", &literal(sqrt_2_by_pi(), Lang::Rust));
+    Ok(src)
+}
+
+/// C header declaring infer(input, output) and the input/output lens.
+pub fn c_header(fcn: &FCN) -> Result<String, CodegenError> {
+    check(fcn)?;
+    let layers = fcn.layers();
+    Ok(format!(
+        "/* Generated by wall-e codegen from {}. */
+#ifndef WALL_E_FCN_H
+#define WALL_E_FCN_H
+
+#define FCN_INPUT_LEN {}
+#define FCN_OUTPUT_LEN {}
+
+void infer(const float input[FCN_INPUT_LEN], float output[FCN_OUTPUT_LEN]);
+
+#endif
+",
+        fcn,
+        layers[0].0,
+        layers[layers.len() - 1].0
+    ))
+}
+
+/// C source defining infer, which includes the header of given name.
+/// Compile with -ffp-contract=off and link with -lm.
+pub fn c_source(fcn: &FCN, header_name: &str) -> Result<String, CodegenError> {
+    check(fcn)?;
+    let layers = fcn.layers();
+    let last = layers.len() - 1;
+    let mut src = format!(
+        "/* Generated by wall-e codegen from {}.
+ * Compile with -ffp-contract=off to keep results bit-identical. */
+#include <math.h>
+#include <string.h>
+#include \"{}\"
+
+#define MAX_LEN {}
+
+",
+        fcn,
+        header_name,
+        max_dof(fcn)
+    );
+    for i in 1..layers.len() {
+        let weights = fcn.layer_weights(i);
+        let bias = fcn.layer_bias(i);
+        src += &format!(
+            "static const float W{}[{}] = {{{}}};\nstatic const float B{}[{}] = {{{}}};\n",
+            i,
+            weights.len(),
+            literals(weights.iter(), Lang::C),
+            i,
+            bias.len(),
+            literals(bias.iter(), Lang::C)
+        );
+    }
+    for (i, (dof, activation)) in layers.iter().enumerate() {
+        if let Activation::ScaledTanh(bounds) = activation {
+            src += &format!(
+                "static const float BOUNDS{}[{}][2] = {{{}}};\n",
+                i,
+                dof,
+                bounds
+                    .iter()
+                    .map(|(lo, hi)| format!(
+                        "{{{}, {}}}",
+                        literal(*lo, Lang::C),
+                        literal(*hi, Lang::C)
+                    ))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            );
+        }
+    }
+    src += &normalizer_statics(fcn, Lang::C);
+    src += &skip_statics(fcn, Lang::C);
+    src += &C_HELPERS.replace("SQRT_2_BY_PI", &literal(sqrt_2_by_pi(), Lang::C));
+    src += &format!(
+        "
+void infer(const float input[FCN_INPUT_LEN], float output[FCN_OUTPUT_LEN]) {{
+    float a[MAX_LEN];
+    float b[MAX_LEN];
+{}    memcpy(a, input, sizeof(float) * {});
+{}{}{}",
+        skip_buffers(fcn, Lang::C),
+        layers[0].0,
+        normalizer_call(fcn, true, "a", layers[0].0, Lang::C),
+        activation_call(fcn, 0, "a", Lang::C),
+        skip_save_call(fcn, 0, "a", Lang::C)
+    );
+    let buffers = ["a", "b"];
+    for i in 1..layers.len() {
+        let (x, y) = (buffers[(i - 1) % 2], buffers[i % 2]);
+        src += &format!(
+            "    dense(W{}, B{}, {}, {}, {}, {});\n{}{}{}",
+            i,
+            i,
+            x,
+            layers[i - 1].0,
+            y,
+            layers[i].0,
+            skip_calls(fcn, i, y, Lang::C),
+            activation_call(fcn, i, y, Lang::C),
+            skip_save_call(fcn, i, y, Lang::C)
+        );
+    }
+    src += &normalizer_call(fcn, false, buffers[last % 2], layers[last].0, Lang::C);
+    src += &format!(
+        "    memcpy(output, {}, sizeof(float) * {});
+}}
+",
+        buffers[last % 2],
+        layers[last].0
+    );
+    Ok(src)
+}
+
+const RUST_HELPERS: &str = "
+extern \"C\" {
+    fn expf(x: f32) -> f32;
+    fn expm1f(x: f32) -> f32;
+    fn log1pf(x: f32) -> f32;
+    fn tanhf(x: f32) -> f32;
+}
+
+fn exp(x: f32) -> f32 {
+    unsafe { expf(x) }
+}
+
+fn abs(x: f32) -> f32 {
+    f32::from_bits(x.to_bits() & 0x7fff_ffff)
+}
+
+/// Eightfold unrolled like ndarray.
+fn fold_sum(xs: &[f32], ys: Option<&[f32]>) -> f32 {
+    let term = |i: usize| match ys {
+        Some(ys) => xs[i] * ys[i],
+        None => xs[i],
+    };
+    let mut p = [0.0f32; 8];
+    let mut i = 0;
+    while xs.len() - i >= 8 {
+        for (j, p) in p.iter_mut().enumerate() {
+            *p = *p + term(i + j);
+        }
+        i += 8;
+    }
+    let mut sum = 0.0f32;
+    sum = sum + (p[0] + p[4]);
+    sum = sum + (p[1] + p[5]);
+    sum = sum + (p[2] + p[6]);
+    sum = sum + (p[3] + p[7]);
+    while i < xs.len() {
+        sum = sum + term(i);
+        i += 1;
+    }
+    sum
+}
+
+fn dense(w: &[f32], b: &[f32], x: &[f32], y: &mut [f32]) {
+    for (r, y) in y.iter_mut().enumerate() {
+        *y = fold_sum(&w[(r * x.len())..((r + 1) * x.len())], Some(x)) + b[r];
+    }
+}
+
+fn dense_acc(w: &[f32], x: &[f32], y: &mut [f32]) {
+    for (r, y) in y.iter_mut().enumerate() {
+        *y = *y + fold_sum(&w[(r * x.len())..((r + 1) * x.len())], Some(x));
+    }
+}
+
+fn add(x: &[f32], y: &mut [f32]) {
+    for (x, y) in x.iter().zip(y.iter_mut()) {
+        *y = *y + x;
+    }
+}
+
+fn leaky_relu(x: &mut [f32], leak: f32) {
+    for e in x.iter_mut() {
+        *e = if *e > 0.0 { *e } else { *e * leak };
+    }
+}
+
+fn sigmoid(x: &mut [f32]) {
+    for e in x.iter_mut() {
+        *e = 1.0 / (1.0 + exp(-*e));
+    }
+}
+
+fn tanh_(x: &mut [f32]) {
+    for e in x.iter_mut() {
+        *e = unsafe { tanhf(*e) };
+    }
+}
+
+fn relu(x: &mut [f32]) {
+    for e in x.iter_mut() {
+        *e = e.max(0.0);
+    }
+}
+
+fn elu(x: &mut [f32], alpha: f32) {
+    for e in x.iter_mut() {
+        *e = if *e > 0.0 { *e } else { alpha * unsafe { expm1f(*e) } };
+    }
+}
+
+fn softplus(x: &mut [f32]) {
+    for e in x.iter_mut() {
+        *e = e.max(0.0) + unsafe { log1pf(exp(-abs(*e))) };
+    }
+}
+
+fn swish(x: &mut [f32]) {
+    for e in x.iter_mut() {
+        *e = *e * (1.0 / (1.0 + exp(-*e)));
+    }
+}
+
+fn gelu(x: &mut [f32]) {
+    for e in x.iter_mut() {
+        let v = *e;
+        *e = 0.5 * v * (1.0 + unsafe { tanhf(SQRT_2_BY_PI * (v + 0.044715 * v * v * v)) });
+    }
+}
+
+fn softmax(x: &mut [f32]) {
+    let max = x.iter().fold(f32::NEG_INFINITY, |max, &e| max.max(e));
+    for e in x.iter_mut() {
+        *e = exp(*e - max);
+    }
+    let sum = fold_sum(x, None);
+    for e in x.iter_mut() {
+        *e = *e / sum;
+    }
+}
+
+fn scaled_tanh(x: &mut [f32], bounds: &[(f32, f32)]) {
+    for (e, (lo, hi)) in x.iter_mut().zip(bounds.iter()) {
+        *e = lo + (hi - lo) * 0.5 * (unsafe { tanhf(*e) } + 1.0);
+    }
+}
+
+fn normalize(x: &mut [f32], center: &[f32], divisor: &[f32]) {
+    for ((e, c), d) in x.iter_mut().zip(center.iter()).zip(divisor.iter()) {
+        *e = (*e - c) / d;
+    }
+}
+
+fn denormalize(x: &mut [f32], center: &[f32], divisor: &[f32]) {
+    for ((e, c), d) in x.iter_mut().zip(center.iter()).zip(divisor.iter()) {
+        *e = *e * d + c;
+    }
+}
+";
+
+const C_HELPERS: &str = "
+/* Eightfold unrolled like ndarray, ys == 0 sums xs. */
+static float fold_sum(const float *xs, const float *ys, int len) {
+    float p[8] = {0.0f, 0.0f, 0.0f, 0.0f, 0.0f, 0.0f, 0.0f, 0.0f};
+    float sum = 0.0f;
+    int i = 0, j;
+    for (; len - i >= 8; i += 8) {
+        for (j = 0; j < 8; j++) {
+            p[j] = p[j] + (ys ? xs[i + j] * ys[i + j] : xs[i + j]);
+        }
+    }
+    sum = sum + (p[0] + p[4]);
+    sum = sum + (p[1] + p[5]);
+    sum = sum + (p[2] + p[6]);
+    sum = sum + (p[3] + p[7]);
+    for (; i < len; i++) {
+        sum = sum + (ys ? xs[i] * ys[i] : xs[i]);
+    }
+    return sum;
+}
+
+static void dense(const float *w, const float *b, const float *x, int x_len, float *y, int y_len) {
+    int r;
+    for (r = 0; r < y_len; r++) {
+        y[r] = fold_sum(w + r * x_len, x, x_len) + b[r];
+    }
+}
+
+static void dense_acc(const float *w, const float *x, int x_len, float *y, int y_len) {
+    int r;
+    for (r = 0; r < y_len; r++) {
+        y[r] = y[r] + fold_sum(w + r * x_len, x, x_len);
+    }
+}
+
+static void add(const float *x, float *y, int len) {
+    int i;
+    for (i = 0; i < len; i++) {
+        y[i] = y[i] + x[i];
+    }
+}
+
+static void leaky_relu(float *x, int len, float leak) {
+    int i;
+    for (i = 0; i < len; i++) {
+        x[i] = x[i] > 0.0f ? x[i] : x[i] * leak;
+    }
+}
+
+static void sigmoid(float *x, int len) {
+    int i;
+    for (i = 0; i < len; i++) {
+        x[i] = 1.0f / (1.0f + expf(-x[i]));
+    }
+}
+
+static void tanh_(float *x, int len) {
+    int i;
+    for (i = 0; i < len; i++) {
+        x[i] = tanhf(x[i]);
+    }
+}
+
+static void relu(float *x, int len) {
+    int i;
+    for (i = 0; i < len; i++) {
+        x[i] = fmaxf(x[i], 0.0f);
+    }
+}
+
+static void elu(float *x, int len, float alpha) {
+    int i;
+    for (i = 0; i < len; i++) {
+        x[i] = x[i] > 0.0f ? x[i] : alpha * expm1f(x[i]);
+    }
+}
+
+static void softplus(float *x, int len) {
+    int i;
+    for (i = 0; i < len; i++) {
+        x[i] = fmaxf(x[i], 0.0f) + log1pf(expf(-fabsf(x[i])));
+    }
+}
+
+static void swish(float *x, int len) {
+    int i;
+    for (i = 0; i < len; i++) {
+        x[i] = x[i] * (1.0f / (1.0f + expf(-x[i])));
+    }
+}
+
+static void gelu(float *x, int len) {
+    int i;
+    for (i = 0; i < len; i++) {
+        float v = x[i];
+        x[i] = 0.5f * v * (1.0f + tanhf(SQRT_2_BY_PI * (v + 0.044715f * v * v * v)));
+    }
+}
+
+static void softmax(float *x, int len) {
+    float max = -INFINITY, sum;
+    int i;
+    for (i = 0; i < len; i++) {
+        max = fmaxf(max, x[i]);
+    }
+    for (i = 0; i < len; i++) {
+        x[i] = expf(x[i] - max);
+    }
+    sum = fold_sum(x, 0, len);
+    for (i = 0; i < len; i++) {
+        x[i] = x[i] / sum;
+    }
+}
+
+static void scaled_tanh(float *x, int len, const float bounds[][2]) {
+    int i;
+    for (i = 0; i < len; i++) {
+        x[i] = bounds[i][0] + (bounds[i][1] - bounds[i][0]) * 0.5f * (tanhf(x[i]) + 1.0f);
+    }
+}
+
+static void normalize(float *x, int len, const float *center, const float *divisor) {
+    int i;
+    for (i = 0; i < len; i++) {
+        x[i] = (x[i] - center[i]) / divisor[i];
+    }
+}
+
+static void denormalize(float *x, int len, const float *center, const float *divisor) {
+    int i;
+    for (i = 0; i < len; i++) {
+        x[i] = x[i] * divisor[i] + center[i];
+    }
+}
+";
@@ -72,8 +72,8 @@ pub struct DiffDriveModel {
 }
 
 impl DiffDriveModel {
-    const V_BOUNDS: (f32, f32) = (0.0, 20.0);
-    const W_BOUNDS: (f32, f32) = (-1.0, 1.0);
+    pub const V_BOUNDS: (f32, f32) = (0.0, 20.0);
+    pub const W_BOUNDS: (f32, f32) = (-1.0, 1.0);
     const TRIAL_LENGTH: usize = 500;
 
     pub fn spawn_randomly(
@@ -82,15 +82,37 @@ impl DiffDriveModel {
         or_bounds: (f32, f32),
         radius: f32,
         goal: (f32, f32),
+    ) -> DiffDriveModel {
+        DiffDriveModel::spawn_randomly_using(
+            x_bounds,
+            y_bounds,
+            or_bounds,
+            radius,
+            goal,
+            &mut rand::thread_rng(),
+        )
+    }
+
+    pub fn spawn_randomly_using<R: Rng + ?Sized>(
+        x_bounds: (f32, f32),
+        y_bounds: (f32, f32),
+        or_bounds: (f32, f32),
+        radius: f32,
+        goal: (f32, f32),
+        rng: &mut R,
     ) -> DiffDriveModel {
         // Compile time asserts
         const_assert!(DiffDriveModel::V_BOUNDS.0 < DiffDriveModel::V_BOUNDS.1);
         const_assert!(DiffDriveModel::W_BOUNDS.0 < DiffDriveModel::W_BOUNDS.1);
         // Spawn at random location
-        let mut rng = rand::thread_rng();
         let x = x_bounds.0 + (x_bounds.1 - x_bounds.0) * rng.gen::<f32>();
         let y = y_bounds.0 + (y_bounds.1 - y_bounds.0) * rng.gen::<f32>();
         let or = or_bounds.0 + (or_bounds.1 - or_bounds.0) * rng.gen::<f32>();
+        DiffDriveModel::at(x, y, or, radius, goal)
+    }
+
+    /// Agent at rest at the pose (x, y, or).
+    pub fn at(x: f32, y: f32, or: f32, radius: f32, goal: (f32, f32)) -> DiffDriveModel {
         // Trail config
         let mut trail = Trail::new(DiffDriveModel::TRIAL_LENGTH);
         trail.add(x, y);
@@ -154,9 +176,14 @@ impl DiffDriveModel {
         (self.v, self.w)
     }
 
-    // pub fn state(&self) -> (f32, f32, f32) {
-    //     (self.x, self.y, self.or_in_rad)
-    // }
+    pub fn state(&self) -> (f32, f32, f32) {
+        (self.x, self.y, self.or_in_rad)
+    }
+
+    /// Goal coordinates relative to agent and orientation, without scaling.
+    pub fn relative_state(&self) -> (f32, f32, f32) {
+        (self.goal.0 - self.x, self.goal.1 - self.y, self.or_in_rad)
+    }
 
     pub fn scaled_state(&self) -> (f32, f32, f32) {
         (
@@ -0,0 +1,276 @@
+use crate::ceo::{
+    compare_rewards, count_evaluations, CeoError, CeoObserver, GenerationStats, HallOfFame,
+    NanPolicy, Reward, StoppingCriteria,
+};
+use crate::fcn::*;
+use crate::float::Float;
+use crate::gd::{Update, UpdateState};
+use crate::optimizer::{Optimizer, Outcome};
+use crate::seed;
+use ndarray::prelude::*;
+use rand::RngCore;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// Openai style evolution strategy. Ascends a gradient of reward estimated from all candidates,
+/// which are mirrored gaussian perturbations of params with rewards shaped into centered ranks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ES {
+    pub generations: usize,
+    /// Candidates per generation are twice this, every perturbation is also evaluated negated.
+    pub num_pairs: usize,
+    pub num_evaluation_samples: usize,
+    /// Std of perturbations.
+    pub noise_std: f32,
+    pub learning_rate: f32,
+    /// Coefficient of the l2 penalty of params, applied along with the estimated gradient.
+    pub weight_decay: f32,
+    pub update: Update,
+    /// Master seed of perturbations and evaluations, none to draw one from entropy.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub nan_policy: NanPolicy,
+    #[serde(default)]
+    pub stopping: StoppingCriteria,
+}
+
+impl Default for ES {
+    fn default() -> ES {
+        ES {
+            generations: 300,
+            num_pairs: 25,
+            num_evaluation_samples: 300,
+            noise_std: 0.1,
+            learning_rate: 0.03,
+            weight_decay: 0.005,
+            update: Update::Adam {
+                beta1: 0.9,
+                beta2: 0.999,
+                epsilon: 1e-8,
+            },
+            seed: None,
+            nan_policy: NanPolicy::Worst,
+            stopping: StoppingCriteria::default(),
+        }
+    }
+}
+
+impl Optimizer for ES {
+    /// Sets params of fcn to the params after the last generation,
+    /// or to the best ones if stopped early by the stopping criteria, an interrupt or observer.
+    fn optimize_with<F: Float>(
+        &self,
+        fcn: &mut FCN<F>,
+        reward: &(dyn Reward<F> + std::marker::Sync),
+        observer: &mut dyn CeoObserver<F>,
+    ) -> Result<Outcome<F>, CeoError> {
+        if self.num_pairs == 0 {
+            return Err(CeoError::TooFewCandidates);
+        }
+        let start = Instant::now();
+        let num_params = fcn.params().len();
+        let noise_std = F::from_f32(self.noise_std);
+        let mut rng = seed::rng(seed::derive(
+            self.seed.unwrap_or_else(rand::random),
+            seed::OPTIMIZER_STREAM,
+        ));
+        let mut update_state = UpdateState::new(num_params);
+        let mut best: Option<(F, Array1<F>)> = None;
+        let mut num_evaluations = 0;
+        let mut observer = self.stopping.observer(observer);
+        for generation in 0..self.generations {
+            // Every pair has its own streams derived from the seed of its generation,
+            // both of its members share episodes so that their difference is due to the perturbation only
+            let generation_seed = rng.next_u64();
+            let pairs = (0..self.num_pairs)
+                .into_par_iter()
+                .map(|k| {
+                    let pair_seed = seed::derive(generation_seed, k as u64);
+                    let mut noise_rng = seed::rng(seed::derive(pair_seed, 0));
+                    let randn_noise =
+                        Array::from_shape_fn(num_params, |_| F::standard_normal(&mut noise_rng));
+                    let scaled_randn_noise = &randn_noise * noise_std;
+                    let reward_seed = seed::derive(pair_seed, 1);
+                    let (positive_reward, positive_num_invalid) = self.nan_policy.evaluate(
+                        reward,
+                        fcn,
+                        &(fcn.params() + &scaled_randn_noise),
+                        self.num_evaluation_samples,
+                        reward_seed,
+                    );
+                    let (negative_reward, negative_num_invalid) = self.nan_policy.evaluate(
+                        reward,
+                        fcn,
+                        &(fcn.params() - &scaled_randn_noise),
+                        self.num_evaluation_samples,
+                        reward_seed,
+                    );
+                    (
+                        positive_reward,
+                        negative_reward,
+                        randn_noise,
+                        positive_num_invalid + negative_num_invalid,
+                    )
+                })
+                .collect::<Vec<(F, F, Array1<F>, usize)>>();
+            let mut num_invalid = pairs.iter().map(|pair| pair.3).sum();
+            self.nan_policy.check(num_invalid, generation + 1)?;
+            let rewards = pairs
+                .iter()
+                .flat_map(|(positive, negative, _, _)| vec![*positive, *negative])
+                .collect::<Vec<F>>();
+            let ranks = centered_ranks(&rewards);
+            // Gradient of reward, mean of (f(th + std e) - f(th - std e)) e / (2 std)
+            let mut grads = Array1::zeros(num_params);
+            for (k, (_, _, randn_noise, _)) in pairs.iter().enumerate() {
+                grads.scaled_add(ranks[2 * k] - ranks[2 * k + 1], randn_noise);
+            }
+            let grads = grads / (F::from_usize(2 * self.num_pairs) * noise_std);
+            // Update descends penalized negative reward
+            let loss_grads = fcn.params() * F::from_f32(self.weight_decay) - grads;
+            let delta =
+                update_state.delta(&self.update, loss_grads, F::from_f32(self.learning_rate));
+            fcn.set_params(fcn.params() - &delta);
+            let (current_reward, current_num_invalid) = self.nan_policy.evaluate(
+                reward,
+                fcn,
+                fcn.params(),
+                self.num_evaluation_samples,
+                seed::derive(generation_seed, self.num_pairs as u64),
+            );
+            num_invalid += current_num_invalid;
+            self.nan_policy.check(num_invalid, generation + 1)?;
+            let improved = match &best {
+                Some((best_reward, _)) => current_reward > *best_reward,
+                None => current_reward.is_finite(),
+            };
+            if improved {
+                best = Some((current_reward, fcn.params().clone()));
+            }
+            let mut sorted_rewards = rewards.clone();
+            sorted_rewards.sort_by(|a, b| compare_rewards(*b, *a));
+            num_evaluations += count_evaluations(&rewards, current_reward, num_invalid);
+            // Better half is elite
+            let stats = GenerationStats {
+                wall_time: start.elapsed(),
+                num_invalid: num_invalid,
+                num_evaluations: num_evaluations,
+                ..GenerationStats::from_sorted_rewards(
+                    generation + 1,
+                    &sorted_rewards,
+                    self.num_pairs,
+                    current_reward,
+                    noise_std,
+                )
+            };
+            if observer.observe(&stats) {
+                if let Some((_, best_params)) = best {
+                    fcn.set_params(best_params);
+                }
+                break;
+            }
+        }
+        Ok(Outcome {
+            noise_std: Array::from_elem(num_params, noise_std),
+            hall_of_fame: HallOfFame::default(),
+        })
+    }
+}
+
+/// Ranks of xs mapped onto [-0.5, 0.5], ties broken by order and invalid rewards ranked lowest.
+fn centered_ranks<F: Float>(xs: &[F]) -> Vec<F> {
+    let mut order = (0..xs.len()).collect::<Vec<usize>>();
+    order.sort_by(|&a, &b| compare_rewards(xs[a], xs[b]));
+    let mut ranks = vec![F::zero(); xs.len()];
+    for (rank, &k) in order.iter().enumerate() {
+        ranks[k] = F::from_usize(rank) / F::from_usize(xs.len() - 1) - F::from_f32(0.5);
+    }
+    ranks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Negative squared distance of params to 1, or 0 everywhere if flat.
+    struct Quadratic {
+        flat: bool,
+    }
+
+    impl Reward<f64> for Quadratic {
+        fn reward(&self, _: &FCN<f64>, params: &Array1<f64>, _: usize, _: u64) -> f64 {
+            if self.flat {
+                0.0
+            } else {
+                -params.mapv(|th| (th - 1.0) * (th - 1.0)).sum()
+            }
+        }
+    }
+
+    fn fcn() -> FCN<f64> {
+        FCN::new_with(
+            vec![
+                (2, Activation::Linear),
+                (2, Activation::Tanh),
+                (1, Activation::Linear),
+            ],
+            &Init::Normal(0.0, 0.5),
+            &mut seed::rng(0),
+        )
+    }
+
+    #[test]
+    fn centered_ranks_are_spread_over_unit_interval() {
+        let ranks = centered_ranks(&[3.0, f64::NAN, -1.0, 10.0, 0.0]);
+        assert_eq!(ranks, vec![0.25, -0.5, -0.25, 0.5, 0.0]);
+    }
+
+    #[test]
+    fn improves_reward_of_a_quadratic() {
+        let es = ES {
+            generations: 100,
+            num_pairs: 10,
+            num_evaluation_samples: 1,
+            weight_decay: 0.0,
+            seed: Some(4),
+            ..ES::default()
+        };
+        let reward = Quadratic { flat: false };
+        let mut fcn = fcn();
+        let initial_reward = reward.reward(&fcn, fcn.params(), 1, 0);
+        es.optimize_with(&mut fcn, &reward, &mut |_: &GenerationStats<f64>| false)
+            .unwrap();
+        let final_reward = reward.reward(&fcn, fcn.params(), 1, 0);
+        assert!(final_reward > 0.1 * initial_reward, "{}", final_reward);
+    }
+
+    #[test]
+    fn weight_decay_shrinks_params_under_flat_reward() {
+        let norm = |weight_decay| {
+            let es = ES {
+                generations: 30,
+                num_pairs: 10,
+                num_evaluation_samples: 1,
+                weight_decay: weight_decay,
+                learning_rate: 0.1,
+                update: Update::Sgd,
+                seed: Some(4),
+                ..ES::default()
+            };
+            let mut fcn = fcn();
+            es.optimize_with(
+                &mut fcn,
+                &Quadratic { flat: true },
+                &mut |_: &GenerationStats<f64>| false,
+            )
+            .unwrap();
+            fcn.params().dot(fcn.params()).sqrt()
+        };
+        let initial_norm = fcn().params().dot(fcn().params()).sqrt();
+        assert!(norm(1.0) < 0.1 * initial_norm);
+        assert!(norm(0.0) > 0.9 * initial_norm);
+    }
+}
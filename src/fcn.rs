@@ -1,109 +1,1967 @@
+use crate::float::Float;
+use ndarray::linalg::{general_mat_mul, general_mat_vec_mul};
 use ndarray::prelude::*;
+use ndarray::Zip;
 use ndarray_rand::rand_distr::Uniform;
 use ndarray_rand::RandomExt;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
 use std::fmt;
+use std::ops::Range;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub enum Activation {
     Linear,
     LeakyReLu(f32),
     Sigmoid,
+    Tanh,
+    ReLu,
+    /// Exponential linear unit with given alpha.
+    Elu(f32),
+    Softplus,
+    Swish,
+    /// Tanh approximation of gaussian error linear unit.
+    Gelu,
+    /// Normalizes the layer into a probability distribution.
+    Softmax,
+    /// Squashes each unit into its (lo, hi) bounds, one bound per unit of the layer.
+    ScaledTanh(Vec<(f32, f32)>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Activation {
+    /// Applies activation along the last axis, so that both a single input and a batch of inputs work.
+    pub(crate) fn activate<F: Float, D: Dimension>(&self, mut x: Array<F, D>) -> Array<F, D> {
+        self.activate_inplace(x.view_mut());
+        x
+    }
+
+    /// Same as activate, in place.
+    pub(crate) fn activate_inplace<F: Float, D: Dimension>(&self, mut x: ArrayViewMut<'_, F, D>) {
+        let last_axis = Axis(x.ndim() - 1);
+        let (zero, one, half) = (F::zero(), F::one(), F::from_f32(0.5));
+        match self {
+            Activation::Linear => {}
+            Activation::LeakyReLu(leak) => {
+                let leak = F::from_f32(*leak);
+                x.mapv_inplace(|e| if e > zero { e } else { e * leak })
+            }
+            Activation::Sigmoid => x.mapv_inplace(sigmoid),
+            Activation::Tanh => x.mapv_inplace(F::tanh),
+            Activation::ReLu => x.mapv_inplace(|e| e.max(zero)),
+            Activation::Elu(alpha) => {
+                let alpha = F::from_f32(*alpha);
+                x.mapv_inplace(|e| if e > zero { e } else { alpha * e.exp_m1() })
+            }
+            Activation::Softplus => x.mapv_inplace(|e| e.max(zero) + (-e.abs()).exp().ln_1p()),
+            Activation::Swish => x.mapv_inplace(|e| e * sigmoid(e)),
+            Activation::Gelu => x.mapv_inplace(|e| {
+                let sqrt_2_by_pi = (F::from_f32(2.0) / F::from_f32(std::f32::consts::PI)).sqrt();
+                half * e * (one + (sqrt_2_by_pi * (e + F::from_f32(0.044715) * e * e * e)).tanh())
+            }),
+            Activation::Softmax => {
+                for mut lane in x.lanes_mut(last_axis) {
+                    let max = lane.fold(F::neg_infinity(), |max, &e| max.max(e));
+                    lane.mapv_inplace(|e| (e - max).exp());
+                    let sum = lane.sum();
+                    lane.mapv_inplace(|e| e / sum);
+                }
+            }
+            Activation::ScaledTanh(bounds) => {
+                for mut lane in x.lanes_mut(last_axis) {
+                    for (e, (lo, hi)) in lane.iter_mut().zip(bounds.iter()) {
+                        let (lo, hi) = (F::from_f32(*lo), F::from_f32(*hi));
+                        *e = lo + (hi - lo) * half * (e.tanh() + one);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Gradient w.r.t. pre-activations z of a batch, given activations a = activate(z) and gradient w.r.t. a.
+    fn backward<F: Float>(&self, z: &Array2<F>, a: &Array2<F>, grad_a: Array2<F>) -> Array2<F> {
+        let (zero, one, half) = (F::zero(), F::one(), F::from_f32(0.5));
+        match self {
+            Activation::Linear => grad_a,
+            Activation::LeakyReLu(leak) => {
+                let leak = F::from_f32(*leak);
+                grad_a * &z.mapv(|e| if e > zero { one } else { leak })
+            }
+            Activation::Sigmoid => grad_a * &a.mapv(|e| e * (one - e)),
+            Activation::Tanh => grad_a * &a.mapv(|e| one - e * e),
+            Activation::ReLu => grad_a * &z.mapv(|e| if e > zero { one } else { zero }),
+            Activation::Elu(alpha) => {
+                let alpha = F::from_f32(*alpha);
+                let mut grad_z = grad_a;
+                Zip::from(&mut grad_z).and(z).and(a).apply(|g, &z, &a| {
+                    if z <= zero {
+                        *g *= a + alpha;
+                    }
+                });
+                grad_z
+            }
+            Activation::Softplus => grad_a * &z.mapv(sigmoid),
+            Activation::Swish => grad_a * &z.mapv(|e| sigmoid(e) * (one + e * (one - sigmoid(e)))),
+            Activation::Gelu => {
+                let c = F::from_f32(0.044715);
+                grad_a
+                    * &z.mapv(|e| {
+                        let sqrt_2_by_pi =
+                            (F::from_f32(2.0) / F::from_f32(std::f32::consts::PI)).sqrt();
+                        let t = (sqrt_2_by_pi * (e + c * e * e * e)).tanh();
+                        half * (one + t)
+                            + half
+                                * e
+                                * (one - t * t)
+                                * sqrt_2_by_pi
+                                * (one + F::from_f32(3.0) * c * e * e)
+                    })
+            }
+            Activation::Softmax => {
+                let mut grad_z = grad_a;
+                for (mut g, a) in grad_z.genrows_mut().into_iter().zip(a.genrows()) {
+                    let dot = g.dot(&a);
+                    Zip::from(&mut g).and(&a).apply(|g, &a| *g = a * (*g - dot));
+                }
+                grad_z
+            }
+            Activation::ScaledTanh(bounds) => {
+                let mut grad_z = grad_a;
+                for (mut g, z) in grad_z.genrows_mut().into_iter().zip(z.genrows()) {
+                    for ((g, z), (lo, hi)) in g.iter_mut().zip(z.iter()).zip(bounds.iter()) {
+                        let t = z.tanh();
+                        *g *= (F::from_f32(*hi) - F::from_f32(*lo)) * half * (one - t * t);
+                    }
+                }
+                grad_z
+            }
+        }
+    }
+}
+
+fn sigmoid<F: Float>(e: F) -> F {
+    F::one() / (F::one() + (-e).exp())
+}
+
+/// Scheme for initializing the params of a layer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct FCN {
+pub enum Init {
+    /// Weights and biases from uniform(lo, hi).
+    Uniform(f32, f32),
+    /// Weights and biases from normal(mean, std).
+    Normal(f32, f32),
+    Zeros,
+    Constant(f32),
+    /// Glorot uniform weights, zero biases.
+    Xavier,
+    /// Kaiming normal weights, zero biases.
+    He,
+    /// One scheme per non-input layer.
+    PerLayer(Vec<Init>),
+}
+
+impl Init {
+    /// Checks that the distribution of the scheme is valid, ex. lo < hi of uniform.
+    fn validate(&self) -> Result<(), FcnError> {
+        let valid = match self {
+            Init::Uniform(lo, hi) => lo.is_finite() && hi.is_finite() && lo < hi,
+            Init::Normal(mean, std) => mean.is_finite() && std.is_finite() && *std >= 0.0,
+            Init::Constant(c) => c.is_finite(),
+            Init::PerLayer(inits) => {
+                for init in inits.iter() {
+                    if let Init::PerLayer(_) = init {
+                        return Err(FcnError::NestedPerLayerInit);
+                    }
+                    init.validate()?;
+                }
+                true
+            }
+            Init::Zeros | Init::Xavier | Init::He => true,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(FcnError::InvalidInit(self.clone()))
+        }
+    }
+
+    /// Samples the weight matrix (out dof, in dof) and bias of a layer.
+    fn sample<F: Float, R: Rng>(
+        &self,
+        prev_layer_dof: usize,
+        curr_layer_dof: usize,
+        rng: &mut R,
+    ) -> (Array2<F>, Array1<F>) {
+        let shape = (curr_layer_dof, prev_layer_dof);
+        // Same stream as rand_distr's Normal
+        let normal = |mean: F, std: F, rng: &mut R| mean + std * F::standard_normal(rng);
+        match self {
+            Init::Uniform(lo, hi) => {
+                let uniform = Uniform::new(F::from_f32(*lo), F::from_f32(*hi));
+                (
+                    Array::random_using(shape, &uniform, rng),
+                    Array::random_using(curr_layer_dof, &uniform, rng),
+                )
+            }
+            Init::Normal(mean, std) => {
+                let (mean, std) = (F::from_f32(*mean), F::from_f32(*std));
+                (
+                    Array::from_shape_fn(shape, |_| normal(mean, std, rng)),
+                    Array::from_shape_fn(curr_layer_dof, |_| normal(mean, std, rng)),
+                )
+            }
+            Init::Zeros => (Array::zeros(shape), Array::zeros(curr_layer_dof)),
+            Init::Constant(c) => (
+                Array::from_elem(shape, F::from_f32(*c)),
+                Array::from_elem(curr_layer_dof, F::from_f32(*c)),
+            ),
+            Init::Xavier => {
+                let limit =
+                    (F::from_f32(6.0) / F::from_usize(prev_layer_dof + curr_layer_dof)).sqrt();
+                (
+                    Array::random_using(shape, Uniform::new(-limit, limit), rng),
+                    Array::zeros(curr_layer_dof),
+                )
+            }
+            Init::He => {
+                let std = (F::from_f32(2.0) / F::from_usize(prev_layer_dof)).sqrt();
+                (
+                    Array::from_shape_fn(shape, |_| normal(F::zero(), std, rng)),
+                    Array::zeros(curr_layer_dof),
+                )
+            }
+            Init::PerLayer(_) => panic!("Trying to init a layer with nested per layer schemes."),
+        }
+    }
+}
+
+/// Recurrent connection of a layer onto itself across steps.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum Recurrence {
+    /// h = activation(W x + b + U h_prev).
+    Elman,
+    /// Gated recurrent unit, layer activation is used for the candidate state.
+    Gru,
+}
+
+impl Recurrence {
+    /// Number of params in addition to the weight matrix and bias of the layer.
+    fn num_extra_params(&self, prev_layer_dof: usize, curr_layer_dof: usize) -> usize {
+        match self {
+            Recurrence::Elman => curr_layer_dof * curr_layer_dof,
+            Recurrence::Gru => {
+                3 * curr_layer_dof * curr_layer_dof + 2 * (prev_layer_dof + 1) * curr_layer_dof
+            }
+        }
+    }
+}
+
+/// Connection from the output of an earlier layer into a later layer, in addition to the chain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum Skip {
+    /// Adds output of layer from to the pre-activations of layer to, both of same dof.
+    Residual { from: usize, to: usize },
+    /// Concatenates output of layer from to the input of layer to.
+    /// Its weight matrix (to dof, from dof) is packed after the params of recurrent connections.
+    Concat { from: usize, to: usize },
+}
+
+impl Skip {
+    pub fn from(&self) -> usize {
+        match self {
+            Skip::Residual { from, .. } | Skip::Concat { from, .. } => *from,
+        }
+    }
+
+    pub fn to(&self) -> usize {
+        match self {
+            Skip::Residual { to, .. } | Skip::Concat { to, .. } => *to,
+        }
+    }
+
+    fn num_params(&self, layers: &[(usize, Activation)]) -> usize {
+        match self {
+            Skip::Residual { .. } => 0,
+            Skip::Concat { from, to } => layers[*from].0 * layers[*to].0,
+        }
+    }
+}
+
+/// Hidden states of the recurrent layers, one row per input of the batch.
+/// The caller carries it b/w steps.
+#[derive(Debug, Clone)]
+pub struct HiddenState<F = f32> {
+    states: Vec<Array2<F>>,
+}
+
+/// Preallocated outputs of every layer for evaluating a single input without allocating.
+/// The caller reuses it b/w evaluations of the same model.
+#[derive(Debug, Clone)]
+pub struct FcnWorkspace<F = f32> {
+    outputs: Vec<Array1<F>>,
+}
+
+/// Preallocated outputs of every layer for evaluating a batch of inputs of fixed size without allocating.
+/// The caller reuses it b/w evaluations of the same model.
+#[derive(Debug, Clone)]
+pub struct FcnBatchWorkspace<F = f32> {
+    outputs: Vec<Array2<F>>,
+}
+
+/// Per-unit normalization x' = (x - center) / divisor, applied to inputs before the input layer activation.
+/// As output stage it is inverted, i.e. y = y' * divisor + center.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum Normalizer<F = f32> {
+    /// Fixed center and divisor.
+    Affine { shift: Array1<F>, scale: Array1<F> },
+    /// Mean and std estimated from observed values, std is offset by epsilon.
+    /// An unobserved normalizer has unit std.
+    Running {
+        count: usize,
+        mean: Array1<F>,
+        m2: Array1<F>,
+        epsilon: F,
+    },
+}
+
+impl<F: Float> Normalizer<F> {
+    /// Maps [lo, hi] of every unit onto [-1, 1].
+    /// Units with lo >= hi, ex. a constant state, get divisor 1 instead of dividing by 0, i.e. are only centered.
+    pub fn from_bounds(bounds: &[(F, F)]) -> Normalizer<F> {
+        let two = F::from_f32(2.0);
+        Normalizer::Affine {
+            shift: bounds.iter().map(|(lo, hi)| (*lo + *hi) / two).collect(),
+            scale: bounds
+                .iter()
+                .map(|(lo, hi)| if hi > lo { (*hi - *lo) / two } else { F::one() })
+                .collect(),
+        }
+    }
+
+    /// Unobserved running normalizer of len units.
+    pub fn running(len: usize, epsilon: F) -> Normalizer<F> {
+        Normalizer::Running {
+            count: 0,
+            mean: Array1::zeros(len),
+            m2: Array1::zeros(len),
+            epsilon: epsilon,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Normalizer::Affine { shift, .. } => shift.len(),
+            Normalizer::Running { mean, .. } => mean.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Current (center, divisor) of every unit.
+    pub fn center_and_divisor(&self) -> (Array1<F>, Array1<F>) {
+        match self {
+            Normalizer::Affine { shift, scale } => (shift.clone(), scale.clone()),
+            Normalizer::Running {
+                count,
+                mean,
+                m2,
+                epsilon,
+            } => {
+                let std = if *count == 0 {
+                    Array1::ones(mean.len())
+                } else {
+                    m2.mapv(|m2| (m2 / F::from_usize(*count)).sqrt())
+                };
+                (mean.clone(), std + *epsilon)
+            }
+        }
+    }
+
+    /// Normalizes along the last axis.
+    pub fn normalize<D: Dimension>(&self, x: Array<F, D>) -> Array<F, D> {
+        let (center, divisor) = self.center_and_divisor();
+        (x - &center) / &divisor
+    }
+
+    /// Inverse of normalize along the last axis.
+    pub fn denormalize<D: Dimension>(&self, x: Array<F, D>) -> Array<F, D> {
+        let (center, divisor) = self.center_and_divisor();
+        x * &divisor + &center
+    }
+
+    /// Same as center_and_divisor for a single unit, without allocating.
+    fn unit_center_and_divisor(&self, unit: usize) -> (F, F) {
+        match self {
+            Normalizer::Affine { shift, scale } => (shift[unit], scale[unit]),
+            Normalizer::Running {
+                count,
+                mean,
+                m2,
+                epsilon,
+            } => {
+                let std = if *count == 0 {
+                    F::one()
+                } else {
+                    (m2[unit] / F::from_usize(*count)).sqrt()
+                };
+                (mean[unit], std + *epsilon)
+            }
+        }
+    }
+
+    /// Same as normalize for a single value, in place.
+    pub(crate) fn normalize_inplace(&self, mut x: ArrayViewMut1<'_, F>) {
+        for (unit, x) in x.iter_mut().enumerate() {
+            let (center, divisor) = self.unit_center_and_divisor(unit);
+            *x = (*x - center) / divisor;
+        }
+    }
+
+    /// Same as denormalize for a single value, in place.
+    pub(crate) fn denormalize_inplace(&self, mut x: ArrayViewMut1<'_, F>) {
+        for (unit, x) in x.iter_mut().enumerate() {
+            let (center, divisor) = self.unit_center_and_divisor(unit);
+            *x = *x * divisor + center;
+        }
+    }
+
+    /// Updates running estimates with a batch of values, one per row. Affine normalizers are fixed.
+    pub fn observe(&mut self, x: &Array2<F>) {
+        if let Normalizer::Running {
+            count, mean, m2, ..
+        } = self
+        {
+            if x.nrows() == 0 {
+                return;
+            }
+            // Merges batch moments into running moments (Chan et al.)
+            let batch_count = F::from_usize(x.nrows());
+            let batch_mean = x.sum_axis(Axis(0)) / batch_count;
+            let batch_m2 = (x - &batch_mean).mapv(|d| d * d).sum_axis(Axis(0));
+            let total_count = F::from_usize(*count) + batch_count;
+            let delta = &batch_mean - &*mean;
+            let ratio = F::from_usize(*count) * batch_count / total_count;
+            *m2 += &(batch_m2 + delta.mapv(|d| d * d * ratio));
+            *mean += &(delta * (batch_count / total_count));
+            *count += x.nrows();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FcnError {
+    TooFewLayers(usize),
+    ZeroWidthLayer(usize),
+    BoundsLenMismatch {
+        layer: usize,
+        expected: usize,
+        found: usize,
+    },
+    InitLenMismatch {
+        expected: usize,
+        found: usize,
+    },
+    NestedPerLayerInit,
+    /// Distribution of the scheme is invalid, ex. uniform with lo >= hi or normal with a negative std.
+    InvalidInit(Init),
+    InvalidRecurrentLayer(usize),
+    InputLenMismatch {
+        expected: usize,
+        found: usize,
+    },
+    ParamLenMismatch {
+        expected: usize,
+        found: usize,
+    },
+    NormalizerLenMismatch {
+        expected: usize,
+        found: usize,
+    },
+    InvalidSkip {
+        from: usize,
+        to: usize,
+    },
+    /// Shapes (batch size, dof) of the states of recurrent layers.
+    HiddenStateShape {
+        expected: Vec<(usize, usize)>,
+        found: Vec<(usize, usize)>,
+    },
+}
+
+impl fmt::Display for FcnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FcnError::TooFewLayers(found) => write!(
+                f,
+                "Trying to create a model with less than 2 layers, found {}.",
+                found
+            ),
+            FcnError::ZeroWidthLayer(layer) => {
+                write!(f, "Trying to create a model with layer {} of 0 dof.", layer)
+            }
+            FcnError::BoundsLenMismatch {
+                layer,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Trying to create scaled tanh layer {} with {} bounds for {} units.",
+                layer, found, expected
+            ),
+            FcnError::InitLenMismatch { expected, found } => write!(
+                f,
+                "Trying to init a model with {} schemes for {} non-input layers.",
+                found, expected
+            ),
+            FcnError::NestedPerLayerInit => {
+                write!(f, "Trying to init a layer with nested per layer schemes.")
+            }
+            FcnError::InvalidInit(init) => {
+                write!(f, "Trying to init a layer with invalid scheme {:?}.", init)
+            }
+            FcnError::InvalidRecurrentLayer(layer) => write!(
+                f,
+                "Trying to make layer {} recurrent, which is the input layer, non-existent or out of order.",
+                layer
+            ),
+            FcnError::InputLenMismatch { expected, found } => write!(
+                f,
+                "Invalid input len for fcn, expected {}, found {}.",
+                expected, found
+            ),
+            FcnError::ParamLenMismatch { expected, found } => write!(
+                f,
+                "Invalid params len for fcn, expected {}, found {}.",
+                expected, found
+            ),
+            FcnError::NormalizerLenMismatch { expected, found } => write!(
+                f,
+                "Invalid normalizer len for fcn, expected {}, found {}.",
+                expected, found
+            ),
+            FcnError::InvalidSkip { from, to } => write!(
+                f,
+                "Trying to skip from layer {} to layer {}, which is backwards, non-existent or of mismatching dof.",
+                from, to
+            ),
+            FcnError::HiddenStateShape { expected, found } => write!(
+                f,
+                "Invalid hidden state shapes for fcn, expected {:?}, found {:?}.",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FcnError {}
+
+/// Deserialized form of FCN before it is validated.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, bound(deserialize = "F: Float"))]
+struct UncheckedFCN<F> {
     layers: Vec<(usize, Activation)>,
-    params: Array1<f32>,
+    #[serde(default)]
+    recurrent: Vec<(usize, Recurrence)>,
+    #[serde(default)]
+    skips: Vec<Skip>,
+    params: Array1<F>,
+    #[serde(default)]
+    input_normalizer: Option<Normalizer<F>>,
+    #[serde(default)]
+    output_normalizer: Option<Normalizer<F>>,
+}
+
+impl<F: Float> TryFrom<UncheckedFCN<F>> for FCN<F> {
+    type Error = FcnError;
+
+    fn try_from(unchecked: UncheckedFCN<F>) -> Result<FCN<F>, FcnError> {
+        Self::validate(&unchecked.layers, &unchecked.recurrent, &unchecked.skips)?;
+        let num_params =
+            Self::num_params(&unchecked.layers, &unchecked.recurrent, &unchecked.skips);
+        if unchecked.params.len() != num_params {
+            return Err(FcnError::ParamLenMismatch {
+                expected: num_params,
+                found: unchecked.params.len(),
+            });
+        }
+        let mut fcn = FCN {
+            layers: unchecked.layers,
+            recurrent: unchecked.recurrent,
+            skips: unchecked.skips,
+            params: unchecked.params,
+            input_normalizer: None,
+            output_normalizer: None,
+        };
+        fcn.set_input_normalizer(unchecked.input_normalizer)?;
+        fcn.set_output_normalizer(unchecked.output_normalizer)?;
+        Ok(fcn)
+    }
 }
 
-impl fmt::Display for FCN {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(
+    deny_unknown_fields,
+    try_from = "UncheckedFCN<F>",
+    bound(deserialize = "F: Float")
+)]
+pub struct FCN<F = f32> {
+    layers: Vec<(usize, Activation)>,
+    /// (layer index, recurrence) pairs in increasing order of layer index.
+    /// Their params are packed after those of all the layers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    recurrent: Vec<(usize, Recurrence)>,
+    /// Connections in addition to the chain of layers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    skips: Vec<Skip>,
+    params: Array1<F>,
+    /// Applied to raw inputs before the input layer activation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    input_normalizer: Option<Normalizer<F>>,
+    /// Inverted and applied to outputs of the last layer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output_normalizer: Option<Normalizer<F>>,
+}
+
+impl<F> fmt::Display for FCN<F> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "fcn, layers={:?}, num params={}",
+            "fcn, layers={:?}, recurrent={:?}, skips={:?}, num params={}",
             self.layers,
+            self.recurrent,
+            self.skips,
             self.params.len()
         )
     }
 }
 
-impl FCN {
-    pub fn new(layers: Vec<(usize, Activation)>) -> FCN {
-        assert!(
-            layers.len() >= 2,
-            "Trying to create a model with less than 2 layers."
-        );
-        let num_params = {
-            let mut num_params = 0;
-            for i in 1..layers.len() {
-                num_params += (layers[i - 1].0 + 1) * layers[i].0;
+impl<F: Float> FCN<F> {
+    /// Initializes params from uniform(0, 1) using thread rng.
+    pub fn new(layers: Vec<(usize, Activation)>) -> FCN<F> {
+        Self::new_with(layers, &Init::Uniform(0.0, 1.0), &mut rand::thread_rng())
+    }
+
+    /// Initializes params using given scheme and rng. A seeded rng gives the same network every time.
+    pub fn new_with<R: Rng>(layers: Vec<(usize, Activation)>, init: &Init, rng: &mut R) -> FCN<F> {
+        Self::new_recurrent_with(layers, vec![], init, rng)
+    }
+
+    /// Like new_with, but layers at given indices also get recurrent connections.
+    pub fn new_recurrent_with<R: Rng>(
+        layers: Vec<(usize, Activation)>,
+        recurrent: Vec<(usize, Recurrence)>,
+        init: &Init,
+        rng: &mut R,
+    ) -> FCN<F> {
+        Self::try_new_recurrent_with(layers, recurrent, init, rng)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Like new_recurrent_with, but with skip connections b/w layers too.
+    pub fn new_graph_with<R: Rng>(
+        layers: Vec<(usize, Activation)>,
+        recurrent: Vec<(usize, Recurrence)>,
+        skips: Vec<Skip>,
+        init: &Init,
+        rng: &mut R,
+    ) -> FCN<F> {
+        Self::try_new_graph_with(layers, recurrent, skips, init, rng)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_new(layers: Vec<(usize, Activation)>) -> Result<FCN<F>, FcnError> {
+        Self::try_new_with(layers, &Init::Uniform(0.0, 1.0), &mut rand::thread_rng())
+    }
+
+    pub fn try_new_with<R: Rng>(
+        layers: Vec<(usize, Activation)>,
+        init: &Init,
+        rng: &mut R,
+    ) -> Result<FCN<F>, FcnError> {
+        Self::try_new_recurrent_with(layers, vec![], init, rng)
+    }
+
+    pub fn try_new_recurrent_with<R: Rng>(
+        layers: Vec<(usize, Activation)>,
+        recurrent: Vec<(usize, Recurrence)>,
+        init: &Init,
+        rng: &mut R,
+    ) -> Result<FCN<F>, FcnError> {
+        Self::try_new_graph_with(layers, recurrent, vec![], init, rng)
+    }
+
+    pub fn try_new_graph_with<R: Rng>(
+        layers: Vec<(usize, Activation)>,
+        recurrent: Vec<(usize, Recurrence)>,
+        skips: Vec<Skip>,
+        init: &Init,
+        rng: &mut R,
+    ) -> Result<FCN<F>, FcnError> {
+        Self::validate(&layers, &recurrent, &skips)?;
+        if let Init::PerLayer(inits) = init {
+            if inits.len() != layers.len() - 1 {
+                return Err(FcnError::InitLenMismatch {
+                    expected: layers.len() - 1,
+                    found: inits.len(),
+                });
             }
-            num_params
+        }
+        init.validate()?;
+        let layer_init = |i: usize| match init {
+            Init::PerLayer(inits) => &inits[i - 1],
+            init => init,
         };
-        FCN {
+        let mut params = Vec::new();
+        for i in 1..layers.len() {
+            let (matrix, bias) = layer_init(i).sample(layers[i - 1].0, layers[i].0, rng);
+            params.extend(matrix.iter());
+            params.extend(bias.iter());
+        }
+        for (i, recurrence) in recurrent.iter() {
+            let (prev_layer_dof, curr_layer_dof) = (layers[i - 1].0, layers[*i].0);
+            let init = layer_init(*i);
+            match recurrence {
+                Recurrence::Elman => {
+                    let (u, _) = init.sample(curr_layer_dof, curr_layer_dof, rng);
+                    params.extend(u.iter());
+                }
+                Recurrence::Gru => {
+                    let (u_candidate, _) = init.sample(curr_layer_dof, curr_layer_dof, rng);
+                    params.extend(u_candidate.iter());
+                    for _gate in 0..2 {
+                        let (w, b) = init.sample(prev_layer_dof, curr_layer_dof, rng);
+                        let (u, _) = init.sample(curr_layer_dof, curr_layer_dof, rng);
+                        params.extend(w.iter());
+                        params.extend(u.iter());
+                        params.extend(b.iter());
+                    }
+                }
+            }
+        }
+        for skip in skips.iter() {
+            if let Skip::Concat { from, to } = skip {
+                let (matrix, _) = layer_init(*to).sample(layers[*from].0, layers[*to].0, rng);
+                params.extend(matrix.iter());
+            }
+        }
+        Ok(FCN {
             layers: layers,
-            params: //Array::from_elem((num_params,), 0.01),
-            Array::random(num_params, Uniform::new(0.0, 1.0)),
+            recurrent: recurrent,
+            skips: skips,
+            params: Array1::from(params),
+            input_normalizer: None,
+            output_normalizer: None,
+        })
+    }
+
+    /// Checks that layers, recurrent and skip connections describe a valid model.
+    fn validate(
+        layers: &[(usize, Activation)],
+        recurrent: &[(usize, Recurrence)],
+        skips: &[Skip],
+    ) -> Result<(), FcnError> {
+        if layers.len() < 2 {
+            return Err(FcnError::TooFewLayers(layers.len()));
+        }
+        for (i, (dof, activation)) in layers.iter().enumerate() {
+            if *dof == 0 {
+                return Err(FcnError::ZeroWidthLayer(i));
+            }
+            if let Activation::ScaledTanh(bounds) = activation {
+                if bounds.len() != *dof {
+                    return Err(FcnError::BoundsLenMismatch {
+                        layer: i,
+                        expected: *dof,
+                        found: bounds.len(),
+                    });
+                }
+            }
         }
+        for (j, (i, _)) in recurrent.iter().enumerate() {
+            if *i < 1 || *i >= layers.len() || (j > 0 && recurrent[j - 1].0 >= *i) {
+                return Err(FcnError::InvalidRecurrentLayer(*i));
+            }
+        }
+        for skip in skips.iter() {
+            let (from, to) = (skip.from(), skip.to());
+            let is_residual = matches!(skip, Skip::Residual { .. });
+            if from >= to || to >= layers.len() || (is_residual && layers[from].0 != layers[to].0) {
+                return Err(FcnError::InvalidSkip { from: from, to: to });
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of params needed by layers, recurrent and skip connections.
+    fn num_params(
+        layers: &[(usize, Activation)],
+        recurrent: &[(usize, Recurrence)],
+        skips: &[Skip],
+    ) -> usize {
+        let num_layer_params = (1..layers.len())
+            .map(|i| (layers[i - 1].0 + 1) * layers[i].0)
+            .sum::<usize>();
+        let num_recurrent_params = recurrent
+            .iter()
+            .map(|(i, recurrence)| recurrence.num_extra_params(layers[i - 1].0, layers[*i].0))
+            .sum::<usize>();
+        let num_skip_params = skips
+            .iter()
+            .map(|skip| skip.num_params(layers))
+            .sum::<usize>();
+        num_layer_params + num_recurrent_params + num_skip_params
     }
 
-    pub fn params(&self) -> &Array1<f32> {
+    /// Checks input len and params len for an evaluation.
+    fn check_eval(&self, input_len: usize, params_len: usize) -> Result<(), FcnError> {
+        if input_len != self.layers[0].0 {
+            return Err(FcnError::InputLenMismatch {
+                expected: self.layers[0].0,
+                found: input_len,
+            });
+        }
+        if params_len != self.params.len() {
+            return Err(FcnError::ParamLenMismatch {
+                expected: self.params.len(),
+                found: params_len,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn params(&self) -> &Array1<F> {
         &self.params
     }
 
-    pub fn set_params(&mut self, new_params: Array1<f32>) {
+    pub fn set_params(&mut self, new_params: Array1<F>) {
         self.params = new_params;
     }
 
+    pub fn layers(&self) -> &[(usize, Activation)] {
+        &self.layers
+    }
+
+    pub fn recurrent(&self) -> &[(usize, Recurrence)] {
+        &self.recurrent
+    }
+
+    pub fn skips(&self) -> &[Skip] {
+        &self.skips
+    }
+
+    pub fn input_normalizer(&self) -> Option<&Normalizer<F>> {
+        self.input_normalizer.as_ref()
+    }
+
+    pub fn output_normalizer(&self) -> Option<&Normalizer<F>> {
+        self.output_normalizer.as_ref()
+    }
+
+    pub fn set_input_normalizer(
+        &mut self,
+        normalizer: Option<Normalizer<F>>,
+    ) -> Result<(), FcnError> {
+        Self::check_normalizer(normalizer.as_ref(), self.layers[0].0)?;
+        self.input_normalizer = normalizer;
+        Ok(())
+    }
+
+    pub fn set_output_normalizer(
+        &mut self,
+        normalizer: Option<Normalizer<F>>,
+    ) -> Result<(), FcnError> {
+        Self::check_normalizer(normalizer.as_ref(), self.layers[self.layers.len() - 1].0)?;
+        self.output_normalizer = normalizer;
+        Ok(())
+    }
+
+    fn check_normalizer(normalizer: Option<&Normalizer<F>>, dof: usize) -> Result<(), FcnError> {
+        match normalizer {
+            Some(normalizer) if normalizer.len() != dof => Err(FcnError::NormalizerLenMismatch {
+                expected: dof,
+                found: normalizer.len(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Updates running input normalizer with a batch of raw inputs, one per row.
+    pub fn observe_inputs(&mut self, inputs: &Array2<F>) {
+        if let Some(normalizer) = &mut self.input_normalizer {
+            normalizer.observe(inputs);
+        }
+    }
+
+    /// Updates running output normalizer with a batch of raw targets, one per row.
+    pub fn observe_targets(&mut self, targets: &Array2<F>) {
+        if let Some(normalizer) = &mut self.output_normalizer {
+            normalizer.observe(targets);
+        }
+    }
+
+    /// Raw inputs, one per row along the last axis, as seen by the input layer activation.
+    pub fn normalize_inputs<D: Dimension>(&self, inputs: Array<F, D>) -> Array<F, D> {
+        match &self.input_normalizer {
+            Some(normalizer) => normalizer.normalize(inputs),
+            None => inputs,
+        }
+    }
+
+    /// Last layer outputs, one per row along the last axis, mapped back to the target domain.
+    pub fn denormalize_outputs<D: Dimension>(&self, outputs: Array<F, D>) -> Array<F, D> {
+        match &self.output_normalizer {
+            Some(normalizer) => normalizer.denormalize(outputs),
+            None => outputs,
+        }
+    }
+
+    /// Range of params occupied by weight matrix and bias of layer i (i >= 1), in that order.
+    /// Weight matrix is stored row major with shape (curr layer dof, prev layer dof).
+    pub fn layer_param_range(&self, i: usize) -> Range<usize> {
+        assert!(
+            i >= 1 && i < self.layers.len(),
+            "Invalid layer index for fcn"
+        );
+        let start = (1..i)
+            .map(|j| (self.layers[j - 1].0 + 1) * self.layers[j].0)
+            .sum::<usize>();
+        start..(start + (self.layers[i - 1].0 + 1) * self.layers[i].0)
+    }
+
+    /// Range of params occupied by the recurrent connections of layer i, if it is recurrent.
+    pub fn recurrent_param_range(&self, i: usize) -> Option<Range<usize>> {
+        let mut start = self.layer_param_range(self.layers.len() - 1).end;
+        for (j, recurrence) in self.recurrent.iter() {
+            let len = recurrence.num_extra_params(self.layers[j - 1].0, self.layers[*j].0);
+            if *j == i {
+                return Some(start..(start + len));
+            }
+            start += len;
+        }
+        None
+    }
+
+    /// Range of params occupied by the weight matrix of skip j, if it is a concat skip.
+    pub fn skip_param_range(&self, j: usize) -> Option<Range<usize>> {
+        let mut start = self.layer_param_range(self.layers.len() - 1).end
+            + self
+                .recurrent
+                .iter()
+                .map(|(i, recurrence)| {
+                    recurrence.num_extra_params(self.layers[i - 1].0, self.layers[*i].0)
+                })
+                .sum::<usize>();
+        for skip in self.skips[..j].iter() {
+            start += skip.num_params(&self.layers);
+        }
+        match self.skips[j] {
+            Skip::Residual { .. } => None,
+            Skip::Concat { .. } => Some(start..(start + self.skips[j].num_params(&self.layers))),
+        }
+    }
+
+    /// Weight matrix (to dof, from dof) of skip j, if it is a concat skip.
+    pub fn skip_weights(&self, j: usize) -> Option<ArrayView2<'_, F>> {
+        self.skip_param_range(j)
+            .map(|_| self.skip_params(&self.params, j))
+    }
+
+    /// Weight matrix of concat skip j from params.
+    fn skip_params<'a>(&self, params: &'a Array1<F>, j: usize) -> ArrayView2<'a, F> {
+        let range = self.skip_param_range(j).unwrap();
+        let shape = (
+            self.layers[self.skips[j].to()].0,
+            self.layers[self.skips[j].from()].0,
+        );
+        params.slice(s![range]).into_shape(shape).unwrap()
+    }
+
+    pub fn layer_weights(&self, i: usize) -> ArrayView2<'_, F> {
+        self.layer_params(&self.params, i).0
+    }
+
+    pub fn layer_bias(&self, i: usize) -> ArrayView1<'_, F> {
+        self.layer_params(&self.params, i).1
+    }
+
+    /// Mutable weight matrix and bias of layer i (i >= 1).
+    pub fn layer_mut(&mut self, i: usize) -> (ArrayViewMut2<'_, F>, ArrayViewMut1<'_, F>) {
+        let range = self.layer_param_range(i);
+        let (prev_layer_dof, curr_layer_dof) = (self.layers[i - 1].0, self.layers[i].0);
+        let (matrix, bias) = self
+            .params
+            .slice_mut(s![range])
+            .split_at(Axis(0), prev_layer_dof * curr_layer_dof);
+        (
+            matrix.into_shape((curr_layer_dof, prev_layer_dof)).unwrap(),
+            bias,
+        )
+    }
+
+    /// Table of shape, number of params and flops of every layer.
+    /// A multiply-add counts as 2 flops, activations are not counted.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{:>5} {:>12} {:>24} {:>10} {:>10}\n",
+            "layer", "shape", "activation", "params", "flops"
+        );
+        summary += &format!(
+            "{:>5} {:>12} {:>24} {:>10} {:>10}\n",
+            0,
+            format!("{}", self.layers[0].0),
+            format!("{:?}", self.layers[0].1),
+            0,
+            0
+        );
+        let (mut total_params, mut total_flops) = (0, 0);
+        for i in 1..self.layers.len() {
+            let (prev_layer_dof, curr_layer_dof) = (self.layers[i - 1].0, self.layers[i].0);
+            let mut num_params = self.layer_param_range(i).len();
+            let mut flops = 2 * prev_layer_dof * curr_layer_dof + curr_layer_dof;
+            let mut activation = format!("{:?}", self.layers[i].1);
+            if let Some(range) = self.recurrent_param_range(i) {
+                // Every recurrent param takes part in one multiply-add or bias add per step
+                num_params += range.len();
+                flops += 2 * range.len();
+                activation += &format!(
+                    "+{:?}",
+                    self.recurrent.iter().find(|(j, _)| *j == i).unwrap().1
+                );
+            }
+            for (j, skip) in self
+                .skips
+                .iter()
+                .enumerate()
+                .filter(|(_, skip)| skip.to() == i)
+            {
+                match self.skip_param_range(j) {
+                    Some(range) => {
+                        num_params += range.len();
+                        flops += 2 * range.len();
+                        activation += &format!("+Concat({})", skip.from());
+                    }
+                    None => {
+                        flops += curr_layer_dof;
+                        activation += &format!("+Residual({})", skip.from());
+                    }
+                }
+            }
+            summary += &format!(
+                "{:>5} {:>12} {:>24} {:>10} {:>10}\n",
+                i,
+                format!("{}x{}", curr_layer_dof, prev_layer_dof),
+                activation,
+                num_params,
+                flops
+            );
+            total_params += num_params;
+            total_flops += flops;
+        }
+        summary += &format!(
+            "{:>5} {:>12} {:>24} {:>10} {:>10}\n",
+            "total", "", "", total_params, total_flops
+        );
+        summary
+    }
+
+    /// Weight matrix and bias of layer i (i >= 1) from params.
+    fn layer_params<'a>(
+        &self,
+        params: &'a Array1<F>,
+        i: usize,
+    ) -> (ArrayView2<'a, F>, ArrayView1<'a, F>) {
+        let range = self.layer_param_range(i);
+        let (prev_layer_dof, curr_layer_dof) = (self.layers[i - 1].0, self.layers[i].0);
+        let (matrix, bias) = params
+            .slice(s![range])
+            .split_at(Axis(0), prev_layer_dof * curr_layer_dof);
+        (
+            matrix.into_shape((curr_layer_dof, prev_layer_dof)).unwrap(),
+            bias,
+        )
+    }
+
     /// Clones input but not params.
-    pub fn at_with(&self, input: &Array1<f32>, params: &Array1<f32>) -> Array1<f32> {
-        assert_eq!(input.len(), self.layers[0].0, "Invalid input len for fcn");
+    /// A model with recurrent layers takes a single step from zero hidden state, use step_with to carry it.
+    pub fn at_with(&self, input: &Array1<F>, params: &Array1<F>) -> Array1<F> {
+        self.check_eval(input.len(), params.len())
+            .unwrap_or_else(|e| panic!("{}", e));
+        if !self.recurrent.is_empty() {
+            return self.step_with(input, params, &mut self.hidden_state(1));
+        }
+        let mut outputs = Vec::with_capacity(self.layers.len());
+        outputs.push(
+            self.layers[0]
+                .1
+                .activate(self.normalize_inputs(input.to_owned())),
+        );
+        for i in 1..self.layers.len() {
+            let (matrix, bias) = self.layer_params(params, i);
+            let mut z = matrix.dot(&outputs[i - 1]) + bias;
+            for (j, skip) in self
+                .skips
+                .iter()
+                .enumerate()
+                .filter(|(_, skip)| skip.to() == i)
+            {
+                z = match skip {
+                    Skip::Residual { from, .. } => z + &outputs[*from],
+                    Skip::Concat { from, .. } => {
+                        z + self.skip_params(params, j).dot(&outputs[*from])
+                    }
+                };
+            }
+            outputs.push(self.layers[i].1.activate(z));
+        }
+        self.denormalize_outputs(outputs.pop().unwrap())
+    }
+
+    pub fn try_at_with(
+        &self,
+        input: &Array1<F>,
+        params: &Array1<F>,
+    ) -> Result<Array1<F>, FcnError> {
+        self.check_eval(input.len(), params.len())?;
+        Ok(self.at_with(input, params))
+    }
+
+    /// Evaluates a batch of inputs, one input per row, as matrix-matrix products.
+    /// Clones inputs but not params. Recurrent models take a single step from zero hidden state, as in at_with.
+    pub fn at_batch_with(&self, inputs: &Array2<F>, params: &Array1<F>) -> Array2<F> {
+        self.check_eval(inputs.ncols(), params.len())
+            .unwrap_or_else(|e| panic!("{}", e));
+        if !self.recurrent.is_empty() {
+            return self.step_batch_with(inputs, params, &mut self.hidden_state(inputs.nrows()));
+        }
+        let mut outputs = Vec::with_capacity(self.layers.len());
+        outputs.push(
+            self.layers[0]
+                .1
+                .activate(self.normalize_inputs(inputs.to_owned())),
+        );
+        for i in 1..self.layers.len() {
+            let (matrix, bias) = self.layer_params(params, i);
+            let z = outputs[i - 1].dot(&matrix.t()) + bias;
+            let z = self.add_skips(params, i, |k| &outputs[k], z);
+            outputs.push(self.layers[i].1.activate(z));
+        }
+        self.denormalize_outputs(outputs.pop().unwrap())
+    }
+
+    /// Adds contributions of skips into layer i to its pre-activations z, given outputs of earlier layers.
+    fn add_skips<'a, O: Fn(usize) -> &'a Array2<F>>(
+        &self,
+        params: &Array1<F>,
+        i: usize,
+        output: O,
+        mut z: Array2<F>,
+    ) -> Array2<F> {
+        for (j, skip) in self
+            .skips
+            .iter()
+            .enumerate()
+            .filter(|(_, skip)| skip.to() == i)
+        {
+            z = match skip {
+                Skip::Residual { from, .. } => z + output(*from),
+                Skip::Concat { from, .. } => {
+                    z + output(*from).dot(&self.skip_params(params, j).t())
+                }
+            };
+        }
+        z
+    }
+
+    /// Workspace for at_into.
+    pub fn workspace(&self) -> FcnWorkspace<F> {
+        FcnWorkspace {
+            outputs: self
+                .layers
+                .iter()
+                .map(|(dof, _)| Array1::zeros(*dof))
+                .collect(),
+        }
+    }
+
+    /// Workspace for at_batch_into with batches of given size.
+    pub fn batch_workspace(&self, batch_size: usize) -> FcnBatchWorkspace<F> {
+        FcnBatchWorkspace {
+            outputs: self
+                .layers
+                .iter()
+                .map(|(dof, _)| Array2::zeros((batch_size, *dof)))
+                .collect(),
+        }
+    }
+
+    /// Same as at_with, but writes into output using the buffers of workspace, so that it doesn't allocate.
+    pub fn at_into(
+        &self,
+        input: &Array1<F>,
+        params: &Array1<F>,
+        workspace: &mut FcnWorkspace<F>,
+        output: &mut Array1<F>,
+    ) {
+        self.check_eval(input.len(), params.len())
+            .unwrap_or_else(|e| panic!("{}", e));
+        self.eval_into(input.view(), params, workspace, output.view_mut());
+    }
+
+    /// Same as at_batch_with, but writes into outputs using the buffers of workspace instead of allocating per layer,
+    /// only matrix-matrix products allocate their packing buffers. Biases and concat skips are accumulated by the
+    /// products instead of added after them, so outputs equal those of at_batch_with up to rounding.
+    pub fn at_batch_into(
+        &self,
+        inputs: &Array2<F>,
+        params: &Array1<F>,
+        workspace: &mut FcnBatchWorkspace<F>,
+        outputs: &mut Array2<F>,
+    ) {
+        self.check_eval(inputs.ncols(), params.len())
+            .unwrap_or_else(|e| panic!("{}", e));
+        assert!(
+            self.recurrent.is_empty(),
+            "Trying to evaluate recurrent layers without hidden state."
+        );
+        assert!(
+            workspace.outputs.len() == self.layers.len()
+                && workspace
+                    .outputs
+                    .iter()
+                    .zip(self.layers.iter())
+                    .all(|(buffer, (dof, _))| buffer.dim() == (inputs.nrows(), *dof)),
+            "Invalid workspace for fcn"
+        );
         assert_eq!(
-            params.len(),
-            self.params.len(),
-            "Invalid params len for fcn"
-        );
-        let mut params_offset = 0;
-        let mut output = input.to_owned();
-        output = match &self.layers[0].1 {
-            Activation::Linear => output,
-            Activation::LeakyReLu(leak) => output.mapv(|e| if e > 0.0 { e } else { e * leak }),
-            Activation::Sigmoid => output.mapv(|e| 1.0 / (1.0 + (-e).exp())),
-        };
+            outputs.dim(),
+            (inputs.nrows(), self.layers[self.layers.len() - 1].0),
+            "Invalid outputs shape for fcn"
+        );
+        let (one, buffers) = (F::one(), &mut workspace.outputs);
+        buffers[0].assign(inputs);
+        if let Some(normalizer) = &self.input_normalizer {
+            for input in buffers[0].genrows_mut() {
+                normalizer.normalize_inplace(input);
+            }
+        }
+        self.layers[0].1.activate_inplace(buffers[0].view_mut());
         for i in 1..self.layers.len() {
-            let prev_layer_dof = self.layers[i - 1].0;
-            let curr_layer_dof = self.layers[i].0;
-            let curr_layer_activation = &self.layers[i].1;
-            // let now = Instant::now();
-            let matrix = params
-                .slice(s![
-                    params_offset..(params_offset + prev_layer_dof * curr_layer_dof)
-                ])
-                .into_shape((curr_layer_dof, prev_layer_dof))
-                .unwrap();
-            params_offset += prev_layer_dof * curr_layer_dof;
-            let bias = params
-                .slice(s![params_offset..(params_offset + curr_layer_dof)])
-                .into_shape(curr_layer_dof)
-                .unwrap();
-            // println!("1:{}", now.elapsed().as_nanos());
-            // let now = Instant::now();
-            output = matrix.dot(&output) + bias;
-            output = match curr_layer_activation {
-                Activation::Linear => output,
-                Activation::LeakyReLu(leak) => output.mapv(|e| if e > 0.0 { e } else { e * leak }),
-                Activation::Sigmoid => output.mapv(|e| 1.0 / (1.0 + (-e).exp())),
+            let (earlier, later) = buffers.split_at_mut(i);
+            let z = &mut later[0];
+            let (matrix, bias) = self.layer_params(params, i);
+            z.assign(&bias);
+            general_mat_mul(one, &earlier[i - 1], &matrix.t(), one, z);
+            for (j, skip) in self
+                .skips
+                .iter()
+                .enumerate()
+                .filter(|(_, skip)| skip.to() == i)
+            {
+                match skip {
+                    Skip::Residual { from, .. } => *z += &earlier[*from],
+                    Skip::Concat { from, .. } => general_mat_mul(
+                        one,
+                        &earlier[*from],
+                        &self.skip_params(params, j).t(),
+                        one,
+                        z,
+                    ),
+                }
+            }
+            self.layers[i].1.activate_inplace(z.view_mut());
+        }
+        outputs.assign(&buffers[self.layers.len() - 1]);
+        if let Some(normalizer) = &self.output_normalizer {
+            for output in outputs.genrows_mut() {
+                normalizer.denormalize_inplace(output);
+            }
+        }
+    }
+
+    /// Matrix-vector evaluation of a checked input, in the same order of operations as at_with.
+    fn eval_into(
+        &self,
+        input: ArrayView1<'_, F>,
+        params: &Array1<F>,
+        workspace: &mut FcnWorkspace<F>,
+        mut output: ArrayViewMut1<'_, F>,
+    ) {
+        assert!(
+            self.recurrent.is_empty(),
+            "Trying to evaluate recurrent layers without hidden state."
+        );
+        assert!(
+            workspace.outputs.len() == self.layers.len()
+                && workspace
+                    .outputs
+                    .iter()
+                    .zip(self.layers.iter())
+                    .all(|(buffer, (dof, _))| buffer.len() == *dof),
+            "Invalid workspace for fcn"
+        );
+        assert_eq!(
+            output.len(),
+            self.layers[self.layers.len() - 1].0,
+            "Invalid output len for fcn"
+        );
+        let (one, outputs) = (F::one(), &mut workspace.outputs);
+        outputs[0].assign(&input);
+        if let Some(normalizer) = &self.input_normalizer {
+            normalizer.normalize_inplace(outputs[0].view_mut());
+        }
+        self.layers[0].1.activate_inplace(outputs[0].view_mut());
+        for i in 1..self.layers.len() {
+            let (earlier, later) = outputs.split_at_mut(i);
+            let z = &mut later[0];
+            let (matrix, bias) = self.layer_params(params, i);
+            z.assign(&bias);
+            general_mat_vec_mul(one, &matrix, &earlier[i - 1], one, z);
+            for (j, skip) in self
+                .skips
+                .iter()
+                .enumerate()
+                .filter(|(_, skip)| skip.to() == i)
+            {
+                match skip {
+                    Skip::Residual { from, .. } => *z += &earlier[*from],
+                    Skip::Concat { from, .. } => general_mat_vec_mul(
+                        one,
+                        &self.skip_params(params, j),
+                        &earlier[*from],
+                        one,
+                        z,
+                    ),
+                }
+            }
+            self.layers[i].1.activate_inplace(z.view_mut());
+        }
+        output.assign(&outputs[self.layers.len() - 1]);
+        if let Some(normalizer) = &self.output_normalizer {
+            normalizer.denormalize_inplace(output);
+        }
+    }
+
+    /// Zero hidden state for a batch of given size.
+    pub fn hidden_state(&self, batch_size: usize) -> HiddenState<F> {
+        HiddenState {
+            states: self
+                .recurrent
+                .iter()
+                .map(|(i, _)| Array2::zeros((batch_size, self.layers[*i].0)))
+                .collect(),
+        }
+    }
+
+    /// Evaluates one step of a batch of sequences, one input per row, and advances hidden state.
+    /// Equivalent to at_batch_with for a model without recurrent layers.
+    pub fn step_batch_with(
+        &self,
+        inputs: &Array2<F>,
+        params: &Array1<F>,
+        state: &mut HiddenState<F>,
+    ) -> Array2<F> {
+        self.check_step(inputs, params, state)
+            .unwrap_or_else(|e| panic!("{}", e));
+        let mut recurrent = self
+            .recurrent
+            .iter()
+            .zip(state.states.iter_mut())
+            .peekable();
+        let mut outputs = Vec::with_capacity(self.layers.len());
+        outputs.push(
+            self.layers[0]
+                .1
+                .activate(self.normalize_inputs(inputs.to_owned())),
+        );
+        for i in 1..self.layers.len() {
+            let (prev_layer_dof, curr_layer_dof) = (self.layers[i - 1].0, self.layers[i].0);
+            let (matrix, bias) = self.layer_params(params, i);
+            let z = outputs[i - 1].dot(&matrix.t()) + bias;
+            let z = self.add_skips(params, i, |k| &outputs[k], z);
+            let activation = &self.layers[i].1;
+            let output = match recurrent.peek() {
+                Some(((j, _), _)) if *j == i => {
+                    let ((_, recurrence), h) = recurrent.next().unwrap();
+                    let mut extra_params_offset = self.recurrent_param_range(i).unwrap().start;
+                    let mut take = |rows: usize, cols: usize| {
+                        let view = params
+                            .slice(s![extra_params_offset..(extra_params_offset + rows * cols)])
+                            .into_shape((rows, cols))
+                            .unwrap();
+                        extra_params_offset += rows * cols;
+                        view
+                    };
+                    let new_h = match recurrence {
+                        Recurrence::Elman => {
+                            let u = take(curr_layer_dof, curr_layer_dof);
+                            activation.activate(z + h.dot(&u.t()))
+                        }
+                        Recurrence::Gru => {
+                            let u_candidate = take(curr_layer_dof, curr_layer_dof);
+                            let mut gates = Vec::with_capacity(2);
+                            for _gate in 0..2 {
+                                let w = take(curr_layer_dof, prev_layer_dof);
+                                let u = take(curr_layer_dof, curr_layer_dof);
+                                let b = take(1, curr_layer_dof);
+                                gates.push(
+                                    (outputs[i - 1].dot(&w.t()) + h.dot(&u.t()) + b).mapv(sigmoid),
+                                );
+                            }
+                            let (update, reset) = (&gates[0], &gates[1]);
+                            let candidate =
+                                activation.activate(z + reset * &h.dot(&u_candidate.t()));
+                            update.mapv(|u| F::one() - u) * &candidate + update * &*h
+                        }
+                    };
+                    *h = new_h.clone();
+                    new_h
+                }
+                _ => activation.activate(z),
             };
-            params_offset += curr_layer_dof;
-            // println!("2:{}", now.elapsed().as_nanos());
+            outputs.push(output);
+        }
+        self.denormalize_outputs(outputs.pop().unwrap())
+    }
+
+    pub fn try_step_batch_with(
+        &self,
+        inputs: &Array2<F>,
+        params: &Array1<F>,
+        state: &mut HiddenState<F>,
+    ) -> Result<Array2<F>, FcnError> {
+        self.check_step(inputs, params, state)?;
+        Ok(self.step_batch_with(inputs, params, state))
+    }
+
+    /// Checks input len, params len and that state holds a state per recurrent layer for every input.
+    fn check_step(
+        &self,
+        inputs: &Array2<F>,
+        params: &Array1<F>,
+        state: &HiddenState<F>,
+    ) -> Result<(), FcnError> {
+        self.check_eval(inputs.ncols(), params.len())?;
+        let expected = self
+            .recurrent
+            .iter()
+            .map(|(i, _)| (inputs.nrows(), self.layers[*i].0))
+            .collect::<Vec<(usize, usize)>>();
+        let found = state
+            .states
+            .iter()
+            .map(|h| h.dim())
+            .collect::<Vec<(usize, usize)>>();
+        if expected != found {
+            return Err(FcnError::HiddenStateShape { expected, found });
         }
-        output
+        Ok(())
+    }
+
+    /// Evaluates one step of a sequence and advances hidden state.
+    pub fn step_with(
+        &self,
+        input: &Array1<F>,
+        params: &Array1<F>,
+        state: &mut HiddenState<F>,
+    ) -> Array1<F> {
+        let inputs = input.to_owned().into_shape((1, input.len())).unwrap();
+        self.step_batch_with(&inputs, params, state)
+            .row(0)
+            .to_owned()
+    }
+
+    pub fn step(&self, input: &Array1<F>, state: &mut HiddenState<F>) -> Array1<F> {
+        self.step_with(input, &self.params, state)
+    }
+
+    pub fn try_at_batch_with(
+        &self,
+        inputs: &Array2<F>,
+        params: &Array1<F>,
+    ) -> Result<Array2<F>, FcnError> {
+        self.check_eval(inputs.ncols(), params.len())?;
+        Ok(self.at_batch_with(inputs, params))
     }
 
-    pub fn at(&self, input: &Array1<f32>) -> Array1<f32> {
+    pub fn at_batch(&self, inputs: &Array2<F>) -> Array2<F> {
+        self.at_batch_with(inputs, &self.params)
+    }
+
+    pub fn at(&self, input: &Array1<F>) -> Array1<F> {
         self.at_with(&input, &self.params)
     }
+
+    /// Batch forward pass that keeps (pre-activations, activations) of every layer for backprop.
+    /// Inputs are normalized, outputs are not denormalized.
+    pub(crate) fn forward_trace_with(
+        &self,
+        inputs: &Array2<F>,
+        params: &Array1<F>,
+    ) -> Vec<(Array2<F>, Array2<F>)> {
+        assert!(
+            self.recurrent.is_empty(),
+            "Trying to backprop through recurrent layers."
+        );
+        let mut trace = Vec::with_capacity(self.layers.len());
+        let inputs = self.normalize_inputs(inputs.to_owned());
+        trace.push((inputs.clone(), self.layers[0].1.activate(inputs)));
+        for i in 1..self.layers.len() {
+            let (matrix, bias) = self.layer_params(params, i);
+            let z = trace[i - 1].1.dot(&matrix.t()) + bias;
+            let z = self.add_skips(params, i, |k| &trace[k].1, z);
+            let a = self.layers[i].1.activate(z.clone());
+            trace.push((z, a));
+        }
+        trace
+    }
+
+    /// Reverse-mode gradient of a scalar loss w.r.t. params,
+    /// given the gradient of that loss w.r.t. the batch outputs.
+    pub fn grad_with(
+        &self,
+        inputs: &Array2<F>,
+        params: &Array1<F>,
+        output_grads: &Array2<F>,
+    ) -> Array1<F> {
+        self.check_eval(inputs.ncols(), params.len())
+            .unwrap_or_else(|e| panic!("{}", e));
+        let trace = self.forward_trace_with(inputs, params);
+        let output_grads = self.output_grads_before_denormalization(output_grads.to_owned());
+        self.backward_with(&trace, params, output_grads)
+    }
+
+    /// Chains gradients w.r.t. denormalized outputs through the output normalizer.
+    fn output_grads_before_denormalization(&self, output_grads: Array2<F>) -> Array2<F> {
+        match &self.output_normalizer {
+            Some(normalizer) => output_grads * &normalizer.center_and_divisor().1,
+            None => output_grads,
+        }
+    }
+
+    fn backward_with(
+        &self,
+        trace: &[(Array2<F>, Array2<F>)],
+        params: &Array1<F>,
+        output_grads: Array2<F>,
+    ) -> Array1<F> {
+        let mut grads = Array1::zeros(params.len());
+        // Gradients w.r.t. outputs of every layer, accumulated over the chain and skips
+        let mut grad_as = trace
+            .iter()
+            .map(|(_, a)| Array2::zeros(a.dim()))
+            .collect::<Vec<Array2<F>>>();
+        grad_as[self.layers.len() - 1] = output_grads;
+        for i in (1..self.layers.len()).rev() {
+            let (z, a) = &trace[i];
+            let grad_a = std::mem::replace(&mut grad_as[i], Array2::zeros((0, 0)));
+            let grad_z = self.layers[i].1.backward(z, a, grad_a);
+            for (j, skip) in self
+                .skips
+                .iter()
+                .enumerate()
+                .filter(|(_, skip)| skip.to() == i)
+            {
+                let from = skip.from();
+                match skip {
+                    Skip::Residual { .. } => grad_as[from] += &grad_z,
+                    Skip::Concat { .. } => {
+                        let matrix = self.skip_params(params, j);
+                        grads
+                            .slice_mut(s![self.skip_param_range(j).unwrap()])
+                            .into_shape(matrix.dim())
+                            .unwrap()
+                            .assign(&grad_z.t().dot(&trace[from].1));
+                        grad_as[from] += &grad_z.dot(&matrix);
+                    }
+                }
+            }
+            let prev_a = &trace[i - 1].1;
+            let (matrix, _) = self.layer_params(params, i);
+            let (grad_matrix, mut grad_bias) = grads
+                .slice_mut(s![self.layer_param_range(i)])
+                .split_at(Axis(0), matrix.len());
+            grad_matrix
+                .into_shape(matrix.dim())
+                .unwrap()
+                .assign(&grad_z.t().dot(prev_a));
+            grad_bias.assign(&grad_z.sum_axis(Axis(0)));
+            grad_as[i - 1] += &grad_z.dot(&matrix);
+        }
+        grads
+    }
+
+    /// Mean squared error of a batch and its gradient w.r.t. params.
+    pub fn mse_grad_with(
+        &self,
+        inputs: &Array2<F>,
+        targets: &Array2<F>,
+        params: &Array1<F>,
+    ) -> (F, Array1<F>) {
+        self.check_eval(inputs.ncols(), params.len())
+            .unwrap_or_else(|e| panic!("{}", e));
+        let trace = self.forward_trace_with(inputs, params);
+        let outputs = self.denormalize_outputs(trace[trace.len() - 1].1.clone());
+        assert_eq!(
+            outputs.dim(),
+            targets.dim(),
+            "Invalid targets shape for fcn"
+        );
+        let errors = outputs - targets;
+        let mse = errors.mapv(|e| e * e).sum() / F::from_usize(errors.len());
+        let output_grads = self.output_grads_before_denormalization(
+            errors * (F::from_f32(2.0) / F::from_usize(targets.len())),
+        );
+        (mse, self.backward_with(&trace, params, output_grads))
+    }
+
+    /// Central finite-difference gradient of a scalar loss of batch outputs w.r.t. params.
+    pub fn finite_difference_grad_with<L: Fn(&Array2<F>) -> F>(
+        &self,
+        inputs: &Array2<F>,
+        params: &Array1<F>,
+        loss: L,
+        epsilon: F,
+    ) -> Array1<F> {
+        let mut perturbed_params = params.to_owned();
+        let mut grads = Array1::zeros(params.len());
+        for i in 0..params.len() {
+            perturbed_params[i] = params[i] + epsilon;
+            let loss_plus = loss(&self.at_batch_with(inputs, &perturbed_params));
+            perturbed_params[i] = params[i] - epsilon;
+            let loss_minus = loss(&self.at_batch_with(inputs, &perturbed_params));
+            perturbed_params[i] = params[i];
+            grads[i] = (loss_plus - loss_minus) / (F::from_f32(2.0) * epsilon);
+        }
+        grads
+    }
+
+    /// Max relative error b/w analytic and finite-difference mse gradients at params.
+    pub fn check_mse_grad_with(
+        &self,
+        inputs: &Array2<F>,
+        targets: &Array2<F>,
+        params: &Array1<F>,
+        epsilon: F,
+    ) -> F {
+        let (_mse, analytic) = self.mse_grad_with(inputs, targets, params);
+        let numeric = self.finite_difference_grad_with(
+            inputs,
+            params,
+            |outputs| (outputs - targets).mapv(|e| e * e).sum() / F::from_usize(targets.len()),
+            epsilon,
+        );
+        analytic
+            .iter()
+            .zip(numeric.iter())
+            .map(|(&a, &n)| (a - n).abs() / (a.abs() + n.abs()).max(F::from_f32(1e-6)))
+            .fold(F::zero(), F::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seed;
+
+    /// Max relative error of the backward pass of a seeded f64 fcn on a random batch.
+    fn grad_error(layers: Vec<(usize, Activation)>, skips: Vec<Skip>) -> f64 {
+        let mut rng = seed::rng(7);
+        let fcn =
+            FCN::<f64>::new_graph_with(layers, vec![], skips, &Init::Normal(0.0, 0.5), &mut rng);
+        let num_inputs = fcn.layers()[0].0;
+        let num_outputs = fcn.layers()[fcn.layers().len() - 1].0;
+        let inputs = Array::from_shape_fn((5, num_inputs), |_| f64::standard_normal(&mut rng));
+        let targets = Array::from_shape_fn((5, num_outputs), |_| f64::standard_normal(&mut rng));
+        fcn.check_mse_grad_with(&inputs, &targets, fcn.params(), 1e-6)
+    }
+
+    #[test]
+    fn grad_matches_finite_differences_for_every_activation() {
+        let activations = vec![
+            Activation::Linear,
+            Activation::LeakyReLu(0.1),
+            Activation::Sigmoid,
+            Activation::Tanh,
+            Activation::ReLu,
+            Activation::Elu(1.0),
+            Activation::Softplus,
+            Activation::Swish,
+            Activation::Gelu,
+            Activation::Softmax,
+            Activation::ScaledTanh(vec![(-1.0, 2.0), (0.0, 3.0), (-5.0, -4.0)]),
+        ];
+        for activation in activations {
+            let error = grad_error(
+                vec![
+                    (2, Activation::Linear),
+                    (3, activation.clone()),
+                    (3, activation.clone()),
+                ],
+                vec![],
+            );
+            assert!(error < 1e-5, "{:?} error={}", activation, error);
+        }
+    }
+
+    #[test]
+    fn grad_matches_finite_differences_through_skips() {
+        let layers = vec![
+            (2, Activation::Linear),
+            (3, Activation::Tanh),
+            (3, Activation::Gelu),
+            (2, Activation::Linear),
+        ];
+        let skips = vec![
+            Skip::Residual { from: 1, to: 2 },
+            Skip::Concat { from: 0, to: 3 },
+            Skip::Concat { from: 1, to: 3 },
+        ];
+        let error = grad_error(layers, skips);
+        assert!(error < 1e-5, "error={}", error);
+    }
+
+    #[test]
+    fn summary_counts_params_of_every_layer_and_its_skips() {
+        let fcn = FCN::<f32>::new_graph_with(
+            vec![
+                (2, Activation::Linear),
+                (3, Activation::Tanh),
+                (3, Activation::ReLu),
+                (1, Activation::Linear),
+            ],
+            vec![],
+            vec![
+                Skip::Residual { from: 1, to: 2 },
+                Skip::Concat { from: 0, to: 3 },
+            ],
+            &Init::Normal(0.0, 0.5),
+            &mut seed::rng(0),
+        );
+        assert_eq!(fcn.layer_param_range(1), 0..9);
+        assert_eq!(fcn.layer_param_range(2), 9..21);
+        assert_eq!(fcn.layer_param_range(3), 21..25);
+        assert_eq!(fcn.recurrent_param_range(3), None);
+        // Only the concat skip has params
+        assert_eq!(fcn.skip_param_range(0), None);
+        assert_eq!(fcn.skip_param_range(1), Some(25..27));
+        assert_eq!(fcn.params().len(), 27);
+        let expected = concat!(
+            "layer        shape               activation     params      flops\n",
+            "    0            2                   Linear          0          0\n",
+            "    1          3x2                     Tanh          9         15\n",
+            "    2          3x3         ReLu+Residual(1)         12         24\n",
+            "    3          1x3         Linear+Concat(0)          6         11\n",
+            "total                                               27         50\n",
+        );
+        assert_eq!(fcn.summary(), expected);
+    }
+
+    #[test]
+    fn invalid_inits_are_errors() {
+        let layers = || vec![(2, Activation::Linear), (1, Activation::Linear)];
+        for init in [
+            Init::Uniform(1.0, 1.0),
+            Init::Uniform(2.0, 1.0),
+            Init::Normal(0.0, -1.0),
+            Init::Normal(0.0, f32::INFINITY),
+        ]
+        .iter()
+        {
+            let result = FCN::<f32>::try_new_with(layers(), init, &mut seed::rng(0));
+            assert_eq!(result.unwrap_err(), FcnError::InvalidInit(init.clone()));
+        }
+        let init = Init::PerLayer(vec![Init::Uniform(1.0, 0.0)]);
+        let result = FCN::<f32>::try_new_with(layers(), &init, &mut seed::rng(0));
+        assert_eq!(
+            result.unwrap_err(),
+            FcnError::InvalidInit(Init::Uniform(1.0, 0.0))
+        );
+    }
+
+    fn recurrent_fcn(recurrence: Recurrence) -> FCN<f64> {
+        FCN::new_recurrent_with(
+            vec![
+                (2, Activation::Linear),
+                (3, Activation::Tanh),
+                (1, Activation::Linear),
+            ],
+            vec![(1, recurrence)],
+            &Init::Normal(0.0, 0.5),
+            &mut seed::rng(0),
+        )
+    }
+
+    #[test]
+    fn recurrent_layers_carry_state_across_steps() {
+        for recurrence in [Recurrence::Elman, Recurrence::Gru].iter() {
+            let fcn = recurrent_fcn(recurrence.clone());
+            let input = arr1(&[0.5, -1.0]);
+            let mut state = fcn.hidden_state(1);
+            let first = fcn.step(&input, &mut state);
+            assert!(state.states[0].iter().any(|&h| h != 0.0));
+            let second = fcn.step(&input, &mut state);
+            assert_ne!(first, second, "{:?}", recurrence);
+            // From zero state again
+            assert_eq!(fcn.step(&input, &mut fcn.hidden_state(1)), first);
+            assert_eq!(fcn.at(&input), first);
+        }
+    }
+
+    #[test]
+    fn batch_steps_match_steps_of_every_row() {
+        let mut rng = seed::rng(1);
+        for recurrence in [Recurrence::Elman, Recurrence::Gru].iter() {
+            let fcn = recurrent_fcn(recurrence.clone());
+            let mut batch_state = fcn.hidden_state(3);
+            let mut row_states = vec![fcn.hidden_state(1); 3];
+            for _ in 0..4 {
+                let inputs = Array2::from_shape_fn((3, 2), |_| rng.gen_range(-1.0, 1.0));
+                let outputs = fcn.step_batch_with(&inputs, fcn.params(), &mut batch_state);
+                for (k, state) in row_states.iter_mut().enumerate() {
+                    let output = fcn.step(&inputs.row(k).to_owned(), state);
+                    assert!(outputs
+                        .row(k)
+                        .iter()
+                        .zip(output.iter())
+                        .all(|(a, b)| (a - b).abs() < 1e-12));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mismatching_hidden_state_is_an_error() {
+        let fcn = FCN::<f32>::new_recurrent_with(
+            vec![
+                (2, Activation::Linear),
+                (3, Activation::Tanh),
+                (1, Activation::Linear),
+            ],
+            vec![(1, Recurrence::Gru)],
+            &Init::Xavier,
+            &mut seed::rng(0),
+        );
+        let inputs = Array2::zeros((4, 2));
+        let mut state = fcn.hidden_state(3);
+        assert_eq!(
+            fcn.try_step_batch_with(&inputs, fcn.params(), &mut state)
+                .unwrap_err(),
+            FcnError::HiddenStateShape {
+                expected: vec![(4, 3)],
+                found: vec![(3, 3)],
+            }
+        );
+        let mut state = fcn.hidden_state(4);
+        assert!(fcn
+            .try_step_batch_with(&inputs, fcn.params(), &mut state)
+            .is_ok());
+    }
+
+    #[test]
+    fn affine_normalizer_maps_bounds_onto_unit_interval() {
+        let normalizer = Normalizer::from_bounds(&[(-10.0, 10.0), (2.0, 6.0), (3.0, 3.0)]);
+        let x = arr2(&[[-10.0, 2.0, 3.0], [10.0, 6.0, 3.0], [0.0, 4.0, 5.0]]);
+        let normalized = normalizer.normalize(x.clone());
+        assert_eq!(
+            normalized,
+            arr2(&[[-1.0, -1.0, 0.0], [1.0, 1.0, 0.0], [0.0, 0.0, 2.0]])
+        );
+        assert_eq!(normalizer.denormalize(normalized), x);
+    }
+
+    #[test]
+    fn running_normalizer_matches_batch_moments() {
+        let mut rng = seed::rng(2);
+        let x = Array::from_shape_fn((100, 2), |(_, j)| {
+            3.0 * f64::standard_normal(&mut rng) + j as f64
+        });
+        let mut normalizer = Normalizer::running(2, 0.0);
+        assert_eq!(
+            normalizer.center_and_divisor(),
+            (arr1(&[0.0, 0.0]), arr1(&[1.0, 1.0]))
+        );
+        normalizer.observe(&x.slice(s![..30, ..]).to_owned());
+        normalizer.observe(&x.slice(s![30.., ..]).to_owned());
+        let (center, divisor) = normalizer.center_and_divisor();
+        let mean = x.mean_axis(Axis(0)).unwrap();
+        let std = x.std_axis(Axis(0), 0.0);
+        assert!((center - mean).iter().all(|d| d.abs() < 1e-12));
+        assert!((divisor - std).iter().all(|d| d.abs() < 1e-12));
+    }
+
+    #[test]
+    fn normalizers_wrap_the_network() {
+        let mut fcn = FCN::<f64>::new_with(
+            vec![
+                (2, Activation::Linear),
+                (3, Activation::Tanh),
+                (2, Activation::Linear),
+            ],
+            &Init::Normal(0.0, 0.5),
+            &mut seed::rng(4),
+        );
+        let inputs = arr2(&[[-4.0, 1.0], [7.0, 0.5]]);
+        let outputs = fcn.at_batch(&inputs);
+        let input_normalizer = Normalizer::from_bounds(&[(-10.0, 10.0), (0.0, 1.0)]);
+        let output_normalizer = Normalizer::from_bounds(&[(-3.0, 5.0), (1.0, 1.0)]);
+        fcn.set_input_normalizer(Some(input_normalizer.clone()))
+            .unwrap();
+        fcn.set_output_normalizer(Some(output_normalizer.clone()))
+            .unwrap();
+        let normalized_outputs = fcn.at_batch(&input_normalizer.denormalize(inputs.clone()));
+        let expected = output_normalizer.denormalize(outputs);
+        assert!((normalized_outputs - &expected)
+            .iter()
+            .all(|d| d.abs() < 1e-12));
+        assert_eq!(
+            fcn.set_input_normalizer(Some(Normalizer::from_bounds(&[(0.0, 1.0)])))
+                .unwrap_err(),
+            FcnError::NormalizerLenMismatch {
+                expected: 2,
+                found: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn batch_workspace_evaluation_matches_at_batch_with() {
+        let mut rng = seed::rng(9);
+        let mut fcn = FCN::<f32>::new_graph_with(
+            vec![
+                (3, Activation::Linear),
+                (5, Activation::LeakyReLu(0.1)),
+                (5, Activation::Tanh),
+                (2, Activation::ScaledTanh(vec![(-1.0, 1.0), (0.0, 2.0)])),
+            ],
+            vec![],
+            vec![
+                Skip::Residual { from: 1, to: 2 },
+                Skip::Concat { from: 0, to: 3 },
+            ],
+            &Init::Normal(0.0, 0.5),
+            &mut rng,
+        );
+        fcn.set_input_normalizer(Some(Normalizer::from_bounds(&[(-10.0, 10.0); 3])))
+            .unwrap();
+        fcn.set_output_normalizer(Some(Normalizer::from_bounds(&[(0.0, 4.0), (-2.0, 2.0)])))
+            .unwrap();
+        let mut workspace = fcn.batch_workspace(6);
+        let mut outputs = Array2::zeros((6, 2));
+        for _ in 0..3 {
+            let inputs = Array::from_shape_fn((6, 3), |_| 10.0 * f32::standard_normal(&mut rng));
+            fcn.at_batch_into(&inputs, fcn.params(), &mut workspace, &mut outputs);
+            let expected = fcn.at_batch_with(&inputs, fcn.params());
+            assert!(outputs
+                .iter()
+                .zip(expected.iter())
+                .all(|(a, b)| (a - b).abs() <= 1e-5 * b.abs().max(1.0)));
+        }
+    }
 }
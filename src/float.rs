@@ -0,0 +1,45 @@
+use ndarray::NdFloat;
+use ndarray_rand::rand_distr::{Distribution, StandardNormal};
+use rand::distributions::uniform::SampleUniform;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Float type of params and rewards, either f32 or f64.
+pub trait Float: NdFloat + SampleUniform + Serialize + DeserializeOwned {
+    /// Exact for f32, widening for f64.
+    fn from_f32(x: f32) -> Self;
+
+    fn from_usize(x: usize) -> Self;
+
+    /// Sample from normal(0, 1), same stream as rand_distr's StandardNormal.
+    fn standard_normal<R: Rng + ?Sized>(rng: &mut R) -> Self;
+}
+
+impl Float for f32 {
+    fn from_f32(x: f32) -> f32 {
+        x
+    }
+
+    fn from_usize(x: usize) -> f32 {
+        x as f32
+    }
+
+    fn standard_normal<R: Rng + ?Sized>(rng: &mut R) -> f32 {
+        StandardNormal.sample(rng)
+    }
+}
+
+impl Float for f64 {
+    fn from_f32(x: f32) -> f64 {
+        x as f64
+    }
+
+    fn from_usize(x: usize) -> f64 {
+        x as f64
+    }
+
+    fn standard_normal<R: Rng + ?Sized>(rng: &mut R) -> f64 {
+        StandardNormal.sample(rng)
+    }
+}
@@ -0,0 +1,134 @@
+use crate::fcn::*;
+use crate::float::Float;
+use crate::interrupt;
+use crate::seed;
+use ndarray::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Source of supervised minibatches.
+pub trait Minibatches<F: Float = f32> {
+    /// Returns (inputs, targets), one sample per row, sampled from a stream derived from seed.
+    fn minibatch(&self, batch_size: usize, seed: u64) -> (Array2<F>, Array2<F>);
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum Update {
+    Sgd,
+    /// Heavy ball momentum with given decay.
+    Momentum(f32),
+    Adam {
+        beta1: f32,
+        beta2: f32,
+        epsilon: f32,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GD {
+    pub steps: usize,
+    pub batch_size: usize,
+    pub learning_rate: f32,
+    pub update: Update,
+    /// Master seed of minibatches, none to draw one from entropy.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
+
+impl Default for GD {
+    fn default() -> GD {
+        GD {
+            steps: 5000,
+            batch_size: 64,
+            learning_rate: 0.01,
+            update: Update::Adam {
+                beta1: 0.9,
+                beta2: 0.999,
+                epsilon: 1e-8,
+            },
+            seed: None,
+        }
+    }
+}
+
+impl GD {
+    /// Minimizes mean squared error of fcn on minibatches, until steps or an interrupt. Returns mse of last minibatch.
+    /// Running normalizers of fcn are updated with every minibatch before its gradient is taken.
+    pub fn optimize<F: Float>(&self, fcn: &mut FCN<F>, data: &dyn Minibatches<F>) -> F {
+        let mut update_state = UpdateState::new(fcn.params().len());
+        let mut mse = F::zero();
+        let learning_rate = F::from_f32(self.learning_rate);
+        let master_seed = seed::derive(
+            self.seed.unwrap_or_else(rand::random),
+            seed::OPTIMIZER_STREAM,
+        );
+        for step in 0..self.steps {
+            let (inputs, targets) =
+                data.minibatch(self.batch_size, seed::derive(master_seed, step as u64));
+            fcn.observe_inputs(&inputs);
+            fcn.observe_targets(&targets);
+            let (batch_mse, grads) = fcn.mse_grad_with(&inputs, &targets, fcn.params());
+            mse = batch_mse;
+            let delta = update_state.delta(&self.update, grads, learning_rate);
+            fcn.set_params(fcn.params() - &delta);
+            println!("step={} mse={:?}", step + 1, mse);
+            if interrupt::requested() {
+                break;
+            }
+        }
+        mse
+    }
+}
+
+/// Moments of gradients accumulated by an update rule.
+pub(crate) struct UpdateState<F> {
+    first_moment: Array1<F>,
+    second_moment: Array1<F>,
+    num_steps: i32,
+}
+
+impl<F: Float> UpdateState<F> {
+    pub(crate) fn new(num_params: usize) -> UpdateState<F> {
+        UpdateState {
+            first_moment: Array1::zeros(num_params),
+            second_moment: Array1::zeros(num_params),
+            num_steps: 0,
+        }
+    }
+
+    /// Step to subtract from params to descend along grads.
+    pub(crate) fn delta(
+        &mut self,
+        update: &Update,
+        grads: Array1<F>,
+        learning_rate: F,
+    ) -> Array1<F> {
+        self.num_steps += 1;
+        match update {
+            Update::Sgd => grads * learning_rate,
+            Update::Momentum(decay) => {
+                self.first_moment = &self.first_moment * F::from_f32(*decay) + grads;
+                &self.first_moment * learning_rate
+            }
+            Update::Adam {
+                beta1,
+                beta2,
+                epsilon,
+            } => {
+                let (beta1, beta2, epsilon) = (
+                    F::from_f32(*beta1),
+                    F::from_f32(*beta2),
+                    F::from_f32(*epsilon),
+                );
+                self.first_moment = &self.first_moment * beta1 + &grads * (F::one() - beta1);
+                self.second_moment =
+                    &self.second_moment * beta2 + grads.mapv(|g| g * g) * (F::one() - beta2);
+                let t = self.num_steps;
+                let first_moment_hat = &self.first_moment / (F::one() - beta1.powi(t));
+                let second_moment_hat = &self.second_moment / (F::one() - beta2.powi(t));
+                first_moment_hat / second_moment_hat.mapv(|v| v.sqrt() + epsilon) * learning_rate
+            }
+        }
+    }
+}
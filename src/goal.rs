@@ -10,7 +10,14 @@ pub struct Goal {
 
 impl Goal {
     pub fn in_region(x_bounds: (f32, f32), y_bounds: (f32, f32)) -> Goal {
-        let mut rng = rand::thread_rng();
+        Goal::in_region_using(x_bounds, y_bounds, &mut rand::thread_rng())
+    }
+
+    pub fn in_region_using<R: Rng + ?Sized>(
+        x_bounds: (f32, f32),
+        y_bounds: (f32, f32),
+        rng: &mut R,
+    ) -> Goal {
         let x = x_bounds.0 + (x_bounds.1 - x_bounds.0) * rng.gen::<f32>();
         let y = y_bounds.0 + (y_bounds.1 - y_bounds.0) * rng.gen::<f32>();
         Goal { x: x, y: y }
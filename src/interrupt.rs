@@ -0,0 +1,23 @@
+//! Ctrl-C as a request to stop optimization after the current generation.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Makes the first Ctrl-C request optimizers to stop after their current generation, and a second one exit.
+pub fn install_handler() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(|| {
+        if request() {
+            std::process::exit(130);
+        }
+        println!("interrupted, stopping after the current generation");
+    })
+}
+
+/// Requests optimizers to stop after their current generation, returns whether it was already requested.
+pub fn request() -> bool {
+    REQUESTED.swap(true, Ordering::SeqCst)
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
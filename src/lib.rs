@@ -1,6 +1,17 @@
 #[macro_use]
 extern crate static_assertions;
+pub mod bounds;
 pub mod ceo;
+pub mod cma_es;
+pub mod codegen;
 pub mod diff_drive_model;
+pub mod es;
 pub mod fcn;
+pub mod float;
+pub mod gd;
 pub mod goal;
+pub mod interrupt;
+pub mod moceo;
+pub mod optimizer;
+pub mod quant;
+pub mod seed;
@@ -0,0 +1,560 @@
+use crate::ceo::{
+    compare_rewards, mean, next_distribution, sample_candidate, CeoError, CeoObserver,
+    GenerationStats, NanPolicy, NoiseSchedule, PrintObserver, Recombination, StoppingCriteria,
+};
+use crate::fcn::*;
+use crate::float::Float;
+use crate::seed;
+use ndarray::prelude::*;
+use rand::RngCore;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::time::Instant;
+
+/// Objectives to maximize jointly, ex. the terms of a reward before they are weighted.
+pub trait MultiReward<F: Float = f32> {
+    fn num_objectives(&self) -> usize;
+
+    /// Mean objectives over episodes sampled from streams derived from seed, so equal seeds give equal episodes.
+    fn rewards(
+        &self,
+        fcn: &FCN<F>,
+        params: &Array1<F>,
+        num_episodes: usize,
+        seed: u64,
+    ) -> Array1<F>;
+}
+
+/// Multi-objective ceo. Its elite is selected from candidates by nsga-ii,
+/// i.e. by front of non-dominated sorting, then by crowding distance within the last front.
+/// Candidates are sampled and the search distribution updated as by ceo, with the elite ranked in order of selection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MoCEO {
+    pub generations: usize,
+    pub batch_size: usize,
+    pub num_evaluation_samples: usize,
+    pub elite_frac: f32,
+    pub initial_std: f32,
+    pub noise_factor: f32,
+    /// Max number of points of the pareto set, the most crowded ones are dropped beyond it, 0 for no limit.
+    pub pareto_set_size: usize,
+    /// Master seed of noise and evaluations, none to draw one from entropy.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub nan_policy: NanPolicy,
+    /// Stops on statistics of the first objective, see optimize_with.
+    #[serde(default)]
+    pub stopping: StoppingCriteria,
+    #[serde(default)]
+    pub recombination: Recombination,
+    /// Carries the previous elite unperturbed into every generation in place of the last sampled candidates,
+    /// so that it competes again with objectives evaluated on the episodes of the generation.
+    #[serde(default)]
+    pub elitism: bool,
+    #[serde(default)]
+    pub noise_schedule: NoiseSchedule,
+    /// Bounds of the std of every param after the extra std is added.
+    #[serde(default)]
+    pub min_std: Option<f32>,
+    #[serde(default)]
+    pub max_std: Option<f32>,
+}
+
+impl Default for MoCEO {
+    fn default() -> MoCEO {
+        MoCEO {
+            generations: 300,
+            batch_size: 50,
+            num_evaluation_samples: 300,
+            elite_frac: 0.25,
+            initial_std: 2.0,
+            noise_factor: 2.0,
+            pareto_set_size: 100,
+            seed: None,
+            nan_policy: NanPolicy::Worst,
+            stopping: StoppingCriteria::default(),
+            recombination: Recombination::Equal,
+            elitism: false,
+            noise_schedule: NoiseSchedule::Hyperbolic,
+            min_std: None,
+            max_std: None,
+        }
+    }
+}
+
+/// Params evaluated during optimization that no other evaluated params dominate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, bound(deserialize = "F: Float"))]
+pub struct ParetoSet<F = f32> {
+    /// In decreasing order of the first objective.
+    pub points: Vec<ParetoPoint<F>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, bound(deserialize = "F: Float"))]
+pub struct ParetoPoint<F = f32> {
+    /// Generation of the objectives, from 1.
+    pub generation: usize,
+    pub objectives: Array1<F>,
+    pub params: Array1<F>,
+}
+
+impl<F> Default for ParetoSet<F> {
+    fn default() -> ParetoSet<F> {
+        ParetoSet { points: vec![] }
+    }
+}
+
+impl MoCEO {
+    /// Same as optimize_with, using PrintObserver.
+    pub fn optimize<F: Float>(
+        &self,
+        fcn: &mut FCN<F>,
+        reward: &(dyn MultiReward<F> + std::marker::Sync),
+    ) -> Result<ParetoSet<F>, CeoError> {
+        self.optimize_with(fcn, reward, &mut PrintObserver)
+    }
+
+    /// Sets params of fcn to the mean params after the last generation, or the current one if stopped early,
+    /// and returns the pareto set of all evaluated candidates.
+    /// Observer, stopping criteria and noise schedule see statistics of the first objective,
+    /// with the objectives of mean params as current reward.
+    /// Every candidate draws its noise and evaluation seed from streams derived from the seed of its generation.
+    pub fn optimize_with<F: Float>(
+        &self,
+        fcn: &mut FCN<F>,
+        reward: &(dyn MultiReward<F> + std::marker::Sync),
+        observer: &mut dyn CeoObserver<F>,
+    ) -> Result<ParetoSet<F>, CeoError> {
+        let n_elite = (self.batch_size as f32 * self.elite_frac).round() as usize;
+        if n_elite == 0 || reward.num_objectives() == 0 {
+            return Err(CeoError::TooFewCandidates);
+        }
+        let start = Instant::now();
+        let mut rng = seed::rng(seed::derive(
+            self.seed.unwrap_or_else(rand::random),
+            seed::OPTIMIZER_STREAM,
+        ));
+        let mut noise_std = Array::from_elem(fcn.params().len(), F::from_f32(self.initial_std));
+        let noise_scales = Array1::ones(fcn.params().len());
+        let mut observer = self.stopping.observer(observer);
+        let mut elite: Vec<Array1<F>> = vec![];
+        let mut pareto_set = ParetoSet::default();
+        let mut best_reward: Option<F> = None;
+        let mut num_stagnant_generations = 0;
+        let mut num_evaluations = 0;
+        for generation in 0..self.generations {
+            let generation_seed = rng.next_u64();
+            let num_sampled = self.batch_size - elite.len().min(self.batch_size);
+            let candidates = (0..self.batch_size)
+                .into_par_iter()
+                .map(|k| {
+                    let candidate_seed = seed::derive(generation_seed, k as u64);
+                    let params = if k < num_sampled {
+                        sample_candidate(candidate_seed, fcn.params(), &noise_std)
+                    } else {
+                        elite[k - num_sampled].clone()
+                    };
+                    let (objectives, num_invalid) =
+                        self.evaluate(reward, fcn, &params, seed::derive(candidate_seed, 1));
+                    let point = ParetoPoint {
+                        generation: generation + 1,
+                        objectives: objectives,
+                        params: params,
+                    };
+                    (point, num_invalid)
+                })
+                .collect::<Vec<(ParetoPoint<F>, usize)>>();
+            let mut num_invalid = 0;
+            for (point, candidate_num_invalid) in &candidates {
+                num_invalid += candidate_num_invalid;
+                num_evaluations += candidate_num_invalid + is_valid(&point.objectives) as usize;
+            }
+            self.nan_policy.check(num_invalid, generation + 1)?;
+            let candidates = candidates
+                .into_iter()
+                .map(|(point, _)| point)
+                .collect::<Vec<ParetoPoint<F>>>();
+            pareto_set.update(&candidates, self.pareto_set_size);
+            let mut rewards = candidates
+                .iter()
+                .map(|point| point.objectives[0])
+                .collect::<Vec<F>>();
+            rewards.sort_by(|a, b| compare_rewards(*a, *b));
+            rewards.reverse();
+            let selected = select(candidates, n_elite);
+            let elite_rewards = selected
+                .iter()
+                .map(|point| point.objectives[0])
+                .filter(|reward| reward.is_finite())
+                .collect::<Vec<F>>();
+            let elite_ths = selected
+                .iter()
+                .map(|point| point.params.view())
+                .collect::<Vec<ArrayView1<F>>>();
+            let extra_std = self.noise_schedule.extra_std::<F>(
+                self.noise_factor,
+                generation,
+                self.generations,
+                num_stagnant_generations,
+            );
+            let (elite_th_mean, elite_th_std) = next_distribution(
+                &elite_ths,
+                self.recombination,
+                extra_std,
+                &noise_scales,
+                (self.min_std, self.max_std),
+            )?;
+            noise_std = elite_th_std;
+            fcn.set_params(elite_th_mean);
+            if self.elitism {
+                elite = selected.into_iter().map(|point| point.params).collect();
+            }
+            let (current_objectives, current_num_invalid) = self.evaluate(
+                reward,
+                fcn,
+                fcn.params(),
+                seed::derive(generation_seed, self.batch_size as u64),
+            );
+            num_invalid += current_num_invalid;
+            num_evaluations += current_num_invalid + is_valid(&current_objectives) as usize;
+            self.nan_policy.check(num_invalid, generation + 1)?;
+            let current_reward = current_objectives[0];
+            let improved = match best_reward {
+                Some(best_reward) => current_reward > best_reward,
+                None => current_reward.is_finite(),
+            };
+            if improved {
+                best_reward = Some(current_reward);
+                num_stagnant_generations = 0;
+            } else {
+                num_stagnant_generations += 1;
+            }
+            let stats = GenerationStats {
+                elite_mean_reward: mean(&elite_rewards),
+                wall_time: start.elapsed(),
+                num_invalid: num_invalid,
+                num_evaluations: num_evaluations,
+                ..GenerationStats::from_sorted_rewards(
+                    generation + 1,
+                    &rewards,
+                    n_elite,
+                    current_reward,
+                    noise_std.sum() / F::from_usize(noise_std.len()),
+                )
+            };
+            if observer.observe(&stats) {
+                break;
+            }
+        }
+        Ok(pareto_set)
+    }
+
+    /// Objectives of params, resampled under NanPolicy::Resample while any is invalid,
+    /// along with the number of invalid evaluations.
+    fn evaluate<F: Float>(
+        &self,
+        reward: &(dyn MultiReward<F> + std::marker::Sync),
+        fcn: &FCN<F>,
+        params: &Array1<F>,
+        seed: u64,
+    ) -> (Array1<F>, usize) {
+        let mut objectives = reward.rewards(fcn, params, self.num_evaluation_samples, seed);
+        let mut num_invalid = 0;
+        while !is_valid(&objectives) {
+            num_invalid += 1;
+            match self.nan_policy {
+                NanPolicy::Resample(max_resamples) if num_invalid <= max_resamples => {
+                    objectives = reward.rewards(
+                        fcn,
+                        params,
+                        self.num_evaluation_samples,
+                        seed::derive(seed, num_invalid as u64),
+                    )
+                }
+                _ => break,
+            }
+        }
+        (objectives, num_invalid)
+    }
+}
+
+impl<F: Float> ParetoSet<F> {
+    /// Adds the points no point of the set or of points dominates, drops the ones they dominate,
+    /// then the most crowded ones beyond max_size if any.
+    fn update(&mut self, points: &[ParetoPoint<F>], max_size: usize) {
+        let mut pool = std::mem::take(&mut self.points);
+        for point in points {
+            if is_valid(&point.objectives) && !pool.iter().any(|p| p.params == point.params) {
+                pool.push(point.clone());
+            }
+        }
+        let objectives = pool
+            .iter()
+            .map(|point| &point.objectives)
+            .collect::<Vec<&Array1<F>>>();
+        let mut front = non_dominated_fronts(&objectives).swap_remove(0);
+        if max_size > 0 && front.len() > max_size {
+            front = by_crowding_distance(&objectives, front);
+            front.truncate(max_size);
+        }
+        front.sort_unstable();
+        let mut pool = pool.into_iter().map(Some).collect::<Vec<_>>();
+        self.points = front.into_iter().filter_map(|k| pool[k].take()).collect();
+        self.points.sort_by(|a, b| {
+            b.objectives[0]
+                .partial_cmp(&a.objectives[0])
+                .unwrap_or(Ordering::Equal)
+        });
+    }
+}
+
+/// Objectives are valid if all are finite.
+fn is_valid<F: Float>(objectives: &Array1<F>) -> bool {
+    objectives.iter().all(|x| x.is_finite())
+}
+
+/// Whether a is at least as good as b in every objective and better in one,
+/// invalid objectives are dominated by all valid ones.
+fn dominates<F: Float>(a: &Array1<F>, b: &Array1<F>) -> bool {
+    match (is_valid(a), is_valid(b)) {
+        (true, true) => {
+            a.iter().zip(b.iter()).all(|(x, y)| x >= y)
+                && a.iter().zip(b.iter()).any(|(x, y)| x > y)
+        }
+        (valid_a, valid_b) => valid_a && !valid_b,
+    }
+}
+
+/// Indices of objectives in fronts of non-dominated sorting, the first of which is non-dominated.
+fn non_dominated_fronts<F: Float>(objectives: &[&Array1<F>]) -> Vec<Vec<usize>> {
+    let n = objectives.len();
+    let mut dominated = vec![vec![]; n];
+    let mut num_dominating = vec![0; n];
+    for a in 0..n {
+        for b in 0..n {
+            if dominates(objectives[a], objectives[b]) {
+                dominated[a].push(b);
+            } else if dominates(objectives[b], objectives[a]) {
+                num_dominating[a] += 1;
+            }
+        }
+    }
+    let mut fronts = vec![];
+    let mut front = (0..n)
+        .filter(|&a| num_dominating[a] == 0)
+        .collect::<Vec<usize>>();
+    while !front.is_empty() {
+        let mut next_front = vec![];
+        for &a in front.iter() {
+            for &b in dominated[a].iter() {
+                num_dominating[b] -= 1;
+                if num_dominating[b] == 0 {
+                    next_front.push(b);
+                }
+            }
+        }
+        next_front.sort_unstable();
+        fronts.push(front);
+        front = next_front;
+    }
+    if fronts.is_empty() {
+        fronts.push(vec![]);
+    }
+    fronts
+}
+
+/// Indices of front in decreasing order of crowding distance, with extremes of every objective first.
+fn by_crowding_distance<F: Float>(objectives: &[&Array1<F>], mut front: Vec<usize>) -> Vec<usize> {
+    let mut distances = vec![F::zero(); objectives.len()];
+    let num_objectives = objectives[front[0]].len();
+    let columns = (0..num_objectives).map(|m| objectives.iter().map(|x| x[m]).collect::<Vec<F>>());
+    for column in columns {
+        front.sort_by(|&a, &b| column[a].partial_cmp(&column[b]).unwrap_or(Ordering::Equal));
+        let (first, last) = (front[0], front[front.len() - 1]);
+        let range = column[last] - column[first];
+        distances[first] = F::infinity();
+        distances[last] = F::infinity();
+        if range.is_nan() || range <= F::zero() {
+            continue;
+        }
+        for window in front.windows(3) {
+            distances[window[1]] += (column[window[2]] - column[window[0]]) / range;
+        }
+    }
+    front.sort_by(|&a, &b| {
+        distances[b]
+            .partial_cmp(&distances[a])
+            .unwrap_or(Ordering::Equal)
+            .then(a.cmp(&b))
+    });
+    front
+}
+
+/// Best n points of pool by front, then by crowding distance within the last front taken.
+fn select<F: Float>(pool: Vec<ParetoPoint<F>>, n: usize) -> Vec<ParetoPoint<F>> {
+    let objectives = pool
+        .iter()
+        .map(|point| &point.objectives)
+        .collect::<Vec<&Array1<F>>>();
+    let mut selected = Vec::with_capacity(n);
+    for front in non_dominated_fronts(&objectives) {
+        if selected.len() + front.len() <= n {
+            selected.extend(front);
+        } else {
+            let remaining = n - selected.len();
+            selected.extend(
+                by_crowding_distance(&objectives, front)
+                    .into_iter()
+                    .take(remaining),
+            );
+        }
+        if selected.len() == n {
+            break;
+        }
+    }
+    let mut pool = pool.into_iter().map(Some).collect::<Vec<_>>();
+    selected
+        .into_iter()
+        .filter_map(|k| pool[k].take())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Negative squared distances of params to 1 and to -1, recording the params and seed of every evaluation.
+    #[derive(Default)]
+    struct TwoTargets {
+        evaluations: Mutex<Vec<(Array1<f64>, u64)>>,
+    }
+
+    impl MultiReward<f64> for TwoTargets {
+        fn num_objectives(&self) -> usize {
+            2
+        }
+
+        fn rewards(&self, _: &FCN<f64>, params: &Array1<f64>, _: usize, seed: u64) -> Array1<f64> {
+            self.evaluations
+                .lock()
+                .unwrap()
+                .push((params.clone(), seed));
+            arr1(&[
+                -params.mapv(|th| (th - 1.0) * (th - 1.0)).sum(),
+                -params.mapv(|th| (th + 1.0) * (th + 1.0)).sum(),
+            ])
+        }
+    }
+
+    fn fcn() -> FCN<f64> {
+        FCN::new_with(
+            vec![(1, Activation::Linear), (1, Activation::Linear)],
+            &Init::Normal(0.0, 0.5),
+            &mut seed::rng(0),
+        )
+    }
+
+    fn moceo() -> MoCEO {
+        MoCEO {
+            generations: 20,
+            batch_size: 20,
+            num_evaluation_samples: 1,
+            initial_std: 1.0,
+            noise_factor: 0.5,
+            pareto_set_size: 10,
+            seed: Some(7),
+            ..MoCEO::default()
+        }
+    }
+
+    #[test]
+    fn pareto_set_is_non_dominated_and_reproducible() {
+        let optimize = || {
+            let mut fcn = fcn();
+            let pareto_set = moceo()
+                .optimize_with(
+                    &mut fcn,
+                    &TwoTargets::default(),
+                    &mut |_: &GenerationStats<f64>| false,
+                )
+                .unwrap();
+            (fcn, pareto_set)
+        };
+        let (fcn, pareto_set) = optimize();
+        let points = &pareto_set.points;
+        assert!(!points.is_empty() && points.len() <= 10);
+        for a in points {
+            assert!(points
+                .iter()
+                .all(|b| !dominates(&b.objectives, &a.objectives)));
+        }
+        assert!(points
+            .windows(2)
+            .all(|pair| pair[0].objectives[0] >= pair[1].objectives[0]));
+        let (same_fcn, same_pareto_set) = optimize();
+        assert_eq!(same_fcn.params(), fcn.params());
+        assert_eq!(
+            format!("{:?}", same_pareto_set),
+            format!("{:?}", pareto_set)
+        );
+    }
+
+    #[test]
+    fn stopping_criteria_and_std_bounds_apply() {
+        let moceo = MoCEO {
+            stopping: StoppingCriteria {
+                max_evaluations: Some(3 * 21),
+                ..StoppingCriteria::default()
+            },
+            min_std: Some(0.3),
+            recombination: Recombination::LogRank,
+            noise_schedule: NoiseSchedule::Constant,
+            ..moceo()
+        };
+        let mut stats = vec![];
+        moceo
+            .optimize_with(
+                &mut fcn(),
+                &TwoTargets::default(),
+                &mut |generation_stats: &GenerationStats<f64>| {
+                    stats.push(generation_stats.clone());
+                    false
+                },
+            )
+            .unwrap();
+        // Every generation evaluates its candidates and its mean params once
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats[2].num_evaluations, 3 * 21);
+        assert!(stats.iter().all(|stats| stats.std_mean >= 0.3));
+    }
+
+    #[test]
+    fn carried_elite_is_evaluated_again() {
+        let n_elite = 5;
+        let moceo = MoCEO {
+            generations: 5,
+            elitism: true,
+            ..moceo()
+        };
+        let reward = TwoTargets::default();
+        moceo
+            .optimize_with(&mut fcn(), &reward, &mut |_: &GenerationStats<f64>| false)
+            .unwrap();
+        let evaluations = reward.evaluations.into_inner().unwrap();
+        let mut num_reevaluations = 0;
+        for (k, (params, seed)) in evaluations.iter().enumerate() {
+            for (other_params, other_seed) in &evaluations[..k] {
+                if params == other_params {
+                    assert_ne!(seed, other_seed);
+                    num_reevaluations += 1;
+                }
+            }
+        }
+        assert!(num_reevaluations >= n_elite * (moceo.generations - 1));
+    }
+}
@@ -0,0 +1,88 @@
+use crate::ceo::{CeoError, CeoObserver, HallOfFame, PrintObserver, Reward, CEO};
+use crate::cma_es::CmaEs;
+use crate::es::ES;
+use crate::fcn::*;
+use crate::float::Float;
+use ndarray::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Black-box maximizer of reward over params of fcn.
+pub trait Optimizer {
+    /// Reports every generation to observer, which can stop it early.
+    /// Sets params of fcn to the result and returns the final search distribution and hall of fame.
+    fn optimize_with<F: Float>(
+        &self,
+        fcn: &mut FCN<F>,
+        reward: &(dyn Reward<F> + std::marker::Sync),
+        observer: &mut dyn CeoObserver<F>,
+    ) -> Result<Outcome<F>, CeoError>;
+
+    /// Prints progress using PrintObserver.
+    fn optimize<F: Float>(
+        &self,
+        fcn: &mut FCN<F>,
+        reward: &(dyn Reward<F> + std::marker::Sync),
+    ) -> Result<Outcome<F>, CeoError> {
+        self.optimize_with(fcn, reward, &mut PrintObserver)
+    }
+}
+
+/// Result of an optimization besides params of fcn.
+#[derive(Debug, Clone)]
+pub struct Outcome<F = f32> {
+    /// Std per param of the final search distribution.
+    pub noise_std: Array1<F>,
+    /// Empty for optimizers that keep none.
+    pub hall_of_fame: HallOfFame<F>,
+}
+
+/// Optimizer selected by name in json, ex. `{"CmaEs": {...}}` or `{"Es": {...}}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum AnyOptimizer {
+    Ceo(CEO),
+    CmaEs(CmaEs),
+    Es(ES),
+}
+
+impl AnyOptimizer {
+    pub fn seed(&self) -> Option<u64> {
+        match self {
+            AnyOptimizer::Ceo(ceo) => ceo.seed,
+            AnyOptimizer::CmaEs(cma_es) => cma_es.seed,
+            AnyOptimizer::Es(es) => es.seed,
+        }
+    }
+
+    pub fn set_seed(&mut self, seed: Option<u64>) {
+        match self {
+            AnyOptimizer::Ceo(ceo) => ceo.seed = seed,
+            AnyOptimizer::CmaEs(cma_es) => cma_es.seed = seed,
+            AnyOptimizer::Es(es) => es.seed = seed,
+        }
+    }
+
+    /// Episodes per evaluation of a candidate.
+    pub fn num_evaluation_samples(&self) -> usize {
+        match self {
+            AnyOptimizer::Ceo(ceo) => ceo.num_evalation_samples,
+            AnyOptimizer::CmaEs(cma_es) => cma_es.num_evaluation_samples,
+            AnyOptimizer::Es(es) => es.num_evaluation_samples,
+        }
+    }
+}
+
+impl Optimizer for AnyOptimizer {
+    fn optimize_with<F: Float>(
+        &self,
+        fcn: &mut FCN<F>,
+        reward: &(dyn Reward<F> + std::marker::Sync),
+        observer: &mut dyn CeoObserver<F>,
+    ) -> Result<Outcome<F>, CeoError> {
+        match self {
+            AnyOptimizer::Ceo(ceo) => ceo.optimize_with(fcn, reward, observer),
+            AnyOptimizer::CmaEs(cma_es) => cma_es.optimize_with(fcn, reward, observer),
+            AnyOptimizer::Es(es) => es.optimize_with(fcn, reward, observer),
+        }
+    }
+}
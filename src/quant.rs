@@ -0,0 +1,493 @@
+use crate::fcn::*;
+use ndarray::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuantError {
+    /// Only feed-forward models can be quantized.
+    RecurrentLayers,
+    NoCalibrationSamples,
+    /// Only activations applied unit by unit can be tabulated.
+    UnsupportedActivation(Activation),
+}
+
+impl fmt::Display for QuantError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuantError::RecurrentLayers => {
+                write!(f, "Trying to quantize a model with recurrent layers.")
+            }
+            QuantError::NoCalibrationSamples => {
+                write!(f, "Trying to calibrate quantization without samples.")
+            }
+            QuantError::UnsupportedActivation(activation) => {
+                write!(
+                    f,
+                    "Trying to quantize unsupported activation {:?}.",
+                    activation
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for QuantError {}
+
+/// Affine map b/w f32 and int8, x = scale * (q - zero_point).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quantization {
+    pub scale: f32,
+    pub zero_point: i8,
+}
+
+impl Quantization {
+    /// Covers [min, max], which is widened to contain 0 so that 0 is exactly representable.
+    pub fn from_range(min: f32, max: f32) -> Quantization {
+        let (min, max) = (min.min(0.0), max.max(0.0));
+        let scale = if max > min { (max - min) / 255.0 } else { 1.0 };
+        let zero_point = (-128.0 - min / scale).round().clamp(-128.0, 127.0) as i8;
+        Quantization {
+            scale: scale,
+            zero_point: zero_point,
+        }
+    }
+
+    fn of<D: Dimension>(x: &Array<f32, D>) -> Quantization {
+        let min = x.fold(f32::INFINITY, |min, &e| min.min(e));
+        let max = x.fold(f32::NEG_INFINITY, |max, &e| max.max(e));
+        Quantization::from_range(min, max)
+    }
+
+    pub fn quantize(&self, x: f32) -> i8 {
+        (x / self.scale + self.zero_point as f32)
+            .round()
+            .clamp(-128.0, 127.0) as i8
+    }
+
+    pub fn dequantize(&self, q: i8) -> f32 {
+        self.scale * (q as i32 - self.zero_point as i32) as f32
+    }
+}
+
+/// Fixed-point multiplication by a positive real, x * real ~ (x * multiplier) >> shift, rounding to nearest.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Requantization {
+    pub multiplier: i32,
+    pub shift: u32,
+}
+
+impl Requantization {
+    /// Multiplier is normalized in [2^30, 2^31) for precision, reals too small for a 62 bit shift become 0
+    /// and reals of at least 2^31, whose products with any nonzero x overflow, saturate.
+    pub fn from_real(real: f64) -> Requantization {
+        if real.is_nan() || real <= 0.0 || real.is_infinite() {
+            return Requantization {
+                multiplier: 0,
+                shift: 0,
+            };
+        }
+        // real = mantissa * 2^exponent with mantissa in [0.5, 1)
+        let mut exponent = real.log2().floor() as i32 + 1;
+        let mut mantissa = real / 2f64.powi(exponent);
+        if mantissa >= 1.0 {
+            mantissa /= 2.0;
+            exponent += 1;
+        } else if mantissa < 0.5 {
+            mantissa *= 2.0;
+            exponent -= 1;
+        }
+        let mut multiplier = (mantissa * (1u64 << 31) as f64).round() as i64;
+        if multiplier == 1 << 31 {
+            multiplier /= 2;
+            exponent += 1;
+        }
+        let shift = 31 - exponent;
+        if shift > 62 {
+            Requantization {
+                multiplier: 0,
+                shift: 0,
+            }
+        } else if shift < 0 {
+            Requantization {
+                multiplier: i32::MAX,
+                shift: 0,
+            }
+        } else {
+            Requantization {
+                multiplier: multiplier as i32,
+                shift: shift as u32,
+            }
+        }
+    }
+
+    /// Saturates to the range of i32.
+    pub fn apply(&self, x: i32) -> i32 {
+        let product = x as i64 * self.multiplier as i64;
+        let rounded = if self.shift == 0 {
+            product
+        } else {
+            (product + (1 << (self.shift - 1))) >> self.shift
+        };
+        rounded.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QuantizedLayer {
+    weights: Array2<i8>,
+    weights_quantization: Quantization,
+    /// Quantized with scale = weights scale * input scale and zero point 0.
+    bias: Array1<i32>,
+    /// From accumulators to pre-activations.
+    requantization: Requantization,
+    pre_activation_quantization: Quantization,
+    activation: Activation,
+    /// Quantized activation of every quantized pre-activation, row q + 128 for pre-activation q, one column per unit.
+    activation_table: Array2<i8>,
+    output_quantization: Quantization,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    skips: Vec<QuantizedSkip>,
+}
+
+/// Skip from the output of an earlier layer, accumulated in i32 and requantized separately.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QuantizedSkip {
+    from: usize,
+    /// Weights of a concat skip, none for a residual skip.
+    weights: Option<(Array2<i8>, Quantization)>,
+    /// From accumulators, or outputs of a residual skip, to pre-activations.
+    requantization: Requantization,
+}
+
+/// Post-training int8 quantization of a feed-forward fcn.
+/// The forward pass of at_batch_quantized is integer only: matrix products and skips are accumulated in i32,
+/// requantized to int8 pre-activations by fixed-point multipliers, and activated by lookup tables,
+/// with pre-activation and output quantizations calibrated on sample inputs.
+/// Floats are only used by quantize_inputs and dequantize_outputs at its boundaries,
+/// which also apply the input normalizer and input activation, and the output denormalizer of the fcn.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuantizedFCN {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    input_normalizer: Option<Normalizer>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output_normalizer: Option<Normalizer>,
+    input_activation: Activation,
+    input_quantization: Quantization,
+    layers: Vec<QuantizedLayer>,
+}
+
+impl fmt::Display for QuantizedFCN {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "quantized fcn, input quantization={:?}, layers=[",
+            self.input_quantization
+        )?;
+        for layer in self.layers.iter() {
+            write!(
+                f,
+                "({}, {:?}, weights={:?}, pre-activation={:?}, output={:?}), ",
+                layer.weights.nrows(),
+                layer.activation,
+                layer.weights_quantization,
+                layer.pre_activation_quantization,
+                layer.output_quantization
+            )?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl QuantizedFCN {
+    /// Quantizes weights per layer and calibrates pre-activation and activation ranges on samples, one input per row.
+    pub fn calibrate(fcn: &FCN, samples: &Array2<f32>) -> Result<QuantizedFCN, QuantError> {
+        if !fcn.recurrent().is_empty() {
+            return Err(QuantError::RecurrentLayers);
+        }
+        if samples.nrows() == 0 {
+            return Err(QuantError::NoCalibrationSamples);
+        }
+        if let Some((_, activation)) = fcn
+            .layers()
+            .iter()
+            .skip(1)
+            .find(|(_, activation)| matches!(activation, Activation::Softmax))
+        {
+            return Err(QuantError::UnsupportedActivation(activation.clone()));
+        }
+        let trace = fcn.forward_trace_with(samples, fcn.params());
+        let input_quantization = Quantization::of(&trace[0].1);
+        let mut output_quantizations = vec![input_quantization];
+        let mut layers = Vec::with_capacity(fcn.layers().len() - 1);
+        for (i, (pre_activations, activations)) in trace.iter().enumerate().skip(1) {
+            let input_scale = output_quantizations[i - 1].scale;
+            let weights = fcn.layer_weights(i).to_owned();
+            let weights_quantization = Quantization::of(&weights);
+            let bias_scale = weights_quantization.scale * input_scale;
+            let pre_activation_quantization = Quantization::of(pre_activations);
+            let output_quantization = Quantization::of(activations);
+            let activation = fcn.layers()[i].1.clone();
+            let skips = fcn
+                .skips()
+                .iter()
+                .enumerate()
+                .filter(|(_, skip)| skip.to() == i)
+                .map(|(j, skip)| {
+                    let from_scale = output_quantizations[skip.from()].scale as f64;
+                    match fcn.skip_weights(j) {
+                        Some(weights) => {
+                            let quantization = Quantization::of(&weights.to_owned());
+                            QuantizedSkip {
+                                from: skip.from(),
+                                weights: Some((
+                                    weights.mapv(|w| quantization.quantize(w)),
+                                    quantization,
+                                )),
+                                requantization: Requantization::from_real(
+                                    quantization.scale as f64 * from_scale
+                                        / pre_activation_quantization.scale as f64,
+                                ),
+                            }
+                        }
+                        None => QuantizedSkip {
+                            from: skip.from(),
+                            weights: None,
+                            requantization: Requantization::from_real(
+                                from_scale / pre_activation_quantization.scale as f64,
+                            ),
+                        },
+                    }
+                })
+                .collect();
+            layers.push(QuantizedLayer {
+                weights: weights.mapv(|w| weights_quantization.quantize(w)),
+                weights_quantization: weights_quantization,
+                bias: fcn.layer_bias(i).mapv(|b| (b / bias_scale).round() as i32),
+                requantization: Requantization::from_real(
+                    bias_scale as f64 / pre_activation_quantization.scale as f64,
+                ),
+                pre_activation_quantization: pre_activation_quantization,
+                activation_table: activation_table(
+                    &activation,
+                    pre_activation_quantization,
+                    output_quantization,
+                    activations.ncols(),
+                ),
+                activation: activation,
+                output_quantization: output_quantization,
+                skips: skips,
+            });
+            output_quantizations.push(output_quantization);
+        }
+        Ok(QuantizedFCN {
+            input_normalizer: fcn.input_normalizer().cloned(),
+            output_normalizer: fcn.output_normalizer().cloned(),
+            input_activation: fcn.layers()[0].1.clone(),
+            input_quantization: input_quantization,
+            layers: layers,
+        })
+    }
+
+    /// Evaluates a batch of inputs, one input per row.
+    pub fn at_batch(&self, inputs: &Array2<f32>) -> Array2<f32> {
+        self.dequantize_outputs(&self.at_batch_quantized(&self.quantize_inputs(inputs)))
+    }
+
+    /// Normalizes, activates and quantizes a batch of inputs, one input per row.
+    pub fn quantize_inputs(&self, inputs: &Array2<f32>) -> Array2<i8> {
+        let inputs = match &self.input_normalizer {
+            Some(normalizer) => normalizer.normalize(inputs.to_owned()),
+            None => inputs.to_owned(),
+        };
+        let quantization = self.input_quantization;
+        self.input_activation
+            .activate(inputs)
+            .mapv(|x| quantization.quantize(x))
+    }
+
+    /// Dequantizes and denormalizes a batch of outputs of at_batch_quantized.
+    pub fn dequantize_outputs(&self, outputs: &Array2<i8>) -> Array2<f32> {
+        let quantization = self.output_quantization();
+        let outputs = outputs.mapv(|q| quantization.dequantize(q));
+        match &self.output_normalizer {
+            Some(normalizer) => normalizer.denormalize(outputs),
+            None => outputs,
+        }
+    }
+
+    /// Integer forward pass of a batch of quantized inputs, one input per row.
+    pub fn at_batch_quantized(&self, inputs: &Array2<i8>) -> Array2<i8> {
+        let mut quantization = self.input_quantization;
+        let mut outputs = inputs.to_owned();
+        // Quantized outputs of every layer so far, for skips
+        let mut layer_outputs = Vec::with_capacity(self.layers.len() + 1);
+        for layer in self.layers.iter() {
+            if self.has_skips() {
+                layer_outputs.push((outputs.clone(), quantization));
+            }
+            // Integer matrix product of zero point shifted values
+            let shifted_inputs = outputs.mapv(|q| q as i32 - quantization.zero_point as i32);
+            let shifted_weights = layer
+                .weights
+                .mapv(|q| q as i32 - layer.weights_quantization.zero_point as i32);
+            let accumulators = shifted_inputs.dot(&shifted_weights.t()) + &layer.bias;
+            let mut z = accumulators.mapv(|acc| layer.requantization.apply(acc));
+            for skip in layer.skips.iter() {
+                let (from_outputs, from_quantization) = &layer_outputs[skip.from];
+                let shifted_from_outputs =
+                    from_outputs.mapv(|q| q as i32 - from_quantization.zero_point as i32);
+                let accumulators = match &skip.weights {
+                    Some((weights, weights_quantization)) => {
+                        let shifted_weights =
+                            weights.mapv(|q| q as i32 - weights_quantization.zero_point as i32);
+                        shifted_from_outputs.dot(&shifted_weights.t())
+                    }
+                    None => shifted_from_outputs,
+                };
+                z.zip_mut_with(&accumulators, |z, &acc| {
+                    *z = z.saturating_add(skip.requantization.apply(acc))
+                });
+            }
+            // Requantized pre-activations index the activation table
+            let zero_point = layer.pre_activation_quantization.zero_point as i32;
+            for mut row in z.genrows_mut() {
+                for (unit, z) in row.iter_mut().enumerate() {
+                    let q = z.saturating_add(zero_point).clamp(-128, 127);
+                    *z = layer.activation_table[[(q + 128) as usize, unit]] as i32;
+                }
+            }
+            quantization = layer.output_quantization;
+            outputs = z.mapv(|q| q as i8);
+        }
+        outputs
+    }
+
+    /// Quantization of the inputs of at_batch_quantized.
+    pub fn input_quantization(&self) -> Quantization {
+        self.input_quantization
+    }
+
+    /// Quantization of the outputs of at_batch_quantized.
+    pub fn output_quantization(&self) -> Quantization {
+        self.layers
+            .last()
+            .map_or(self.input_quantization, |layer| layer.output_quantization)
+    }
+
+    fn has_skips(&self) -> bool {
+        self.layers.iter().any(|layer| !layer.skips.is_empty())
+    }
+
+    pub fn at(&self, input: &Array1<f32>) -> Array1<f32> {
+        let inputs = input.to_owned().into_shape((1, input.len())).unwrap();
+        self.at_batch(&inputs).row(0).to_owned()
+    }
+}
+
+/// Quantized activation of every quantized pre-activation, one column per unit.
+fn activation_table(
+    activation: &Activation,
+    pre_activation_quantization: Quantization,
+    output_quantization: Quantization,
+    num_units: usize,
+) -> Array2<i8> {
+    let pre_activations = Array2::from_shape_fn((256, num_units), |(k, _)| {
+        pre_activation_quantization.dequantize((k as i32 - 128) as i8)
+    });
+    activation
+        .activate(pre_activations)
+        .mapv(|x| output_quantization.quantize(x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seed;
+    use rand::Rng;
+
+    #[test]
+    fn requantization_rounds_products_to_nearest() {
+        let mut rng = seed::rng(5);
+        for &real in [1e-9, 3.7e-4, 0.0123, 0.5, 0.99999, 1.0, 7.25, 1e3].iter() {
+            let requantization = Requantization::from_real(real);
+            for _ in 0..1000 {
+                let x = rng.gen_range(-1_000_000, 1_000_000);
+                let expected = (x as f64 * real).round();
+                let error = (requantization.apply(x) as f64 - expected).abs();
+                assert!(error <= 1.0, "real={} x={} error={}", real, x, error);
+            }
+        }
+        assert_eq!(Requantization::from_real(0.0).apply(12345), 0);
+    }
+
+    #[test]
+    fn requantization_saturates_reals_beyond_i32() {
+        for &real in [2f64.powi(31), 1e12, f64::MAX].iter() {
+            let requantization = Requantization::from_real(real);
+            assert_eq!(requantization.apply(0), 0);
+            assert_eq!(requantization.apply(1), i32::MAX);
+            assert_eq!(requantization.apply(-3), i32::MIN);
+        }
+        // Reals below 2^31 only saturate products that overflow
+        let requantization = Requantization::from_real(2f64.powi(30) * 1.5);
+        assert_eq!(requantization.apply(1), 3 << 29);
+        assert_eq!(requantization.apply(2), i32::MAX);
+    }
+
+    #[test]
+    fn quantized_outputs_stay_close_to_f32_outputs() {
+        let mut rng = seed::rng(11);
+        let mut fcn = FCN::new_graph_with(
+            vec![
+                (2, Activation::Linear),
+                (8, Activation::ReLu),
+                (8, Activation::Tanh),
+                (8, Activation::LeakyReLu(0.1)),
+                (2, Activation::ScaledTanh(vec![(-1.0, 1.0), (0.0, 4.0)])),
+            ],
+            vec![],
+            vec![
+                Skip::Residual { from: 1, to: 3 },
+                Skip::Concat { from: 0, to: 4 },
+            ],
+            &Init::Normal(0.0, 0.5),
+            &mut rng,
+        );
+        fcn.set_input_normalizer(Some(Normalizer::from_bounds(&[(-10.0, 10.0), (0.0, 5.0)])))
+            .unwrap();
+        let samples = Array::from_shape_fn((500, 2), |(_, j)| {
+            if j == 0 {
+                rng.gen_range(-10.0, 10.0)
+            } else {
+                rng.gen_range(0.0, 5.0)
+            }
+        });
+        let quantized_fcn = QuantizedFCN::calibrate(&fcn, &samples).unwrap();
+        let outputs = fcn.at_batch(&samples);
+        let quantized_outputs = quantized_fcn.at_batch(&samples);
+        let max_error = (quantized_outputs - &outputs).fold(0.0f32, |max, e| max.max(e.abs()));
+        let range = outputs.fold(f32::NEG_INFINITY, |max, &e| max.max(e))
+            - outputs.fold(f32::INFINITY, |min, &e| min.min(e));
+        assert!(max_error < 0.05 * range);
+    }
+
+    #[test]
+    fn softmax_cannot_be_quantized() {
+        let fcn = FCN::new_with(
+            vec![(2, Activation::Linear), (3, Activation::Softmax)],
+            &Init::Normal(0.0, 0.5),
+            &mut seed::rng(1),
+        );
+        let samples = Array::zeros((1, 2));
+        assert_eq!(
+            QuantizedFCN::calibrate(&fcn, &samples).unwrap_err(),
+            QuantError::UnsupportedActivation(Activation::Softmax)
+        );
+    }
+}
@@ -0,0 +1,40 @@
+//! Independent random streams derived deterministically from a master seed.
+use rand::SeedableRng;
+use rand_pcg::Pcg32;
+
+/// Stream of the initial params of a model.
+pub const INIT_STREAM: u64 = 0;
+/// Stream of an optimizer, from which it derives streams of its candidates and their evaluations.
+pub const OPTIMIZER_STREAM: u64 = 1;
+/// Stream of evaluations outside of optimization.
+pub const EVAL_STREAM: u64 = 2;
+
+/// Master seed parsed from an optional `seed <n>` prefix of cmd line args, which is then removed,
+/// otherwise drawn from entropy.
+pub fn from_args(args: &mut Vec<String>) -> u64 {
+    if args.len() >= 3 && args[1] == "seed" {
+        let seed = args[2].parse().expect("Bad cmd line parameters.");
+        args.drain(1..3);
+        seed
+    } else {
+        rand::random()
+    }
+}
+
+/// Seed of stream index of seed.
+/// It depends only on seed and index, so streams can be derived in any order, ex. from parallel tasks.
+pub fn derive(seed: u64, index: u64) -> u64 {
+    // Steps of splitmix64 seeded with the mixed seed
+    mix(mix(seed).wrapping_add(index.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15)))
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Generator of the stream with given seed.
+pub fn rng(seed: u64) -> Pcg32 {
+    Pcg32::seed_from_u64(seed)
+}
@@ -0,0 +1,41 @@
+//! In a process of its own, since an interrupt stops every optimization.
+use ndarray::prelude::*;
+use wall_e::ceo::{GenerationStats, Reward, CEO};
+use wall_e::fcn::{Activation, Init, FCN};
+use wall_e::interrupt;
+use wall_e::optimizer::Optimizer;
+use wall_e::seed;
+
+/// Negative squared distance of params to 1.
+struct Quadratic;
+
+impl Reward<f64> for Quadratic {
+    fn reward(&self, _: &FCN<f64>, params: &Array1<f64>, _: usize, _: u64) -> f64 {
+        -params.mapv(|th| (th - 1.0) * (th - 1.0)).sum()
+    }
+}
+
+#[test]
+fn interrupt_stops_after_the_current_generation() {
+    let mut fcn = FCN::new_with(
+        vec![(2, Activation::Linear), (1, Activation::Linear)],
+        &Init::Normal(0.0, 0.5),
+        &mut seed::rng(0),
+    );
+    let ceo = CEO {
+        generations: 10,
+        batch_size: 10,
+        num_evalation_samples: 1,
+        seed: Some(1),
+        ..CEO::default()
+    };
+    assert!(!interrupt::request());
+    let mut generations = vec![];
+    ceo.optimize_with(&mut fcn, &Quadratic, &mut |stats: &GenerationStats<f64>| {
+        generations.push(stats.generation);
+        false
+    })
+    .unwrap();
+    assert_eq!(generations, vec![1]);
+    assert!(interrupt::requested());
+}